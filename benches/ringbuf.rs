@@ -0,0 +1,50 @@
+// Compares the bounded `RingBuffer` with the unbounded `ConcurrentQueue` as a pipeline between
+// producer and consumer threads. Run with `cargo bench --bench ringbuf`.
+use concurrent_collections::queue::ConcurrentQueue;
+use concurrent_collections::ringbuf::RingBuffer;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::thread;
+
+const OPS_PER_PRODUCER: u64 = 10_000;
+const CAPACITY: usize = 1024;
+
+// Each producer hands `OPS_PER_PRODUCER` elements to the consumers, which each take an equal share
+fn pipeline<Q: Sync>(pairs: u64, queue: &Q, enqueue: impl Fn(&Q, u64) + Sync, dequeue: impl Fn(&Q) + Sync) {
+    thread::scope(|s| {
+        for _ in 0..pairs {
+            s.spawn(|| {
+                for i in 0..OPS_PER_PRODUCER {
+                    enqueue(queue, i);
+                }
+            });
+            s.spawn(|| {
+                for _ in 0..OPS_PER_PRODUCER {
+                    dequeue(queue);
+                }
+            });
+        }
+    });
+}
+
+fn bench_pipeline(c: &mut Criterion) {
+    let mut group = c.benchmark_group("queue_pipeline");
+    // One pair is the SPSC case, the rest are MPMC
+    for pairs in [1, 2, 4] {
+        group.throughput(Throughput::Elements(pairs * OPS_PER_PRODUCER));
+        group.bench_with_input(BenchmarkId::new("ring_buffer", pairs), &pairs, |b, &pairs| {
+            let ring = RingBuffer::with_capacity(CAPACITY);
+            b.iter(|| pipeline(pairs, &ring, |r, v| r.enqueue(v), |r| { r.dequeue(); }));
+        });
+        group.bench_with_input(BenchmarkId::new("concurrent_queue", pairs), &pairs, |b, &pairs| {
+            let queue = ConcurrentQueue::new();
+            // The queue has no blocking dequeue, so consumers spin until an element shows up
+            b.iter(|| pipeline(pairs, &queue, |q, v| q.enqueue(v), |q| while q.dequeue().is_none() {
+                std::hint::spin_loop();
+            }));
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_pipeline);
+criterion_main!(benches);
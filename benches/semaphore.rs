@@ -0,0 +1,42 @@
+// Compares spinning budgets for `Semaphore::wait` on short critical sections: never spinning, the
+// default adaptive budget, and a larger one. Run it under `strace -f -c -e trace=futex` to see how
+// many futex calls each saves.
+use concurrent_collections::semaphore::Semaphore;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::thread;
+
+const OPS_PER_THREAD: u64 = 10_000;
+
+// Each thread takes the permit, does a few nanoseconds of work and gives it straight back
+fn short_holds(threads: u64, semaphore: &Semaphore, counter: &AtomicU64) {
+    thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| {
+                for _ in 0..OPS_PER_THREAD {
+                    semaphore.wait();
+                    counter.fetch_add(1, Relaxed);
+                    semaphore.signal();
+                }
+            });
+        }
+    });
+}
+
+fn bench_short_holds(c: &mut Criterion) {
+    let mut group = c.benchmark_group("semaphore_short_holds");
+    for threads in [2, 4, 8] {
+        group.throughput(Throughput::Elements(threads * OPS_PER_THREAD));
+        for (name, spins) in [("park", 0), ("adaptive", 16), ("spin_64", 64)] {
+            group.bench_with_input(BenchmarkId::new(name, threads), &threads, |b, &threads| {
+                let semaphore = Semaphore::with_spin(1, spins);
+                let counter = AtomicU64::new(0);
+                b.iter(|| short_holds(threads, &semaphore, &counter));
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_short_holds);
+criterion_main!(benches);
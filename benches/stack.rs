@@ -0,0 +1,72 @@
+// Compares the semaphore-guarded `Stack` with `LockFreeStack`, with and without elimination, under
+// contention.
+use concurrent_collections::stack::{LockFreeStack, Stack};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::thread;
+
+const OPS_PER_THREAD: u64 = 10_000;
+
+// Each thread pushes and immediately pops, so every thread contends on the head the whole time
+fn contended<S: Sync>(threads: u64, stack: &S, push: impl Fn(&S, u64) + Sync, pop: impl Fn(&S) + Sync) {
+    thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| {
+                for i in 0..OPS_PER_THREAD {
+                    push(stack, i);
+                    pop(stack);
+                }
+            });
+        }
+    });
+}
+
+fn bench_push_pop(c: &mut Criterion) {
+    let mut group = c.benchmark_group("stack_push_pop");
+    for threads in [1, 2, 4, 8] {
+        group.throughput(Throughput::Elements(2 * threads * OPS_PER_THREAD));
+        group.bench_with_input(BenchmarkId::new("semaphore", threads), &threads, |b, &threads| {
+            let stack = Stack::new();
+            b.iter(|| contended(threads, &stack, |s, v| s.push(v), |s| { s.pop(); }));
+        });
+        group.bench_with_input(BenchmarkId::new("lock_free", threads), &threads, |b, &threads| {
+            let stack = LockFreeStack::new();
+            b.iter(|| contended(threads, &stack, |s, v| s.push(v), |s| { s.pop(); }));
+        });
+        group.bench_with_input(BenchmarkId::new("lock_free_elimination", threads), &threads, |b, &threads| {
+            let stack = LockFreeStack::with_elimination();
+            b.iter(|| contended(threads, &stack, |s, v| s.push(v), |s| { s.pop(); }));
+        });
+    }
+    group.finish();
+}
+
+// Bursts of pushes followed by as many pops. After the first burst up to 64 pushes per burst, the
+// free list's cap, reuse a node instead of allocating; the baseline allocates a `Box` every time
+fn bench_churn(c: &mut Criterion) {
+    let mut group = c.benchmark_group("stack_churn");
+    for burst in [16, 64, 256] {
+        group.throughput(Throughput::Elements(2 * burst));
+        group.bench_with_input(BenchmarkId::new("stack", burst), &burst, |b, &burst| {
+            let stack = Stack::new();
+            b.iter(|| {
+                for i in 0..burst {
+                    stack.push(i);
+                }
+                while stack.pop().is_some() {}
+            });
+        });
+        group.bench_with_input(BenchmarkId::new("boxed_baseline", burst), &burst, |b, &burst| {
+            let mut boxes = Vec::with_capacity(burst as usize);
+            b.iter(|| {
+                for i in 0..burst {
+                    boxes.push(Box::new(i));
+                }
+                boxes.clear();
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_push_pop, bench_churn);
+criterion_main!(benches);
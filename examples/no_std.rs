@@ -0,0 +1,38 @@
+// Uses the collections from a `no_std` crate, check it with
+// `cargo build --example no_std --no-default-features`. It is a library rather than a program,
+// since a bare-metal binary would also need a panic handler and an allocator, which are the
+// embedding's business, not ours. Examples pull in the dev-dependencies, which need std, so to
+// check the crate itself on a target without std use
+// `cargo build --lib --no-default-features --target x86_64-unknown-none`.
+#![no_std]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use concurrent_collections::rcu::{Rcu, RcuNode};
+use concurrent_collections::stack::{LockFreeStack, Stack};
+
+// Hands out buffers from a shared pool, falling back to a fresh one when the pool is empty
+pub fn take_buffer(pool: &Stack<Vec<u8>>) -> Vec<u8> {
+    pool.pop().unwrap_or_else(|| Vec::with_capacity(64))
+}
+
+pub fn return_buffer(pool: &Stack<Vec<u8>>, mut buf: Vec<u8>) {
+    buf.clear();
+    pool.push(buf);
+}
+
+pub fn push_events(events: &LockFreeStack<u32>, batch: &[u32]) {
+    for &event in batch {
+        events.push(event);
+    }
+}
+
+// Readers keep whatever node they loaded even if the config is replaced meanwhile
+pub fn current_config(config: &Rcu<[u8; 4]>) -> RcuNode<[u8; 4]> {
+    config.read()
+}
+
+pub fn defaults() -> RcuNode<[u8; 4]> {
+    RcuNode::new([0; 4])
+}
@@ -0,0 +1,61 @@
+#![no_main]
+
+// Drives a `Stack<u64>` from a handful of threads, each running its own script of pushes and pops
+// decoded from the fuzz input. The interleaving is up to the scheduler, but the invariant checked
+// holds for every interleaving: each pushed value comes out exactly once, either from a pop during
+// the run or from draining the stack afterwards. Every pushed value is unique, so a loss or a
+// duplicate always shows up as a mismatch.
+//
+// Run with `cargo +nightly fuzz run stack_push_pop` from the repository root, cargo-fuzz needs
+// nightly for the sanitizer flags.
+
+use concurrent_collections::stack::Stack;
+use libfuzzer_sys::fuzz_target;
+use std::sync::Barrier;
+use std::thread;
+
+const MAX_THREADS: usize = 4;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&first, ops)) = data.split_first() else {
+        return;
+    };
+    let threads = 1 + first as usize % MAX_THREADS;
+
+    // Byte `i` of the script belongs to thread `i % threads`; an even byte pushes, an odd one pops
+    let scripts: Vec<Vec<bool>> = (0..threads)
+        .map(|t| ops.iter().skip(t).step_by(threads).map(|op| op % 2 == 0).collect())
+        .collect();
+
+    let stack = Stack::new();
+    let barrier = Barrier::new(threads);
+    let results: Vec<(Vec<u64>, Vec<u64>)> = thread::scope(|s| {
+        let jhs: Vec<_> = scripts.iter().enumerate().map(|(t, script)| {
+            let (stack, barrier) = (&stack, &barrier);
+            s.spawn(move || {
+                let mut pushed = vec![];
+                let mut popped = vec![];
+                barrier.wait();
+                for (seq, &push) in script.iter().enumerate() {
+                    if push {
+                        let val = ((t as u64) << 32) | seq as u64;
+                        stack.push(val);
+                        pushed.push(val);
+                    } else if let Some(val) = stack.pop() {
+                        popped.push(val);
+                    }
+                }
+                (pushed, popped)
+            })
+        }).collect();
+        jhs.into_iter().map(|jh| jh.join().expect("worker panicked")).collect()
+    });
+
+    let mut pushed: Vec<u64> = results.iter().flat_map(|(pushed, _)| pushed.iter().copied()).collect();
+    let mut received: Vec<u64> = results.into_iter().flat_map(|(_, popped)| popped).collect();
+    received.extend(stack.drain_while(|_| true));
+
+    pushed.sort_unstable();
+    received.sort_unstable();
+    assert_eq!(pushed, received, "stack lost or duplicated a value");
+});
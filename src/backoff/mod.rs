@@ -0,0 +1,55 @@
+use crate::sync::{spin_loop, yield_now};
+
+
+// Past this many steps `snooze` stops spinning and yields instead
+const SPIN_STEPS: u32 = 6;
+
+// Exponential backoff for spin loops. Each `snooze` spins twice as long as the one before, and
+// once that gets long it yields the thread instead, so a preempted holder gets a chance to run
+// even on a single core.
+pub(crate) struct Backoff {
+    step: u32,
+}
+
+impl Backoff {
+    pub(crate) fn new() -> Self {
+        Self { step: 0 }
+    }
+
+    pub(crate) fn snooze(&mut self) {
+        if self.step <= SPIN_STEPS {
+            for _ in 0..1 << self.step {
+                spin_loop();
+            }
+            self.step += 1;
+        } else {
+            yield_now();
+        }
+    }
+
+    // Whether `snooze` has moved on to yielding. Callers that may wait a long time should switch
+    // to blocking properly at this point.
+    #[cfg(any(feature = "std", test))]
+    pub(crate) fn is_completed(&self) -> bool {
+        self.step > SPIN_STEPS
+    }
+}
+
+
+#[cfg(all(test, not(feature = "loom")))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_backoff_spins_then_settles_on_yielding() {
+        let mut backoff = Backoff::new();
+        for expected in 1..=SPIN_STEPS + 1 {
+            assert!(!backoff.is_completed());
+            backoff.snooze();
+            assert_eq!(backoff.step, expected);
+        }
+        assert!(backoff.is_completed());
+        backoff.snooze();
+        assert_eq!(backoff.step, SPIN_STEPS + 1);
+    }
+}
@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use crate::rcu::Rcu;
+
+
+/// A read-mostly map. Readers clone a handle to the current map and never block; writers copy the
+/// whole map, modify the copy and install it, so readers only ever see complete maps.
+#[derive(Clone)]
+pub struct RcuCache<K: Clone + Eq + Hash, V: Clone> {
+    map: Rcu<HashMap<K, V>>,
+}
+
+impl<K: Clone + Eq + Hash, V: Clone> RcuCache<K, V> {
+    pub fn new() -> Self {
+        Self { map: Rcu::new(HashMap::new()) }
+    }
+
+    pub fn get(&self, k: &K) -> Option<V> {
+        self.map.read().data().get(k).cloned()
+    }
+
+    pub fn insert(&self, k: K, v: V) {
+        self.map.update_from(|map| {
+            let mut map = map.clone();
+            map.insert(k.clone(), v.clone());
+            Some(map)
+        });
+    }
+}
+
+impl<K: Clone + Eq + Hash, V: Clone> Default for RcuCache<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+#[cfg(all(test, not(feature = "loom")))]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering::Relaxed};
+    use std::thread;
+
+    #[test]
+    fn test_rcu_cache_concurrent_readers_single_writer() {
+        let cache = RcuCache::new();
+        let done = Arc::new(AtomicBool::new(false));
+
+        let mut reader_jhs = vec![];
+        for _ in 0..4 {
+            let cache = cache.clone();
+            let done = done.clone();
+            reader_jhs.push(thread::spawn(move || {
+                let mut observed = 0;
+                while !done.load(Relaxed) {
+                    // Keys are inserted in order, so if key `k` is visible every key below it
+                    // must be as well, each mapped to its final value
+                    let mut k = 0;
+                    while let Some(v) = cache.get(&k) {
+                        assert_eq!(v, 2 * k);
+                        k += 1;
+                    }
+                    observed = observed.max(k);
+                }
+                observed
+            }));
+        }
+
+        let writer_cache = cache.clone();
+        let writer_jh = thread::spawn(move || {
+            for k in 0..200u32 {
+                writer_cache.insert(k, 2 * k);
+            }
+        });
+
+        writer_jh.join().expect("writer panicked");
+        done.store(true, Relaxed);
+        for jh in reader_jhs {
+            let observed = jh.join().expect("reader panicked");
+            println!("reader observed {observed} keys");
+        }
+
+        for k in 0..200 {
+            assert_eq!(cache.get(&k), Some(2 * k));
+        }
+        assert_eq!(cache.get(&200), None);
+    }
+}
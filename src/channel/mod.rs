@@ -0,0 +1,320 @@
+use core::fmt;
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+use crate::sync::{seq_cst_fence, wait, wake_all, wake_one, AtomicU32, AtomicUsize};
+use crate::sync::Ordering::{Relaxed, SeqCst};
+#[cfg(feature = "std")]
+use crate::backoff::Backoff;
+use crate::queue::ConcurrentQueue;
+use crate::shared::Shared;
+
+// How long `recv_timeout` sleeps between polls once it is done spinning
+#[cfg(feature = "std")]
+const TIMEOUT_POLL: Duration = Duration::from_millis(1);
+
+
+struct InnerChannel<T> {
+    queue: ConcurrentQueue<T>,
+    // Number of live `Sender`s. The channel is disconnected once it reaches zero
+    senders: AtomicUsize,
+    // Wrapping count of sends, also bumped once by the last sender to drop. `recv` parks on it
+    seq: AtomicU32,
+    // 1 while the receiver is in `recv`, so `send` only wakes when someone is waiting
+    parked: AtomicU32,
+}
+
+impl<T> InnerChannel<T> {
+    fn send(&self, val: T) {
+        self.queue.enqueue(val);
+        // SeqCst pairs with `recv`: either it sees our send, or we see it waiting
+        self.seq.fetch_add(1, SeqCst);
+        seq_cst_fence();
+        if self.parked.load(SeqCst) != 0 {
+            wake_one(&self.seq);
+        }
+    }
+
+    fn try_recv(&self) -> Result<T, TryRecvError> {
+        if let Some(val) = self.queue.dequeue() {
+            return Ok(val);
+        }
+        if self.senders.load(SeqCst) == 0 {
+            // Sends made before the last sender dropped are visible now, so one more look settles it
+            return self.queue.dequeue().ok_or(TryRecvError::Disconnected);
+        }
+        Err(TryRecvError::Empty)
+    }
+
+    fn recv(&self) -> Result<T, Disconnected> {
+        self.parked.store(1, SeqCst);
+        seq_cst_fence();
+        let res = loop {
+            let seq = self.seq.load(SeqCst);
+            match self.try_recv() {
+                Ok(val) => break Ok(val),
+                Err(TryRecvError::Disconnected) => break Err(Disconnected),
+                Err(TryRecvError::Empty) => wait(&self.seq, seq),
+            }
+        };
+        self.parked.store(0, SeqCst);
+        res
+    }
+
+    // The futex cannot time out, so like `Semaphore::wait_timeout` this polls instead of parking
+    #[cfg(feature = "std")]
+    fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeout> {
+        let Some(deadline) = Instant::now().checked_add(timeout) else {
+            return self.recv().map_err(|Disconnected| RecvTimeout::Disconnected);
+        };
+        let mut backoff = Backoff::new();
+        loop {
+            match self.try_recv() {
+                Ok(val) => return Ok(val),
+                Err(TryRecvError::Disconnected) => return Err(RecvTimeout::Disconnected),
+                Err(TryRecvError::Empty) => {},
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(RecvTimeout::Timeout);
+            }
+            if backoff.is_completed() {
+                std::thread::sleep(TIMEOUT_POLL.min(deadline - now));
+            } else {
+                backoff.snooze();
+            }
+        }
+    }
+
+    fn disconnect(&self) {
+        // Change the futex word so a receiver that already loaded it does not sleep through this
+        self.seq.fetch_add(1, SeqCst);
+        wake_all(&self.seq);
+    }
+}
+
+/// Error returned by `Receiver::recv` once every `Sender` is gone and nothing is left to receive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Disconnected;
+
+impl fmt::Display for Disconnected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "channel is disconnected")
+    }
+}
+
+impl core::error::Error for Disconnected {}
+
+/// Error returned by `Receiver::try_recv`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    /// Nothing has been sent yet, but a `Sender` is still alive.
+    Empty,
+    /// Every `Sender` is gone and nothing is left to receive.
+    Disconnected,
+}
+
+impl fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TryRecvError::Empty => write!(f, "channel is empty"),
+            TryRecvError::Disconnected => write!(f, "channel is disconnected"),
+        }
+    }
+}
+
+impl core::error::Error for TryRecvError {}
+
+/// Error returned by `Receiver::recv_timeout`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvTimeout {
+    /// Nothing arrived before the timeout, but a `Sender` is still alive.
+    Timeout,
+    /// Every `Sender` is gone and nothing is left to receive.
+    Disconnected,
+}
+
+impl fmt::Display for RecvTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecvTimeout::Timeout => write!(f, "timed out waiting on channel"),
+            RecvTimeout::Disconnected => write!(f, "channel is disconnected"),
+        }
+    }
+}
+
+impl core::error::Error for RecvTimeout {}
+
+/// Creates an unbounded multi-producer single-consumer channel over a `ConcurrentQueue`. Clone the
+/// `Sender` for more producers. Values from one sender arrive in the order it sent them.
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let inner = Shared::new(InnerChannel {
+        queue: ConcurrentQueue::new(),
+        senders: AtomicUsize::new(1),
+        seq: AtomicU32::new(0),
+        parked: AtomicU32::new(0),
+    });
+    (Sender { inner: inner.clone() }, Receiver { inner })
+}
+
+/// The sending half of a `channel`. Once every clone is dropped the `Receiver` is disconnected.
+pub struct Sender<T> {
+    inner: Shared<InnerChannel<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Sends `val` without blocking, waking the receiver if it is waiting. Values sent after the
+    /// `Receiver` is gone are dropped along with the channel.
+    pub fn send(&self, val: T) {
+        self.inner.send(val);
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.inner.senders.fetch_add(1, Relaxed);
+        Sender { inner: self.inner.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        // SeqCst orders our sends before the count reaching zero, see `try_recv`
+        if self.inner.senders.fetch_sub(1, SeqCst) == 1 {
+            self.inner.disconnect();
+        }
+    }
+}
+
+unsafe impl<T> Send for Sender<T> where T: Send {}
+unsafe impl<T> Sync for Sender<T> where T: Send {}
+
+/// The receiving half of a `channel`. There is only ever one, so it can be sent to another thread
+/// but not shared.
+pub struct Receiver<T> {
+    inner: Shared<InnerChannel<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Takes the next value, parking until one is sent. Returns `Err(Disconnected)` once every
+    /// `Sender` is gone and everything they sent has been received.
+    pub fn recv(&self) -> Result<T, Disconnected> {
+        self.inner.recv()
+    }
+
+    /// Takes the next value if one is there right now.
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        self.inner.try_recv()
+    }
+
+    /// Like `recv`, but gives up with `Err(RecvTimeout::Timeout)` if nothing arrives within
+    /// `timeout`.
+    #[cfg(feature = "std")]
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeout> {
+        self.inner.recv_timeout(timeout)
+    }
+}
+
+unsafe impl<T> Send for Receiver<T> where T: Send {}
+
+
+#[cfg(all(test, not(feature = "loom")))]
+mod test {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_channel_try_recv() {
+        let (tx, rx) = channel();
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+        for i in 0..5 {
+            tx.send(i);
+        }
+        for i in 0..5 {
+            assert_eq!(rx.try_recv(), Ok(i));
+        }
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+    }
+
+    #[test]
+    fn test_channel_multi_producer_fan_in() {
+        const PRODUCERS: usize = 4;
+        const PER_PRODUCER: usize = 5_000;
+        let (tx, rx) = channel();
+        thread::scope(|s| {
+            for i in 0..PRODUCERS {
+                let tx = tx.clone();
+                s.spawn(move || {
+                    for j in 0..PER_PRODUCER {
+                        tx.send((i, j));
+                    }
+                });
+            }
+            drop(tx);
+
+            let mut next = [0; PRODUCERS];
+            while let Ok((i, j)) = rx.recv() {
+                // Each producer's values arrive in the order it sent them
+                assert_eq!(j, next[i], "producer {i}");
+                next[i] += 1;
+            }
+            assert_eq!(next, [PER_PRODUCER; PRODUCERS]);
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_channel_recv_timeout_expires_when_empty() {
+        let (tx, rx) = channel::<u32>();
+        let start = std::time::Instant::now();
+        assert_eq!(rx.recv_timeout(Duration::from_millis(30)), Err(RecvTimeout::Timeout));
+        assert!(start.elapsed() >= Duration::from_millis(30));
+
+        drop(tx);
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Err(RecvTimeout::Disconnected));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_channel_recv_timeout_takes_value_sent_in_time() {
+        let (tx, rx) = channel();
+        tx.send(1);
+        assert_eq!(rx.recv_timeout(Duration::ZERO), Ok(1));
+        thread::scope(|s| {
+            s.spawn(move || {
+                thread::sleep(Duration::from_millis(20));
+                tx.send(2);
+            });
+            assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok(2));
+        });
+    }
+
+    #[test]
+    fn test_channel_disconnect_wakes_receiver() {
+        let (tx, rx) = channel::<u32>();
+        let tx2 = tx.clone();
+        tx.send(1);
+        thread::scope(|s| {
+            let receiver = s.spawn(move || {
+                assert_eq!(rx.recv(), Ok(1));
+                assert_eq!(rx.recv(), Ok(2));
+                let res = rx.recv();
+                (rx, res)
+            });
+            thread::sleep(Duration::from_millis(30));
+            assert!(!receiver.is_finished());
+
+            // One sender going away is not enough
+            drop(tx);
+            tx2.send(2);
+            thread::sleep(Duration::from_millis(30));
+            assert!(!receiver.is_finished());
+
+            // The last one going away wakes the parked receiver
+            drop(tx2);
+            let (rx, res) = receiver.join().expect("receiver panicked");
+            assert_eq!(res, Err(Disconnected));
+            assert_eq!(rx.try_recv(), Err(TryRecvError::Disconnected));
+        });
+    }
+}
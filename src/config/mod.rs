@@ -0,0 +1,103 @@
+use crate::rcu::Rcu;
+
+
+/// Hot-reloadable configuration shared by many threads. Readers call [`Config::current`] as often
+/// as they like without blocking; a reload swaps in a complete new value, so a reader sees either
+/// the old config or the new one, never a mix of the two.
+///
+/// ```
+/// use concurrent_collections::config::Config;
+///
+/// #[derive(Clone)]
+/// struct Settings { workers: usize, verbose: bool }
+///
+/// let config = Config::new(Settings { workers: 4, verbose: false });
+/// config.reload(Settings { workers: 8, verbose: true });
+/// assert_eq!(config.current().workers, 8);
+/// ```
+#[derive(Clone)]
+pub struct Config<T: Clone> {
+    rcu: Rcu<T>,
+}
+
+impl<T: Clone> Config<T> {
+    pub fn new(initial: T) -> Self {
+        Self { rcu: Rcu::new(initial) }
+    }
+
+    /// Returns a copy of the config as of the most recent reload.
+    pub fn current(&self) -> T {
+        self.rcu.read().copy()
+    }
+
+    /// Replaces the config. Concurrent reloads are applied one after the other, and the last one
+    /// to install wins.
+    pub fn reload(&self, new: T) {
+        let mut new = new;
+        while let Err(rejected) = self.rcu.update(new) {
+            new = rejected;
+        }
+    }
+}
+
+
+#[cfg(all(test, not(feature = "loom")))]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering::Relaxed};
+    use std::thread;
+
+    #[derive(Clone)]
+    struct Settings {
+        generation: u32,
+        name: String,
+        limits: Vec<u32>,
+    }
+
+    impl Settings {
+        fn generation(generation: u32) -> Self {
+            Self {
+                generation,
+                name: format!("gen-{generation}"),
+                limits: vec![generation; 4],
+            }
+        }
+    }
+
+    #[test]
+    fn test_config_readers_see_whole_reloads() {
+        let config = Config::new(Settings::generation(0));
+        let done = Arc::new(AtomicBool::new(false));
+
+        let mut reader_jhs = vec![];
+        for _ in 0..3 {
+            let config = config.clone();
+            let done = done.clone();
+            reader_jhs.push(thread::spawn(move || {
+                let mut last = 0;
+                while !done.load(Relaxed) {
+                    let cur = config.current();
+                    assert_eq!(cur.name, format!("gen-{}", cur.generation));
+                    assert!(cur.limits.iter().all(|&limit| limit == cur.generation));
+                    assert!(cur.generation >= last);
+                    last = cur.generation;
+                }
+            }));
+        }
+
+        let writer_config = config.clone();
+        let writer_jh = thread::spawn(move || {
+            for generation in 1..=500 {
+                writer_config.reload(Settings::generation(generation));
+            }
+        });
+
+        writer_jh.join().expect("writer panicked");
+        done.store(true, Relaxed);
+        for jh in reader_jhs {
+            jh.join().expect("reader saw a partial config");
+        }
+        assert_eq!(config.current().generation, 500);
+    }
+}
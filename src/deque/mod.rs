@@ -0,0 +1,359 @@
+use core::cell::UnsafeCell;
+use core::ptr;
+use alloc::boxed::Box;
+use crate::sync::AtomicUsize;
+use crate::sync::Ordering::Relaxed;
+use crate::semaphore::Semaphore;
+use crate::shared::Shared;
+
+
+struct DequeNode<T> {
+    val: T,
+    prev: *mut DequeNode<T>,
+    next: *mut DequeNode<T>,
+}
+
+// Both ends of the list, null when it is empty
+struct Ends<T> {
+    front: *mut DequeNode<T>,
+    back: *mut DequeNode<T>,
+}
+
+struct InnerDeque<T> {
+    // Guarded by `sem`
+    ends: UnsafeCell<Ends<T>>,
+    sem: Semaphore,
+    // Number of nodes in the list. Only changed with `sem` held, but may be read without it
+    len: AtomicUsize,
+}
+
+impl<T> InnerDeque<T> {
+    fn new() -> Self {
+        Self {
+            ends: UnsafeCell::new(Ends { front: ptr::null_mut(), back: ptr::null_mut() }),
+            sem: Semaphore::init_with(1, 1),
+            len: AtomicUsize::new(0),
+        }
+    }
+
+    fn alloc_node(val: T) -> *mut DequeNode<T> {
+        Box::into_raw(Box::new(DequeNode { val, prev: ptr::null_mut(), next: ptr::null_mut() }))
+    }
+
+    fn push_front(&self, val: T) {
+        // Allocate before taking the lock, so the critical section is only a few pointer writes
+        let node = Self::alloc_node(val);
+        self.sem.wait();
+        // Safety: We hold the semaphore, so only this thread has access to the list, and `node` is
+        // ours alone until it is linked in
+        unsafe {
+            let ends = &mut *self.ends.get();
+            (*node).next = ends.front;
+            match ends.front.as_mut() {
+                Some(front) => front.prev = node,
+                None => ends.back = node,
+            }
+            ends.front = node;
+        }
+        self.len.fetch_add(1, Relaxed);
+        self.sem.signal();
+    }
+
+    fn push_back(&self, val: T) {
+        let node = Self::alloc_node(val);
+        self.sem.wait();
+        // Safety: Same as `push_front`
+        unsafe {
+            let ends = &mut *self.ends.get();
+            (*node).prev = ends.back;
+            match ends.back.as_mut() {
+                Some(back) => back.next = node,
+                None => ends.front = node,
+            }
+            ends.back = node;
+        }
+        self.len.fetch_add(1, Relaxed);
+        self.sem.signal();
+    }
+
+    // Unlinks the front node, or returns null if the list is empty. Must be called with `sem` held
+    unsafe fn unlink_front(&self) -> *mut DequeNode<T> {
+        let ends = &mut *self.ends.get();
+        let node = ends.front;
+        if let Some(front) = node.as_ref() {
+            ends.front = front.next;
+            match ends.front.as_mut() {
+                Some(next) => next.prev = ptr::null_mut(),
+                None => ends.back = ptr::null_mut(),
+            }
+            self.len.fetch_sub(1, Relaxed);
+        }
+        node
+    }
+
+    // Unlinks the back node, or returns null if the list is empty. Must be called with `sem` held
+    unsafe fn unlink_back(&self) -> *mut DequeNode<T> {
+        let ends = &mut *self.ends.get();
+        let node = ends.back;
+        if let Some(back) = node.as_ref() {
+            ends.back = back.prev;
+            match ends.back.as_mut() {
+                Some(prev) => prev.next = ptr::null_mut(),
+                None => ends.front = ptr::null_mut(),
+            }
+            self.len.fetch_sub(1, Relaxed);
+        }
+        node
+    }
+
+    // Takes the value out of a node that has been unlinked, freeing the node
+    unsafe fn take(node: *mut DequeNode<T>) -> Option<T> {
+        (!node.is_null()).then(|| Box::from_raw(node).val)
+    }
+
+    fn pop_front(&self) -> Option<T> {
+        self.sem.wait();
+        // Safety: We hold the semaphore
+        let node = unsafe { self.unlink_front() };
+        self.sem.signal();
+        // Safety: The node was unlinked while we held the semaphore, so no other thread can reach it
+        unsafe { Self::take(node) }
+    }
+
+    fn pop_back(&self) -> Option<T> {
+        self.sem.wait();
+        // Safety: We hold the semaphore
+        let node = unsafe { self.unlink_back() };
+        self.sem.signal();
+        // Safety: Same as `pop_front`
+        unsafe { Self::take(node) }
+    }
+
+    fn steal(&self) -> Steal<T> {
+        if !self.sem.try_wait() {
+            return Steal::Retry;
+        }
+        // Safety: We hold the semaphore
+        let node = unsafe { self.unlink_front() };
+        self.sem.signal();
+        // Safety: Same as `pop_front`
+        match unsafe { Self::take(node) } {
+            Some(val) => Steal::Success(val),
+            None => Steal::Empty,
+        }
+    }
+}
+
+impl<T> Drop for InnerDeque<T> {
+    fn drop(&mut self) {
+        // Safety: There are no threads that have access to the list
+        unsafe {
+            let mut cur = self.ends.get_mut().front;
+            while !cur.is_null() {
+                let node = Box::from_raw(cur);
+                cur = node.next;
+            }
+        }
+    }
+}
+
+/// Result of `ConcurrentDeque::steal`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Steal<T> {
+    Empty,
+    /// Another thread held the deque's lock, so nothing was attempted. Try again, or go and steal
+    /// from somewhere else.
+    Retry,
+    Success(T),
+}
+
+/// A double-ended queue that any thread may push to and pop from at either end, guarded by a
+/// semaphore like `Stack`. For work stealing, the owning thread pushes and pops at the back, and
+/// other threads `steal` the oldest work from the front without ever blocking on the owner:
+///
+/// ```
+/// use concurrent_collections::deque::{ConcurrentDeque, Steal};
+/// use std::thread;
+///
+/// let work = ConcurrentDeque::new();
+/// for job in 0..4 {
+///     work.push_back(job);
+/// }
+/// thread::scope(|s| {
+///     s.spawn(|| loop {
+///         match work.steal() {
+///             Steal::Success(job) => assert!(job < 4),
+///             Steal::Retry => continue,
+///             Steal::Empty => break,
+///         }
+///     });
+///     while let Some(job) = work.pop_back() {
+///         assert!(job < 4);
+///     }
+/// });
+/// assert!(work.is_empty());
+/// ```
+pub struct ConcurrentDeque<T> {
+    inner: Shared<InnerDeque<T>>,
+}
+
+impl<T> ConcurrentDeque<T> {
+    pub fn new() -> Self {
+        Self { inner: Shared::new(InnerDeque::new()) }
+    }
+
+    pub fn push_front(&self, val: T) {
+        self.inner.push_front(val);
+    }
+
+    pub fn push_back(&self, val: T) {
+        self.inner.push_back(val);
+    }
+
+    pub fn pop_front(&self) -> Option<T> {
+        self.inner.pop_front()
+    }
+
+    pub fn pop_back(&self) -> Option<T> {
+        self.inner.pop_back()
+    }
+
+    /// Takes the front element unless another thread holds the deque's lock, in which case it
+    /// returns `Steal::Retry` straight away instead of waiting for it.
+    pub fn steal(&self) -> Steal<T> {
+        self.inner.steal()
+    }
+
+    /// Returns the number of elements. Other threads may push or pop at any moment, so under
+    /// concurrency this is only a snapshot.
+    pub fn len(&self) -> usize {
+        self.inner.len.load(Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> Default for ConcurrentDeque<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Clone for ConcurrentDeque<T> {
+    fn clone(&self) -> Self {
+        ConcurrentDeque { inner: self.inner.clone() }
+    }
+}
+
+unsafe impl<T> Send for ConcurrentDeque<T> where T: Send {}
+unsafe impl<T> Sync for ConcurrentDeque<T> where T: Send {}
+
+
+#[cfg(all(test, not(feature = "loom")))]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_deque_owner_push_pop() {
+        let deque = ConcurrentDeque::new();
+        assert_eq!(deque.pop_back(), None);
+        assert_eq!(deque.pop_front(), None);
+
+        // Popping the end that was pushed to is LIFO, the other end is FIFO
+        for i in 0..5 {
+            deque.push_back(i);
+        }
+        assert_eq!(deque.pop_back(), Some(4));
+        assert_eq!(deque.pop_front(), Some(0));
+        deque.push_front(10);
+        assert_eq!(deque.len(), 4);
+        assert_eq!(deque.pop_front(), Some(10));
+        assert_eq!(deque.pop_back(), Some(3));
+
+        // Draining from either end leaves both ends consistent
+        assert_eq!(deque.pop_back(), Some(2));
+        assert_eq!(deque.pop_back(), Some(1));
+        assert_eq!(deque.pop_back(), None);
+        assert_eq!(deque.pop_front(), None);
+        deque.push_front(20);
+        assert_eq!(deque.pop_back(), Some(20));
+        assert!(deque.is_empty());
+    }
+
+    #[test]
+    fn test_deque_steal() {
+        let deque = ConcurrentDeque::new();
+        assert_eq!(deque.steal(), Steal::Empty);
+        deque.push_back(1);
+        deque.push_back(2);
+
+        // A held lock makes stealers back off rather than wait
+        deque.inner.sem.wait();
+        assert_eq!(deque.steal(), Steal::Retry);
+        deque.inner.sem.signal();
+
+        assert_eq!(deque.steal(), Steal::Success(1));
+        assert_eq!(deque.pop_back(), Some(2));
+        assert_eq!(deque.steal(), Steal::Empty);
+    }
+
+    #[test]
+    fn test_deque_drops_remaining_elements() {
+        let val = Arc::new(());
+        let deque = ConcurrentDeque::new();
+        for _ in 0..5 {
+            deque.push_front(val.clone());
+            deque.push_back(val.clone());
+        }
+        drop(deque.pop_front());
+        drop(deque.pop_back());
+        assert_eq!(Arc::strong_count(&val), 9);
+        drop(deque);
+        assert_eq!(Arc::strong_count(&val), 1);
+    }
+
+    #[test]
+    fn test_deque_concurrent_steals_take_everything_once() {
+        const JOBS: usize = 20_000;
+        let deque = ConcurrentDeque::new();
+        let mut seen = thread::scope(|s| {
+            let stealers: Vec<_> = (0..3).map(|_| {
+                let deque = &deque;
+                s.spawn(move || {
+                    let mut stolen = vec![];
+                    let mut last = None;
+                    while stolen.len() < JOBS / 4 {
+                        if let Steal::Success(job) = deque.steal() {
+                            // The owner pushes in order, so the front only ever moves forward
+                            assert!(last < Some(job), "stole {job} after {last:?}");
+                            last = Some(job);
+                            stolen.push(job);
+                        }
+                    }
+                    stolen
+                })
+            }).collect();
+
+            // The owner works LIFO at the back, keeping a few jobs back for itself
+            let mut own = vec![];
+            for job in 0..JOBS {
+                deque.push_back(job);
+                if job % 4 == 0 {
+                    own.extend(deque.pop_back());
+                }
+            }
+            let mut seen: Vec<_> = stealers.into_iter().flat_map(|jh| jh.join().expect("stealer panicked")).collect();
+            while let Some(job) = deque.pop_back() {
+                own.push(job);
+            }
+            seen.extend(own);
+            seen
+        });
+        seen.sort();
+        assert_eq!(seen, (0..JOBS).collect::<Vec<_>>());
+    }
+}
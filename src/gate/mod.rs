@@ -0,0 +1,113 @@
+use crate::sync::{wait, wake_all, AtomicU32};
+use crate::sync::Ordering::{Acquire, Release};
+use crate::shared::Shared;
+
+
+// atomic_wait only parks on 32-bit words, so the open flag is a u32 rather than an AtomicBool
+const CLOSED: u32 = 0;
+const OPEN: u32 = 1;
+
+struct InnerGate {
+    state: AtomicU32,
+}
+
+impl InnerGate {
+    fn wait(&self) {
+        // Acquire pairs with the Release in `open`, so whatever the opener did first is visible
+        while self.state.load(Acquire) == CLOSED {
+            wait(&self.state, CLOSED);
+        }
+    }
+
+    fn open(&self) {
+        if self.state.swap(OPEN, Release) == CLOSED {
+            wake_all(&self.state);
+        }
+    }
+
+    fn close(&self) {
+        self.state.store(CLOSED, Release);
+    }
+}
+
+/// An event that threads wait on until it is opened. Unlike a `Semaphore` there are no permits to
+/// use up: once open, every `wait` passes straight through until the gate is closed again.
+#[derive(Clone)]
+pub struct Gate {
+    inner: Shared<InnerGate>,
+}
+
+impl Gate {
+    /// Creates a closed gate.
+    pub fn new() -> Self {
+        Self { inner: Shared::new(InnerGate { state: AtomicU32::new(CLOSED) }) }
+    }
+
+    /// Blocks until the gate is open.
+    pub fn wait(&self) {
+        self.inner.wait();
+    }
+
+    /// Opens the gate, releasing every waiting thread.
+    pub fn open(&self) {
+        self.inner.open();
+    }
+
+    /// Closes the gate, so later calls to `wait` block again.
+    pub fn close(&self) {
+        self.inner.close();
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.inner.state.load(Acquire) == OPEN
+    }
+}
+
+impl Default for Gate {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl Send for Gate {}
+unsafe impl Sync for Gate {}
+
+
+#[cfg(all(test, not(feature = "loom")))]
+mod test {
+    use super::*;
+    use crate::sync::{AtomicUsize, Ordering::Relaxed};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_gate_open_releases_waiters_until_closed() {
+        let gate = Gate::new();
+        let passed = AtomicUsize::new(0);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    gate.wait();
+                    passed.fetch_add(1, Relaxed);
+                });
+            }
+            thread::sleep(Duration::from_millis(30));
+            assert_eq!(passed.load(Relaxed), 0);
+            gate.open();
+        });
+        assert_eq!(passed.load(Relaxed), 4);
+
+        // Open gates let later waiters straight through
+        gate.wait();
+        assert!(gate.is_open());
+
+        gate.close();
+        assert!(!gate.is_open());
+        thread::scope(|s| {
+            let waiter = s.spawn(|| gate.wait());
+            thread::sleep(Duration::from_millis(30));
+            assert!(!waiter.is_finished());
+            gate.open();
+        });
+    }
+}
@@ -0,0 +1,265 @@
+use core::ptr;
+use alloc::{boxed::Box, vec};
+use crate::sync::{fence, seq_cst_fence, AtomicBool, AtomicPtr, AtomicUsize};
+use crate::sync::Ordering::{Acquire, Release, Relaxed, SeqCst};
+
+
+// How many retired pointers pile up before `retire` scans for ones it can free. Every scan walks
+// all the hazard records, so this amortizes that walk over many retires
+pub(crate) const SCAN_THRESHOLD: usize = 64;
+
+// A hazard pointer slot, held by one thread at a time. Records are only freed along with their
+// domain, so the list can be walked without protecting anything
+struct HazardRecord {
+    hazard: AtomicPtr<()>,
+    active: AtomicBool,
+    // Set before the record is published and never changed after
+    next: *mut HazardRecord,
+}
+
+// A pointer waiting for no hazard to point at it, along with how to free it
+struct Retired {
+    ptr: *mut (),
+    free: unsafe fn(*mut ()),
+    next: *mut Retired,
+}
+
+unsafe fn free_box<T>(ptr: *mut ()) {
+    drop(Box::from_raw(ptr.cast::<T>()));
+}
+
+// Hazard pointer based reclamation (Michael, 2004) for one data structure. A thread about to
+// dereference a shared node first publishes it in a hazard slot, then checks that the node is still
+// reachable. Whoever unlinks a node retires it instead of freeing it, and a retired node is freed
+// by the first scan that finds no hazard pointing at it.
+//
+// Guarantees: a node is never freed while a hazard that was published before it was unlinked still
+// points at it. Every other retired node is freed by the next scan, so apart from batches that
+// concurrent scans are working through, about `SCAN_THRESHOLD` plus one node per hazard slot in
+// use are waiting at any time. Whatever is left is freed along with the domain.
+//
+// Slots are claimed per operation rather than cached per thread. Nothing is left behind when a
+// thread exits, and the domain can live inside each structure instead of in a static. The list only
+// grows to the largest number of operations that were ever in flight at once.
+pub(crate) struct HazardDomain {
+    records: AtomicPtr<HazardRecord>,
+    retired: AtomicPtr<Retired>,
+    // Counted before each push onto `retired`, so a scan never takes more than this says
+    retired_len: AtomicUsize,
+}
+
+impl HazardDomain {
+    pub(crate) fn new() -> Self {
+        Self {
+            records: AtomicPtr::new(ptr::null_mut()),
+            retired: AtomicPtr::new(ptr::null_mut()),
+            retired_len: AtomicUsize::new(0),
+        }
+    }
+
+    // Claims a hazard slot until the returned guard drops, adding one if every slot is in use
+    pub(crate) fn hazard(&self) -> Hazard<'_> {
+        let mut cur = self.records.load(Acquire);
+        // Safety: Records are only freed along with the domain, which we borrow
+        while let Some(record) = unsafe { cur.as_ref() } {
+            if !record.active.load(Relaxed) && record.active.compare_exchange(false, true, Acquire, Relaxed).is_ok() {
+                return Hazard { record };
+            }
+            cur = record.next;
+        }
+
+        let record = Box::into_raw(Box::new(HazardRecord {
+            hazard: AtomicPtr::new(ptr::null_mut()),
+            active: AtomicBool::new(true),
+            next: ptr::null_mut(),
+        }));
+        let mut head = self.records.load(Relaxed);
+        loop {
+            // Safety: Nobody else can see the record until the exchange below succeeds
+            unsafe { (*record).next = head; }
+            // Release publishes `next` to threads walking the list
+            match self.records.compare_exchange_weak(head, record, Release, Relaxed) {
+                Ok(_) => break,
+                Err(next) => head = next,
+            }
+        }
+        // Safety: As above, the record lives as long as the domain
+        Hazard { record: unsafe { &*record } }
+    }
+
+    // Hands over `ptr`, which must come from `Box::into_raw` and already be unreachable for threads
+    // that have not protected it, to be freed once no hazard points at it
+    pub(crate) unsafe fn retire<T>(&self, ptr: *mut T) {
+        let retired = Box::into_raw(Box::new(Retired { ptr: ptr.cast(), free: free_box::<T>, next: ptr::null_mut() }));
+        let len = self.retired_len.fetch_add(1, Relaxed) + 1;
+        self.push_retired(retired);
+        if len >= SCAN_THRESHOLD {
+            self.scan();
+        }
+    }
+
+    unsafe fn push_retired(&self, retired: *mut Retired) {
+        let mut head = self.retired.load(Relaxed);
+        loop {
+            (*retired).next = head;
+            match self.retired.compare_exchange_weak(head, retired, Release, Relaxed) {
+                Ok(_) => return,
+                Err(next) => head = next,
+            }
+        }
+    }
+
+    // Frees every retired pointer no hazard points at, putting the rest back. Returns how many
+    // were freed
+    pub(crate) fn scan(&self) -> usize {
+        // Taking the whole list means concurrent scans each work on their own batch
+        let mut cur = self.retired.swap(ptr::null_mut(), Acquire);
+        if cur.is_null() {
+            return 0;
+        }
+        // Every node here was unlinked before we took it. The fence puts this scan in a single order
+        // with the SeqCst hazard store and check in `Hazard::protect`, so a reader whose check
+        // still found a node reachable published its hazard before the loads below. Without it the
+        // hazard store and our loads could pass each other, leaving the reader on a freed node
+        fence(SeqCst);
+        let mut hazards = vec![];
+        let mut record = self.records.load(SeqCst);
+        // Safety: Records are only freed along with the domain, which we borrow
+        while let Some(rec) = unsafe { record.as_ref() } {
+            let hazard = rec.hazard.load(SeqCst);
+            if !hazard.is_null() {
+                hazards.push(hazard);
+            }
+            record = rec.next;
+        }
+
+        let mut freed = 0;
+        // Safety: The batch is ours alone, and each pointer is freed only once nothing guards it
+        unsafe {
+            while !cur.is_null() {
+                let retired = cur;
+                cur = (*retired).next;
+                if hazards.contains(&(*retired).ptr) {
+                    self.push_retired(retired);
+                    continue;
+                }
+                let retired = Box::from_raw(retired);
+                (retired.free)(retired.ptr);
+                self.retired_len.fetch_sub(1, Relaxed);
+                freed += 1;
+            }
+        }
+        freed
+    }
+
+    #[cfg(all(test, not(feature = "loom")))]
+    pub(crate) fn retired_len(&self) -> usize {
+        self.retired_len.load(Relaxed)
+    }
+}
+
+impl Drop for HazardDomain {
+    fn drop(&mut self) {
+        // Safety: We have exclusive access, so no hazard can be held and nothing else can touch the
+        // lists
+        unsafe {
+            let mut cur = self.retired.load(Relaxed);
+            while !cur.is_null() {
+                let retired = Box::from_raw(cur);
+                (retired.free)(retired.ptr);
+                cur = retired.next;
+            }
+            let mut cur = self.records.load(Relaxed);
+            while !cur.is_null() {
+                let record = Box::from_raw(cur);
+                cur = record.next;
+            }
+        }
+    }
+}
+
+// A claimed hazard slot. Dropping it clears the hazard and gives the slot back
+pub(crate) struct Hazard<'d> {
+    record: &'d HazardRecord,
+}
+
+impl Hazard<'_> {
+    // Publishes `ptr` as in use. This only protects it if the caller then checks that `ptr` is
+    // still reachable, since it may have been retired and scanned before the hazard was visible
+    pub(crate) fn protect<T>(&self, ptr: *mut T) {
+        // SeqCst orders the store before the caller's check, which must be a SeqCst load, pairing
+        // with the fence in `scan`
+        self.record.hazard.store(ptr.cast(), SeqCst);
+        seq_cst_fence();
+    }
+
+    pub(crate) fn clear(&self) {
+        self.record.hazard.store(ptr::null_mut(), Release);
+    }
+}
+
+impl Drop for Hazard<'_> {
+    fn drop(&mut self) {
+        self.clear();
+        self.record.active.store(false, Release);
+    }
+}
+
+
+#[cfg(all(test, not(feature = "loom")))]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_scan_frees_only_unprotected_pointers() {
+        let val = Arc::new(());
+        let domain = HazardDomain::new();
+        let protected = Box::into_raw(Box::new(val.clone()));
+        let hazard = domain.hazard();
+        hazard.protect(protected);
+        // Safety: Both pointers come from `Box::into_raw` and nobody else knows about them
+        unsafe {
+            domain.retire(protected);
+            domain.retire(Box::into_raw(Box::new(val.clone())));
+        }
+        assert_eq!(domain.scan(), 1);
+        assert_eq!(Arc::strong_count(&val), 2);
+        assert_eq!(domain.retired_len(), 1);
+
+        drop(hazard);
+        assert_eq!(domain.scan(), 1);
+        assert_eq!(Arc::strong_count(&val), 1);
+        assert_eq!(domain.scan(), 0);
+    }
+
+    #[test]
+    fn test_hazard_slots_are_reused() {
+        let domain = HazardDomain::new();
+        let first = domain.hazard().record as *const HazardRecord;
+        // The first slot is free again, so no new one is added
+        let again = domain.hazard();
+        assert_eq!(again.record as *const HazardRecord, first);
+        let other = domain.hazard();
+        assert_ne!(other.record as *const HazardRecord, first);
+    }
+
+    #[test]
+    fn test_retire_scans_past_threshold_and_drop_frees_the_rest() {
+        let val = Arc::new(());
+        let domain = HazardDomain::new();
+        for _ in 0..SCAN_THRESHOLD - 1 {
+            // Safety: Each pointer comes from `Box::into_raw` and nobody else knows about it
+            unsafe { domain.retire(Box::into_raw(Box::new(val.clone()))); }
+        }
+        assert_eq!(Arc::strong_count(&val), SCAN_THRESHOLD);
+        // Safety: As above
+        unsafe { domain.retire(Box::into_raw(Box::new(val.clone()))); }
+        assert_eq!(Arc::strong_count(&val), 1);
+
+        // Safety: As above
+        unsafe { domain.retire(Box::into_raw(Box::new(val.clone()))); }
+        drop(domain);
+        assert_eq!(Arc::strong_count(&val), 1);
+    }
+}
@@ -0,0 +1,24 @@
+// Tests always have std, they spawn threads to exercise the collections
+#![cfg_attr(all(not(feature = "std"), not(test)), no_std)]
+
+extern crate alloc;
+
+// RcuCache is built on HashMap, which needs std
+#[cfg(feature = "std")]
+pub mod cache;
+pub mod channel;
+pub mod config;
+pub mod deque;
+pub mod gate;
+pub mod pqueue;
+pub mod queue;
+pub mod rcu;
+pub mod ringbuf;
+pub mod semaphore;
+pub mod stack;
+
+mod backoff;
+mod hazard;
+mod shared;
+mod sync;
+mod tagged;
@@ -0,0 +1,213 @@
+use core::cell::UnsafeCell;
+use alloc::collections::BinaryHeap;
+use crate::semaphore::Semaphore;
+use crate::shared::Shared;
+
+
+struct InnerPriorityStack<T: Ord> {
+    // Guarded by `sem`
+    heap: UnsafeCell<BinaryHeap<T>>,
+    sem: Semaphore,
+    // Counts the free slots like `BoundedStack`: a push takes one before touching the heap and a
+    // pop gives one back
+    space: Semaphore,
+}
+
+impl<T: Ord> InnerPriorityStack<T> {
+    fn with_capacity(capacity: u32) -> Self {
+        let heap = UnsafeCell::new(BinaryHeap::new());
+        let sem = Semaphore::init_with(1, 1);
+        Self { heap, sem, space: Semaphore::new(capacity) }
+    }
+
+    // Must be called with a slot taken from `space`
+    fn push_reserved(&self, val: T) {
+        self.sem.wait();
+        // Safety: We hold the semaphore, so only this thread has access to the heap
+        unsafe { (*self.heap.get()).push(val); }
+        self.sem.signal();
+    }
+
+    fn push(&self, val: T) {
+        self.space.wait();
+        self.push_reserved(val);
+    }
+
+    fn try_push(&self, val: T) -> Result<(), T> {
+        if !self.space.try_wait() {
+            return Err(val);
+        }
+        self.push_reserved(val);
+        Ok(())
+    }
+
+    fn pop(&self) -> Option<T> {
+        self.sem.wait();
+        // Safety: We hold the semaphore, so only this thread has access to the heap
+        let res = unsafe { (*self.heap.get()).pop() };
+        self.sem.signal();
+        if res.is_some() {
+            self.space.signal();
+        }
+        res
+    }
+
+    fn peek(&self) -> Option<T>
+    where T: Clone
+    {
+        self.sem.wait();
+        // Safety: We hold the semaphore, so the heap cannot change while we clone its top
+        let res = unsafe { (*self.heap.get()).peek().cloned() };
+        self.sem.signal();
+        res
+    }
+
+    fn len(&self) -> usize {
+        self.sem.wait();
+        // Safety: We hold the semaphore, so only this thread has access to the heap
+        let len = unsafe { (*self.heap.get()).len() };
+        self.sem.signal();
+        len
+    }
+}
+
+/// A semaphore-guarded stack whose `pop` returns the greatest element rather than the most
+/// recently pushed one. Like `BoundedStack` it holds at most `capacity` elements, and `push` parks
+/// while it is full.
+pub struct PriorityStack<T: Ord> {
+    inner: Shared<InnerPriorityStack<T>>,
+}
+
+impl<T: Ord> PriorityStack<T> {
+    /// Creates a stack with room for `u32::MAX` elements, which in practice never fills up.
+    pub fn new() -> Self {
+        Self { inner: Shared::new(InnerPriorityStack::with_capacity(u32::MAX)) }
+    }
+
+    /// Panics if `capacity` is 0 or does not fit in a `u32`.
+    pub fn with_capacity(capacity: usize) -> Self {
+        let capacity = u32::try_from(capacity).expect("capacity must fit in a u32");
+        Self { inner: Shared::new(InnerPriorityStack::with_capacity(capacity)) }
+    }
+
+    /// Pushes `val`, parking until there is room for it if the stack is full.
+    pub fn push(&self, val: T) {
+        self.inner.push(val);
+    }
+
+    /// Pushes `val` if there is room for it right now, otherwise hands it back.
+    pub fn try_push(&self, val: T) -> Result<(), T> {
+        self.inner.try_push(val)
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        self.inner.pop()
+    }
+
+    pub fn peek(&self) -> Option<T>
+    where T: Clone
+    {
+        self.inner.peek()
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.inner.space.max_permits() as usize
+    }
+}
+
+impl<T: Ord> Default for PriorityStack<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Ord> Clone for PriorityStack<T> {
+    fn clone(&self) -> Self {
+        PriorityStack { inner: self.inner.clone() }
+    }
+}
+
+unsafe impl<T> Send for PriorityStack<T> where T: Ord + Send {}
+unsafe impl<T> Sync for PriorityStack<T> where T: Ord + Send {}
+
+
+#[cfg(all(test, not(feature = "loom")))]
+mod test {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_priority_stack_pops_in_descending_order() {
+        let stack = PriorityStack::new();
+        for val in [5, 1, 9, 3, 7, 2, 8] {
+            stack.push(val);
+        }
+        assert_eq!(stack.peek(), Some(9));
+        for expected in [9, 8, 7, 5, 3, 2, 1] {
+            assert_eq!(stack.pop(), Some(expected));
+        }
+        assert_eq!(stack.pop(), None);
+        assert_eq!(stack.peek(), None);
+    }
+
+    #[test]
+    fn test_priority_stack_multi_producer_multi_threaded() {
+        let stack = PriorityStack::new();
+
+        let mut producer_jhs = vec![];
+        for i in 0..3 {
+            let producer_stack = stack.clone();
+            producer_jhs.push(thread::spawn(move || {
+                for j in 0..1000 {
+                    producer_stack.push(3 * j + i);
+                }
+            }));
+        }
+
+        for jh in producer_jhs {
+            jh.join().expect("producer panicked");
+        }
+
+        let mut prev = None;
+        let mut count = 0;
+        while let Some(val) = stack.pop() {
+            if let Some(prev) = prev {
+                assert!(val < prev, "popped {val} after {prev}");
+            }
+            prev = Some(val);
+            count += 1;
+        }
+        assert_eq!(count, 3000);
+    }
+
+    #[test]
+    fn test_priority_stack_bounded() {
+        let stack = PriorityStack::with_capacity(2);
+        assert_eq!(stack.try_push(1), Ok(()));
+        assert_eq!(stack.try_push(3), Ok(()));
+        assert_eq!(stack.try_push(2), Err(2));
+        assert_eq!(stack.len(), stack.capacity());
+
+        thread::scope(|s| {
+            let pusher = s.spawn(|| stack.push(2));
+            thread::sleep(Duration::from_millis(30));
+            assert!(!pusher.is_finished());
+            // Popping frees a slot, and the greatest element comes out first
+            assert_eq!(stack.pop(), Some(3));
+            pusher.join().expect("pusher panicked");
+        });
+        assert_eq!(stack.pop(), Some(2));
+        assert_eq!(stack.pop(), Some(1));
+        assert_eq!(stack.pop(), None);
+        assert!(stack.is_empty());
+    }
+}
@@ -0,0 +1,334 @@
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::mem::MaybeUninit;
+use core::ptr;
+use alloc::boxed::Box;
+use crate::sync::{AtomicPtr, AtomicU32, AtomicU64};
+use crate::sync::Ordering::{AcqRel, Acquire, Release, Relaxed};
+use crate::shared::Shared;
+use crate::tagged::{pack, unpack};
+
+
+struct QueueNode<T> {
+    // Initialized from the enqueue that links the node in until the dequeue that makes it the
+    // sentinel takes it out
+    data: UnsafeCell<MaybeUninit<T>>,
+    // Tagged pointer to the next node. The tag is bumped on every change, including when the node
+    // is reused, so a lagging enqueuer's CAS against an old value fails
+    next: AtomicU64,
+    // Link for the free list
+    free_next: AtomicPtr<QueueNode<T>>,
+    // A node is done with once the dequeue that took its data and the dequeue that moved the head
+    // past it have both released it. Whichever is second puts it on the free list
+    releases: AtomicU32,
+}
+
+struct InnerQueue<T> {
+    // Both tagged pointers. `head` points at a sentinel whose data has already been taken, the
+    // first element lives in the node after it
+    head: AtomicU64,
+    tail: AtomicU64,
+    // Tagged head of a Treiber list of nodes ready for reuse. Nodes are only freed when the queue
+    // is dropped, so a thread that lost a race may still safely read `next` off any node it saw
+    free: AtomicU64,
+    phantom: PhantomData<Box<QueueNode<T>>>,
+}
+
+impl<T> InnerQueue<T> {
+    fn new() -> Self {
+        let sentinel = Box::into_raw(Box::new(QueueNode::<T> {
+            data: UnsafeCell::new(MaybeUninit::uninit()),
+            next: AtomicU64::new(pack::<QueueNode<T>>(ptr::null_mut(), 0)),
+            free_next: AtomicPtr::new(ptr::null_mut()),
+            // The first sentinel never held data, so nobody will release it for taking it
+            releases: AtomicU32::new(1),
+        }));
+        Self {
+            head: AtomicU64::new(pack(sentinel, 0)),
+            tail: AtomicU64::new(pack(sentinel, 0)),
+            free: AtomicU64::new(pack::<QueueNode<T>>(ptr::null_mut(), 0)),
+            phantom: PhantomData,
+        }
+    }
+
+    unsafe fn alloc_node(&self, val: T) -> *mut QueueNode<T> {
+        let mut cur = self.free.load(Acquire);
+        let node = loop {
+            let (node, tag) = unpack::<QueueNode<T>>(cur);
+            if node.is_null() {
+                return Box::into_raw(Box::new(QueueNode {
+                    data: UnsafeCell::new(MaybeUninit::new(val)),
+                    next: AtomicU64::new(pack::<QueueNode<T>>(ptr::null_mut(), 0)),
+                    free_next: AtomicPtr::new(ptr::null_mut()),
+                    releases: AtomicU32::new(0),
+                }));
+            }
+            let next = (*node).free_next.load(Relaxed);
+            match self.free.compare_exchange_weak(cur, pack(next, tag.wrapping_add(1)), Acquire, Acquire) {
+                Ok(_) => break node,
+                Err(word) => cur = word,
+            }
+        };
+        (*(*node).data.get()).write(val);
+        let (_, tag) = unpack::<QueueNode<T>>((*node).next.load(Relaxed));
+        (*node).next.store(pack::<QueueNode<T>>(ptr::null_mut(), tag.wrapping_add(1)), Relaxed);
+        (*node).releases.store(0, Relaxed);
+        node
+    }
+
+    unsafe fn release_node(&self, node: *mut QueueNode<T>) {
+        // AcqRel so that whichever side recycles the node sees the other side done with it
+        if (*node).releases.fetch_add(1, AcqRel) == 0 {
+            return;
+        }
+        let mut cur = self.free.load(Relaxed);
+        loop {
+            let (head, tag) = unpack::<QueueNode<T>>(cur);
+            (*node).free_next.store(head, Relaxed);
+            match self.free.compare_exchange_weak(cur, pack(node, tag.wrapping_add(1)), Release, Relaxed) {
+                Ok(_) => return,
+                Err(word) => cur = word,
+            }
+        }
+    }
+
+    fn enqueue(&self, val: T) {
+        // Safety: Nodes are never freed while the queue is alive, so every pointer we load stays
+        // valid memory even if the node is dequeued and reused under us; the tags catch that case
+        unsafe {
+            let node = self.alloc_node(val);
+            loop {
+                let tail = self.tail.load(Acquire);
+                let (tail_ptr, tail_tag) = unpack::<QueueNode<T>>(tail);
+                let next = (*tail_ptr).next.load(Acquire);
+                let (next_ptr, next_tag) = unpack::<QueueNode<T>>(next);
+                // Make sure `next` was read off the node that was still the tail
+                if tail != self.tail.load(Acquire) {
+                    continue;
+                }
+                if next_ptr.is_null() {
+                    // Release publishes the node's data to the dequeue that takes it
+                    if (*tail_ptr).next.compare_exchange(next, pack(node, next_tag.wrapping_add(1)), Release, Relaxed).is_ok() {
+                        // Swing the tail to our node. If this fails someone already helped
+                        let _ = self.tail.compare_exchange(tail, pack(node, tail_tag.wrapping_add(1)), Release, Relaxed);
+                        return;
+                    }
+                } else {
+                    // The tail is lagging behind a node another enqueue linked in, help it along
+                    let _ = self.tail.compare_exchange(tail, pack(next_ptr, tail_tag.wrapping_add(1)), Release, Relaxed);
+                }
+            }
+        }
+    }
+
+    fn dequeue(&self) -> Option<T> {
+        // Safety: See `enqueue`. Winning the CAS on `head` makes us the only thread to take the
+        // data out of the new sentinel, and it cannot be reused before we release it
+        unsafe {
+            loop {
+                let head = self.head.load(Acquire);
+                let (head_ptr, head_tag) = unpack::<QueueNode<T>>(head);
+                let tail = self.tail.load(Acquire);
+                let (tail_ptr, tail_tag) = unpack::<QueueNode<T>>(tail);
+                let (next_ptr, _) = unpack::<QueueNode<T>>((*head_ptr).next.load(Acquire));
+                if head != self.head.load(Acquire) {
+                    continue;
+                }
+                if head_ptr == tail_ptr {
+                    if next_ptr.is_null() {
+                        return None;
+                    }
+                    // An enqueue linked a node in but has not swung the tail yet, help it along so
+                    // the head never overtakes the tail
+                    let _ = self.tail.compare_exchange(tail, pack(next_ptr, tail_tag.wrapping_add(1)), Release, Relaxed);
+                    continue;
+                }
+                if self.head.compare_exchange(head, pack(next_ptr, head_tag.wrapping_add(1)), Acquire, Relaxed).is_ok() {
+                    let val = (*(*next_ptr).data.get()).assume_init_read();
+                    self.release_node(next_ptr);
+                    self.release_node(head_ptr);
+                    return Some(val);
+                }
+            }
+        }
+    }
+}
+
+impl<T> Drop for InnerQueue<T> {
+    fn drop(&mut self) {
+        while self.dequeue().is_some() {}
+        // Safety: There are no threads that have access to the queue. What is left is the sentinel,
+        // whose data has been taken, and the free list, whose nodes hold none
+        unsafe {
+            let (sentinel, _) = unpack::<QueueNode<T>>(self.head.load(Relaxed));
+            drop(Box::from_raw(sentinel));
+            let (mut cur, _) = unpack::<QueueNode<T>>(self.free.load(Relaxed));
+            while !cur.is_null() {
+                let node = Box::from_raw(cur);
+                cur = node.free_next.load(Relaxed);
+            }
+        }
+    }
+}
+
+/// A lock-free multi-producer multi-consumer FIFO queue (Michael and Scott). `enqueue` and
+/// `dequeue` never block each other; they race on the tail and head with a CAS instead. Like
+/// `LockFreeStack`, dequeued nodes are recycled rather than freed, so memory is only given back
+/// when the last handle is dropped.
+pub struct ConcurrentQueue<T> {
+    inner: Shared<InnerQueue<T>>,
+}
+
+impl<T> ConcurrentQueue<T> {
+    pub fn new() -> Self {
+        Self { inner: Shared::new(InnerQueue::new()) }
+    }
+
+    pub fn enqueue(&self, val: T) {
+        self.inner.enqueue(val);
+    }
+
+    pub fn dequeue(&self) -> Option<T> {
+        self.inner.dequeue()
+    }
+}
+
+impl<T> Default for ConcurrentQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Clone for ConcurrentQueue<T> {
+    fn clone(&self) -> Self {
+        ConcurrentQueue { inner: self.inner.clone() }
+    }
+}
+
+unsafe impl<T> Send for ConcurrentQueue<T> where T: Send {}
+unsafe impl<T> Sync for ConcurrentQueue<T> where T: Send {}
+
+
+#[cfg(all(test, not(feature = "loom")))]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    #[test]
+    fn test_queue_single_threaded() {
+        let queue = ConcurrentQueue::new();
+        assert_eq!(queue.dequeue(), None);
+        for i in 0..100 {
+            queue.enqueue(i);
+        }
+        for i in 0..100 {
+            assert_eq!(queue.dequeue(), Some(i));
+        }
+        assert_eq!(queue.dequeue(), None);
+
+        // Interleaved, so nodes go through the free list and back
+        for i in 0..100 {
+            queue.enqueue(2 * i);
+            queue.enqueue(2 * i + 1);
+            assert_eq!(queue.dequeue(), Some(i));
+        }
+    }
+
+    #[test]
+    fn test_queue_drops_remaining_elements() {
+        let val = Arc::new(());
+        let queue = ConcurrentQueue::new();
+        for _ in 0..10 {
+            queue.enqueue(val.clone());
+        }
+        drop(queue.dequeue());
+        assert_eq!(Arc::strong_count(&val), 10);
+        drop(queue);
+        assert_eq!(Arc::strong_count(&val), 1);
+    }
+
+    #[test]
+    fn test_queue_multi_producer_multi_consumer() {
+        const PRODUCERS: usize = 4;
+        const PER_PRODUCER: usize = 10_000;
+        let queue = ConcurrentQueue::new();
+        let dequeued = Arc::new(AtomicUsize::new(0));
+
+        let mut producer_jhs = vec![];
+        for i in 0..PRODUCERS {
+            let queue = queue.clone();
+            producer_jhs.push(thread::spawn(move || {
+                for j in 0..PER_PRODUCER {
+                    queue.enqueue((i, j));
+                }
+            }));
+        }
+
+        let mut consumer_jhs = vec![];
+        for _ in 0..4 {
+            let queue = queue.clone();
+            let dequeued = dequeued.clone();
+            consumer_jhs.push(thread::spawn(move || {
+                let mut seen = vec![];
+                while dequeued.load(Relaxed) < PRODUCERS * PER_PRODUCER {
+                    if let Some(val) = queue.dequeue() {
+                        dequeued.fetch_add(1, Relaxed);
+                        seen.push(val);
+                    } else {
+                        thread::yield_now();
+                    }
+                }
+                seen
+            }));
+        }
+
+        for jh in producer_jhs {
+            jh.join().expect("producer panicked");
+        }
+        let mut counts = [0; PRODUCERS];
+        for jh in consumer_jhs {
+            let mut last = [None; PRODUCERS];
+            for (i, j) in jh.join().expect("consumer panicked") {
+                // Each producer's elements come out in the order it enqueued them
+                assert!(last[i] < Some(j), "producer {i}: {j} after {:?}", last[i]);
+                last[i] = Some(j);
+                counts[i] += 1;
+            }
+        }
+        assert_eq!(counts, [PER_PRODUCER; PRODUCERS]);
+        assert_eq!(queue.dequeue(), None);
+    }
+}
+
+#[cfg(all(test, feature = "loom"))]
+mod loom_test {
+    use super::*;
+    use loom::thread;
+
+    #[test]
+    fn concurrent_enqueue_dequeue_keeps_fifo() {
+        loom::model(|| {
+            let queue = ConcurrentQueue::new();
+            let producer = {
+                let queue = queue.clone();
+                thread::spawn(move || {
+                    queue.enqueue(1);
+                    queue.enqueue(2);
+                })
+            };
+            let consumer = {
+                let queue = queue.clone();
+                thread::spawn(move || queue.dequeue())
+            };
+
+            producer.join().unwrap();
+            let mut dequeued: Vec<_> = consumer.join().unwrap().into_iter().collect();
+            while let Some(val) = queue.dequeue() {
+                dequeued.push(val);
+            }
+            assert_eq!(dequeued, vec![1, 2]);
+        });
+    }
+}
@@ -1,18 +1,22 @@
-use std::sync::atomic::{AtomicUsize, AtomicU32, AtomicBool, AtomicPtr, fence, Ordering::{Release, Acquire, Relaxed}};
-use std::ptr::NonNull;
-use atomic_wait::{wait, wake_one, wake_all};
+use crate::sync::{seq_cst_fence, wait, wake_all, AtomicU32, AtomicU64, AtomicPtr, Ordering::{Release, Acquire, Relaxed, SeqCst}};
+use core::cell::UnsafeCell;
+use core::ops::Deref;
+use core::ptr;
+use alloc::{boxed::Box, vec, vec::Vec};
+use crate::semaphore::Semaphore;
+use crate::shared::{Shared, WeakShared};
 
 
 #[derive(Debug)]
 pub struct InnerRcuNode<T> {
-    ref_count: AtomicUsize,
+    version: u64,
     data: Option<T>
 }
 
 impl<T: Clone> InnerRcuNode<T> {
-    fn new(data: T) -> Self {
+    fn new(data: T, version: u64) -> Self {
         Self {
-            ref_count: AtomicUsize::new(1),
+            version,
             data: Some(data),
         }
     }
@@ -21,142 +25,1495 @@ impl<T: Clone> InnerRcuNode<T> {
         self.data.as_ref().unwrap().clone()
     }
 
+    fn data(&self) -> &T {
+        self.data.as_ref().unwrap()
+    }
+
     fn take(&mut self) -> Option<T> {
         self.data.take()
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct RcuNode<T: Clone> {
-    inner: NonNull<InnerRcuNode<T>>,
+    inner: Shared<InnerRcuNode<T>>,
 }
 
 impl<T: Clone> RcuNode<T> {
     pub fn new(data: T) -> Self {
-        let inner = NonNull::new(Box::into_raw(Box::new(InnerRcuNode::new(data)))).expect("pointer should not be null");
-        Self { inner }
+        Self::with_version(data, 0)
+    }
+
+    fn with_version(data: T, version: u64) -> Self {
+        Self { inner: Shared::new(InnerRcuNode::new(data, version)) }
+    }
+
+    fn version(&self) -> u64 {
+        self.inner.version
     }
 
     pub fn copy(&self) -> T {
-        // Safety: inner pointer will never be null
-        unsafe {
-            self.inner.as_ref().copy()
-        }
+        self.inner.copy()
+    }
+
+    pub(crate) fn data(&self) -> &T {
+        self.inner.data()
     }
 
+    /// Creates a weak reference to this node, which does not keep its value alive.
+    pub fn downgrade(&self) -> WeakRcuNode<T> {
+        WeakRcuNode { inner: Shared::downgrade(&self.inner) }
+    }
+
+    // Only for nodes no other handle can see, such as one that lost the race to be installed
     fn take(&mut self) -> Option<T> {
-        // Safety: inner pointer will never be null
-        unsafe {
-            self.inner.as_mut().take()
-        }
+        // Safety: Callers guarantee no other handle is reading the node
+        unsafe { (*self.inner.as_ptr()).take() }
     }
 }
 
-impl<T: Clone> Clone for RcuNode<T> {
-    fn clone(&self) -> Self {
-        // Safety: inner pointer will never be null
-        unsafe {
-            self.inner.as_ref().ref_count.fetch_add(1, Relaxed);
-        }
-        Self { inner: self.inner }
+// Every node a caller can get hold of, from `read` or `RcuNode::new`, holds its value. `take` only
+// empties nodes that lost the race to be installed, and those are never handed out, so the unwrap
+// in `data` cannot fire here.
+impl<T: Clone> Deref for RcuNode<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.data()
     }
 }
 
-impl<T: Clone> Drop for RcuNode<T> {
-    fn drop(&mut self) {
-        // Safety: inner pointer will never be null up to this point
-        unsafe {
-            if self.inner.as_ref().ref_count.fetch_sub(1, Release) == 1 {
-                fence(Acquire);
-                // there are exactly zero references remaining to the inner pointer at this point
-                // so it is safe to deallocate
-                drop(Box::from_raw(self.inner.as_ptr()));
-            }
-        }
+/// A reference to an `RcuNode` that does not keep its value alive, e.g. for caches that should not
+/// hold back reclamation. Get the node back with `upgrade`.
+pub struct WeakRcuNode<T: Clone> {
+    inner: WeakShared<InnerRcuNode<T>>,
+}
+
+impl<T: Clone> WeakRcuNode<T> {
+    /// Returns the node if any strong reference to it is still alive.
+    pub fn upgrade(&self) -> Option<RcuNode<T>> {
+        self.inner.upgrade().map(|inner| RcuNode { inner })
     }
 }
 
+impl<T: Clone> Clone for WeakRcuNode<T> {
+    fn clone(&self) -> Self {
+        Self { inner: self.inner.clone() }
+    }
+}
+
+// No retired nodes are waiting to be freed
 const DEFAULT: u32 = 0;
+// Retired nodes are waiting for in-flight readers to drain
 const NEW_EPOCH_INIT: u32 = 1;
-
+// A thread has claimed the retired list and is reclaiming it
 const NEW_EPOCH_COMMIT: u32 = 2;
-
+// The reclaiming thread has freed what it could and put the rest back, and is releasing the list
 const NEW_EPOCH_FINAL: u32 = 3;
 
+/// Where an `Rcu` is in reclaiming replaced values, as reported by `Rcu::epoch_state`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EpochState {
+    /// No replaced value is waiting to be freed.
+    Default,
+    /// Replaced values are waiting for the reads in flight to finish.
+    NewEpochInit,
+    /// A thread is freeing the replaced values.
+    NewEpochCommit,
+    /// A thread has finished freeing and is handing back the values it could not free yet.
+    NewEpochFinal,
+}
+
+impl EpochState {
+    fn from_raw(state: u32) -> Self {
+        match state {
+            DEFAULT => EpochState::Default,
+            NEW_EPOCH_INIT => EpochState::NewEpochInit,
+            NEW_EPOCH_COMMIT => EpochState::NewEpochCommit,
+            NEW_EPOCH_FINAL => EpochState::NewEpochFinal,
+            _ => unreachable!("invalid rcu state {state}"),
+        }
+    }
+}
+
+// The only legal moves are DEFAULT -> NEW_EPOCH_INIT -> NEW_EPOCH_COMMIT -> NEW_EPOCH_FINAL ->
+// DEFAULT. `old` is the state the swap or exchange actually replaced, not the one it expected.
+#[cfg(debug_assertions)]
+fn check_transition(old: u32, new: u32) {
+    assert!(
+        matches!(
+            (old, new),
+            (DEFAULT, NEW_EPOCH_INIT)
+                | (NEW_EPOCH_INIT, NEW_EPOCH_COMMIT)
+                | (NEW_EPOCH_COMMIT, NEW_EPOCH_FINAL)
+                | (NEW_EPOCH_FINAL, DEFAULT)
+        ),
+        "illegal rcu state transition {old} -> {new}"
+    );
+}
+
+#[cfg(not(debug_assertions))]
+fn check_transition(_old: u32, _new: u32) {}
+
+
+struct RetiredNode<T: Clone> {
+    node: *mut RcuNode<T>,
+    // The epoch once the node was unlinked. Only reads pinned in it or earlier can still see it
+    epoch: u64,
+    next: *mut RetiredNode<T>,
+}
+
+// The `num_reads` slot that reads pinned in `epoch` are counted in
+fn slot(epoch: u64) -> usize {
+    (epoch & 1) as usize
+}
+
+type Listener<T> = Box<dyn Fn(&T) + Send>;
 
-#[derive(Debug)]
 struct InnerRcu<T: Clone> {
-    ref_count: AtomicUsize,
-    num_reads: AtomicU32,
+    // Reads in flight, split by the parity of the epoch they pinned in. New reads only go into the
+    // slot for the current epoch, so the other one only ever drains
+    num_reads: [AtomicU32; 2],
+    // Only moves on once the slot the next epoch uses has drained, see `try_flip`
+    epoch: AtomicU64,
     state: AtomicU32,
     cur_alloc: AtomicPtr<RcuNode<T>>,
-    prev_alloc: AtomicPtr<RcuNode<T>>,
+    retired: AtomicPtr<RetiredNode<T>>,
+    version: AtomicU64,
+    // Futex word for `wait_for_version`, bumped after every change to `version`
+    version_seq: AtomicU32,
+    // Guarded by `listeners_sem`
+    listeners: UnsafeCell<Vec<Listener<T>>>,
+    listeners_sem: Semaphore,
+    // Number of threads blocked in `synchronize`, so the last reader out only wakes when needed
+    sync_waiters: AtomicU32,
+    // Futex word for `synchronize`, bumped whenever a slot drains or the epoch moves on
+    sync_seq: AtomicU32,
+    // Counts how often `reclaim` tries to claim the retired list
+    #[cfg(test)]
+    reclaim_attempts: crate::sync::AtomicUsize,
 }
 
 impl<T: Clone> InnerRcu<T> {
     fn new(data: T) -> Self {
         let inner_alloc = Box::into_raw(Box::new(RcuNode::new(data)));
         Self {
-            ref_count: AtomicUsize::new(1),
-            num_reads: AtomicU32::new(0),
-            state: AtomicU32::new(0),
+            num_reads: [AtomicU32::new(0), AtomicU32::new(0)],
+            epoch: AtomicU64::new(0),
+            state: AtomicU32::new(DEFAULT),
             cur_alloc: AtomicPtr::new(inner_alloc),
-            prev_alloc: AtomicPtr::new(inner_alloc),
+            retired: AtomicPtr::new(ptr::null_mut()),
+            version: AtomicU64::new(0),
+            version_seq: AtomicU32::new(0),
+            listeners: UnsafeCell::new(vec![]),
+            listeners_sem: Semaphore::init_with(1, 1),
+            sync_waiters: AtomicU32::new(0),
+            sync_seq: AtomicU32::new(0),
+            #[cfg(test)]
+            reclaim_attempts: crate::sync::AtomicUsize::new(0),
         }
     }
 
-    unsafe fn read(&self) -> RcuNode<T> {
-        self.num_reads.fetch_add(1, Relaxed);
+    // Returns the slot to hand back to `unpin`
+    fn pin(&self) -> usize {
+        loop {
+            let epoch = self.epoch.load(SeqCst);
+            // SeqCst orders our increment before the load of `cur_alloc` that follows, pairing with
+            // the swap in `install` and the loads of `num_reads` in `reclaim` and `try_flip`: either
+            // they see us in flight, or we see the node that replaced the one they are checking.
+            self.num_reads[slot(epoch)].fetch_add(1, SeqCst);
+            seq_cst_fence();
+            // If the epoch moved on in between, the slot may already have been seen drained, so
+            // only count there while it is still the current one
+            if self.epoch.load(SeqCst) == epoch {
+                return slot(epoch);
+            }
+            // Safety: We have not dereferenced anything since the increment
+            unsafe { self.unpin(slot(epoch)); }
+        }
+    }
 
-        let node = (*self.cur_alloc.load(Relaxed)).clone();
+    unsafe fn unpin(&self, slot: usize) {
+        let last = self.num_reads[slot].fetch_sub(1, SeqCst) == 1;
+        if last {
+            self.wake_synchronizers();
+        }
+        // Only bother claiming the retired list if something was retired, and the epoch can move
+        // on: we drained our slot, or the other one already has. Every retire happens while its
+        // writer is pinned, so if we miss one here that writer, or whichever reader drains a slot
+        // later, sees the flag on its own way out.
+        if self.state.load(SeqCst) == NEW_EPOCH_INIT && (last || self.num_reads[slot ^ 1].load(SeqCst) == 0) {
+            self.reclaim();
+        }
+    }
 
-        if self.num_reads.fetch_sub(1, Release) == 1 && self.state.compare_exchange(NEW_EPOCH_INIT, NEW_EPOCH_COMMIT, Relaxed, Relaxed).is_ok() {
-            fence(Acquire);
-            self.state.store(NEW_EPOCH_FINAL, Release);
-            wake_one(&self.state);
+    // Moves the epoch on from `epoch` if the slot the next epoch pins into has drained. New reads
+    // then go there, and the slot for `epoch` starts draining in turn. Returns false if the slot
+    // still has reads in flight or another thread moved the epoch first.
+    fn try_flip(&self, epoch: u64) -> bool {
+        seq_cst_fence();
+        if self.num_reads[slot(epoch + 1)].load(SeqCst) != 0 {
+            return false;
         }
+        let flipped = self.epoch.compare_exchange(epoch, epoch + 1, SeqCst, Relaxed).is_ok();
+        if flipped {
+            self.wake_synchronizers();
+        }
+        flipped
+    }
 
+    fn wake_synchronizers(&self) {
+        // SeqCst pairs with `synchronize`: either it sees the slot drained or the epoch moved on, or
+        // we see it waiting
+        seq_cst_fence();
+        if self.sync_waiters.load(SeqCst) > 0 {
+            self.sync_seq.fetch_add(1, SeqCst);
+            wake_all(&self.sync_seq);
+        }
+    }
+
+    // Must not be called while pinned, or the slot we pinned in never drains
+    unsafe fn synchronize(&self) {
+        self.sync_waiters.fetch_add(1, SeqCst);
+        seq_cst_fence();
+        // Every read in flight now pinned in this epoch or the one before. The flip to `target - 1`
+        // waits for the slot of the one before to drain and the flip to `target` for this one, while
+        // reads that start meanwhile go into the slot that is not being waited on
+        let target = self.epoch.load(SeqCst) + 2;
+        loop {
+            // Load the futex word first, so a drain or flip that lands before we sleep wakes us
+            let seq = self.sync_seq.load(SeqCst);
+            let epoch = self.epoch.load(SeqCst);
+            if epoch >= target {
+                break;
+            }
+            if !self.try_flip(epoch) {
+                wait(&self.sync_seq, seq);
+            }
+        }
+        self.sync_waiters.fetch_sub(1, SeqCst);
+        // Nothing retired before now can be seen any more, so free it rather than leaving it to the
+        // next reader
+        self.reclaim();
+    }
+
+    unsafe fn read(&self) -> RcuNode<T> {
+        let slot = self.pin();
+        let node = (*self.cur_alloc.load(SeqCst)).clone();
+        self.unpin(slot);
         node
     }
 
     unsafe fn update(&self, new_data: T) -> Result<(), T> {
-        let mut neo = Box::into_raw(Box::new(RcuNode::new(new_data)));
-        let prev_ptr = self.prev_alloc.load(Acquire);
-
-        if self.cur_alloc.compare_exchange(prev_ptr, neo, Relaxed, Relaxed).is_ok() {
-            self.state.store(NEW_EPOCH_INIT, Release);
-
-            if self.num_reads.load(Relaxed) != 0 {
-                loop {
-                    let cur_state = self.state.load(Relaxed);
-                    if cur_state == 1 {
-                        wait(&self.state, 1);
-                    } else if cur_state == 2 {
-                        wait(&self.state, 2);
-                    } else {
-                        break;
-                    }
-                }
-            }
+        let slot = self.pin();
+        let cur_ptr = self.cur_alloc.load(SeqCst);
+        let res = self.install(cur_ptr, new_data);
+        self.unpin(slot);
+        res
+    }
+
+    unsafe fn update_from<F: FnMut(&T) -> Option<T>>(&self, mut f: F) -> bool {
+        loop {
+            // Stay pinned for the whole read-modify-write, so the node we compare against cannot be
+            // reclaimed (and its address reused) before our compare_exchange.
+            let slot = self.pin();
+            let cur_ptr = self.cur_alloc.load(SeqCst);
+
+            let installed = match f((*cur_ptr).data()) {
+                // On conflict another writer won, so retry against its value
+                Some(new_data) => self.install(cur_ptr, new_data).is_ok().then_some(true),
+                None => Some(false),
+            };
 
-            // acquire matches the release store of `self.num_reads` and the store of NEW_EPOCH_FINAL
-            // on `self.state`.
-            fence(Acquire);
+            self.unpin(slot);
 
-            // change the state back to default
-            self.state.store(DEFAULT, Relaxed);
+            if let Some(installed) = installed {
+                return installed;
+            }
+        }
+    }
+
+    unsafe fn compare_and_update(&self, expected: &T, mut new_data: T) -> Result<(), T>
+    where T: PartialEq
+    {
+        loop {
+            // Pinned from the comparison through the install, as in `update_from`
+            let slot = self.pin();
+            let cur_ptr = self.cur_alloc.load(SeqCst);
+            if (*cur_ptr).data() != expected {
+                self.unpin(slot);
+                return Err(new_data);
+            }
+            let res = self.install(cur_ptr, new_data);
+            self.unpin(slot);
+            match res {
+                Ok(()) => return Ok(()),
+                // Another writer got in first, its value may still match
+                Err(val) => new_data = val,
+            }
+        }
+    }
 
-            // no other thread will dereference this pointer at this point
-            std::ptr::drop_in_place(prev_ptr);
-            self.prev_alloc.store(neo, Release);
+    // Must be called while pinned, so that `cur_ptr` cannot be reclaimed under us
+    unsafe fn install(&self, cur_ptr: *mut RcuNode<T>, new_data: T) -> Result<(), T> {
+        let version = (*cur_ptr).version() + 1;
+        let neo = Box::into_raw(Box::new(RcuNode::with_version(new_data, version)));
 
+        if self.cur_alloc.compare_exchange(cur_ptr, neo, SeqCst, Relaxed).is_ok() {
+            // Installs can finish out of order, fetch_max keeps the counter monotonic
+            self.version.fetch_max(version, Release);
+            self.version_seq.fetch_add(1, Release);
+            wake_all(&self.version_seq);
+            // Readers may still be cloning the old node, so rather than waiting for them we defer
+            // freeing it until no read is in flight.
+            self.retire(cur_ptr);
+            // We are still pinned, so `neo` stays alive while the listeners look at it
+            self.notify((*neo).data());
             Ok(())
         } else {
             // Safety: no other thread has access to this pointer
-            let err_val = (*neo).take().expect("option should not be none");
+            let mut rejected = Box::from_raw(neo);
+            let err_val = rejected.take().expect("option should not be none");
             Err(err_val)
         }
     }
 
+    fn on_update<F: Fn(&T) + Send + 'static>(&self, f: F) {
+        self.listeners_sem.wait();
+        // Safety: We hold the semaphore, so only this thread has access to the listeners
+        unsafe { (*self.listeners.get()).push(Box::new(f)); }
+        self.listeners_sem.signal();
+    }
+
+    // The listeners run with `listeners_sem` held, so one that updates this Rcu would park on it
+    // in its own nested `notify`. `Rcu::on_update` rules that out.
+    fn notify(&self, data: &T) {
+        self.listeners_sem.wait();
+        // Safety: As in `on_update`
+        for listener in unsafe { &*self.listeners.get() } {
+            listener(data);
+        }
+        self.listeners_sem.signal();
+    }
+
+    fn wait_for_version(&self, target: u64) {
+        loop {
+            // Load the futex word before checking, so an install that lands in between changes it
+            // and `wait` returns straight away instead of sleeping through the wake
+            let seq = self.version_seq.load(Acquire);
+            if self.version.load(Acquire) >= target {
+                return;
+            }
+            wait(&self.version_seq, seq);
+        }
+    }
+
+    unsafe fn read_versioned(&self) -> (T, u64) {
+        // The version travels with the node, so the pair is always consistent
+        let node = self.read();
+        let version = node.version();
+        (node.copy(), version)
+    }
+
+    unsafe fn retire(&self, node: *mut RcuNode<T>) {
+        // SeqCst keeps the load after the swap that unlinked `node` in `install`
+        let epoch = self.epoch.load(SeqCst);
+        let retired = Box::into_raw(Box::new(RetiredNode { node, epoch, next: ptr::null_mut() }));
+        self.push_retired(retired, retired);
+        // If a reclaimer currently holds NEW_EPOCH_COMMIT or NEW_EPOCH_FINAL this fails, but the reclaimer re-checks
+        // the retired list once it is done and flags it for us.
+        self.transition(DEFAULT, NEW_EPOCH_INIT);
+    }
+
+    // Moves `state` from `old` to `new`, returning false if it was not `old`
+    fn transition(&self, old: u32, new: u32) -> bool {
+        match self.state.compare_exchange(old, new, SeqCst, Relaxed) {
+            Ok(observed) => {
+                check_transition(observed, new);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    unsafe fn push_retired(&self, first: *mut RetiredNode<T>, last: *mut RetiredNode<T>) {
+        let mut head = self.retired.load(Relaxed);
+        loop {
+            (*last).next = head;
+            match self.retired.compare_exchange(head, first, SeqCst, Relaxed) {
+                Ok(_) => break,
+                Err(next) => head = next,
+            }
+        }
+    }
+
+    unsafe fn reclaim(&self) -> usize {
+        let mut freed = 0;
+        loop {
+            #[cfg(test)]
+            self.reclaim_attempts.fetch_add(1, Relaxed);
+            // Only one thread reclaims at a time
+            if !self.transition(NEW_EPOCH_INIT, NEW_EPOCH_COMMIT) {
+                return freed;
+            }
+
+            // Move the epoch on as far as the drained slots allow. Reads that could see a node
+            // pinned in the epoch it was retired in or earlier, so two flips later both slots have
+            // drained of them, however many reads started since.
+            for _ in 0..2 {
+                self.try_flip(self.epoch.load(SeqCst));
+            }
+            let epoch = self.epoch.load(SeqCst);
+
+            let mut cur = self.retired.swap(ptr::null_mut(), SeqCst);
+            let (mut kept, mut kept_last): (*mut RetiredNode<T>, _) = (ptr::null_mut(), ptr::null_mut());
+            while !cur.is_null() {
+                let next = (*cur).next;
+                if (*cur).epoch + 2 <= epoch {
+                    let retired = Box::from_raw(cur);
+                    drop(Box::from_raw(retired.node));
+                    freed += 1;
+                } else {
+                    if kept.is_null() {
+                        kept_last = cur;
+                    }
+                    (*cur).next = kept;
+                    kept = cur;
+                }
+                cur = next;
+            }
+            if !kept.is_null() {
+                self.push_retired(kept, kept_last);
+            }
+
+            // Nobody else moves the state while we hold it, so each swap must find the one before
+            let old = self.state.swap(NEW_EPOCH_FINAL, SeqCst);
+            check_transition(old, NEW_EPOCH_FINAL);
+            let old = self.state.swap(DEFAULT, SeqCst);
+            check_transition(old, DEFAULT);
+
+            if self.retired.load(SeqCst).is_null()
+                || !self.transition(DEFAULT, NEW_EPOCH_INIT) {
+                return freed;
+            }
+            // Nodes are still pending. Whoever drains the slot the next flip needs sees
+            // NEW_EPOCH_INIT on its way out and reclaims them, but if it has drained already, go
+            // around again ourselves.
+            if self.num_reads[slot(self.epoch.load(SeqCst) + 1)].load(SeqCst) != 0 {
+                return freed;
+            }
+        }
+    }
+}
+
+impl<T: Clone> Drop for InnerRcu<T> {
+    fn drop(&mut self) {
+        // Safety: we have exclusive access, so no reader can be looking at any of these nodes
+        unsafe {
+            drop(Box::from_raw(self.cur_alloc.load(Relaxed)));
+            let mut cur = self.retired.load(Relaxed);
+            while !cur.is_null() {
+                let retired = Box::from_raw(cur);
+                drop(Box::from_raw(retired.node));
+                cur = retired.next;
+            }
+        }
+    }
+}
+
+/// A pinned, zero-copy view of the value an `Rcu` held when the guard was taken. Values replaced
+/// while a guard is alive are not freed until it drops, so guards should be short-lived.
+pub struct RcuReadGuard<'a, T: Clone> {
+    rcu: &'a InnerRcu<T>,
+    node: *mut RcuNode<T>,
+    slot: usize,
+}
+
+impl<T: Clone> RcuReadGuard<'_, T> {
+    /// Whether an update has replaced the value this guard is looking at.
+    pub fn is_stale(&self) -> bool {
+        self.rcu.cur_alloc.load(SeqCst) != self.node
+    }
+}
+
+impl<T: Clone> Deref for RcuReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // Safety: We are pinned, so `node` cannot be reclaimed while the guard is alive
+        unsafe { (*self.node).data() }
+    }
+}
+
+impl<T: Clone> Drop for RcuReadGuard<'_, T> {
+    fn drop(&mut self) {
+        // Safety: We pinned in `read_guard` and have not unpinned since
+        unsafe { self.rcu.unpin(self.slot); }
+    }
+}
+
+/// A read-copy-update cell. Readers always get a complete value without blocking, while writers
+/// install a new value that readers pick up from then on. Handles can be cloned and sent to other
+/// threads, and the value is freed along with the last handle:
+///
+/// ```
+/// use concurrent_collections::rcu::Rcu;
+/// use std::thread;
+///
+/// let rcu = Rcu::new(0u32);
+/// let writer = {
+///     let rcu = rcu.clone();
+///     thread::spawn(move || {
+///         for i in 1..=100 {
+///             rcu.update(i).expect("nobody else is writing");
+///         }
+///     })
+/// };
+/// let reader = {
+///     let rcu = rcu.clone();
+///     thread::spawn(move || {
+///         // Each read sees one of the values the writer installed, never going backwards
+///         let mut last = 0;
+///         while last < 100 {
+///             let cur = rcu.read().copy();
+///             assert!(cur >= last);
+///             last = cur;
+///         }
+///     })
+/// };
+/// writer.join().unwrap();
+/// reader.join().unwrap();
+/// assert_eq!(rcu.read().copy(), 100);
+/// ```
+pub struct Rcu<T: Clone> {
+    inner: Shared<InnerRcu<T>>,
+}
+
+impl<T: Clone> Rcu<T> {
+    pub fn new(data: T) -> Self {
+        Self { inner: Shared::new(InnerRcu::new(data)) }
+    }
+
+    /// Returns a handle on the current value that keeps it alive across later updates. Deref it
+    /// to read fields in place, or `copy` it to clone the whole value out.
+    pub fn read(&self) -> RcuNode<T> {
+        // Safety: The handle keeps `inner` alive, and InnerRcu pins every node it dereferences
+        unsafe { self.inner.read() }
+    }
+
+    /// Borrows the current value without cloning anything, pinning it until the guard drops.
+    pub fn read_guard(&self) -> RcuReadGuard<'_, T> {
+        let rcu = &*self.inner;
+        let slot = rcu.pin();
+        RcuReadGuard { rcu, node: rcu.cur_alloc.load(SeqCst), slot }
+    }
+
+    /// Runs `f` on the current value in place and returns its result, without cloning the value.
+    /// Handy for pulling one entry out of a large collection:
+    ///
+    /// ```
+    /// use concurrent_collections::rcu::Rcu;
+    ///
+    /// let names = Rcu::new(vec![String::from("ada"), String::from("grace")]);
+    /// assert_eq!(names.read_map(|names| names[1].clone()), "grace");
+    /// assert_eq!(names.read_map(|names| names.len()), 2);
+    /// ```
+    pub fn read_map<R, F: FnOnce(&T) -> R>(&self, f: F) -> R {
+        f(&self.read_guard())
+    }
+
+    /// Runs `f` on a pinned guard, like `read_map`, and also reports whether an update replaced
+    /// the value at any point while the guard was pinned. The check is made as the guard drops, so
+    /// `true` means whatever `f` worked out may already be superseded and is worth redoing.
+    /// `RcuReadGuard::is_stale` checks the same thing from inside `f`.
+    pub fn read_guard_checked<R, F: FnOnce(&RcuReadGuard<'_, T>) -> R>(&self, f: F) -> (R, bool) {
+        let guard = self.read_guard();
+        let res = f(&guard);
+        // Still pinned, so the node cannot have been freed and its address reused by a newer one
+        let stale = guard.is_stale();
+        drop(guard);
+        (res, stale)
+    }
+
+    /// Installs `data` without waiting for readers: the replaced value goes on the retired list
+    /// and is freed once no read can still see it, by the last reader out, `synchronize` or
+    /// `reclaim`. If another writer installs between this one loading the current value and
+    /// swapping it out, nothing is installed and `data` comes back in `Err`; use `update_with` to
+    /// retry instead.
+    pub fn update(&self, data: T) -> Result<(), T> {
+        // Safety: The handle keeps `inner` alive, and InnerRcu pins every node it dereferences
+        unsafe { self.inner.update(data) }
+    }
+
+    /// Returns the number of successful updates so far. Cheap enough to poll, so readers can cache
+    /// a value and only re-read once the version moves.
+    pub fn version(&self) -> u64 {
+        self.inner.version.load(Acquire)
+    }
+
+    /// Blocks until `version()` has reached `target`, e.g. to make sure an update made by another
+    /// thread is visible before reading.
+    pub fn wait_for_version(&self, target: u64) {
+        self.inner.wait_for_version(target)
+    }
+
+    /// Returns the raw reclamation state, for tests and debugging: 0 when nothing is waiting to be
+    /// freed, 1 when retired values are waiting on readers, 2 while a thread is freeing them.
+    pub fn debug_state(&self) -> u32 {
+        self.inner.state.load(SeqCst)
+    }
+
+    /// The number of reads in flight, counting every live `RcuReadGuard`. A relaxed snapshot for
+    /// metrics and debugging, e.g. to see whether replaced values are stuck behind a reader; it
+    /// may be out of date by the time it returns.
+    pub fn active_readers(&self) -> u32 {
+        self.inner.num_reads.iter().map(|reads| reads.load(Relaxed)).sum()
+    }
+
+    /// The reclamation state, as a relaxed snapshot with the same caveats as `active_readers`.
+    /// Staying at `NewEpochInit` while `active_readers` is non-zero means a reader is holding
+    /// replaced values back.
+    pub fn epoch_state(&self) -> EpochState {
+        EpochState::from_raw(self.inner.state.load(Relaxed))
+    }
+
+    /// Blocks until every read that was in flight when it was called has finished, like
+    /// `synchronize_rcu`. Once it returns no reader can still see a value replaced before the call,
+    /// so resources tied to it can be released; the replaced values themselves are freed as well.
+    /// Reads that start after the call are not waited for, so a steady stream of overlapping
+    /// readers cannot hold it up forever. Calling it while holding an `RcuReadGuard` deadlocks.
+    pub fn synchronize(&self) {
+        // Safety: The handle keeps `inner` alive, and `synchronize` only reclaims once nobody is
+        // pinned
+        unsafe { self.inner.synchronize() }
+    }
+
+    /// Frees every retired node no reader can still see and returns how many were freed. Readers
+    /// on their way out already do this, so this only finds work when a reader raced an update on
+    /// its way out; applications can call it e.g. when idle rather than waiting for the next update.
+    pub fn reclaim(&self) -> usize {
+        // Safety: The handle keeps `inner` alive, and `reclaim` only frees nodes while nobody is
+        // pinned, the same as when a reader calls it on its way out
+        unsafe { self.inner.reclaim() }
+    }
+
+    /// Reads the current value together with the version it was installed at.
+    pub fn read_versioned(&self) -> (T, u64) {
+        // Safety: The handle keeps `inner` alive, and InnerRcu pins every node it dereferences
+        unsafe { self.inner.read_versioned() }
+    }
+
+    /// Registers a callback that the updating thread invokes with the new value after each
+    /// successful update. Callbacks run one at a time, with the lock that guards the callback list
+    /// held, so a callback must not register further callbacks or update this `Rcu` (through any
+    /// handle): either one waits for that lock and deadlocks the updating thread.
+    pub fn on_update<F: Fn(&T) + Send + 'static>(&self, f: F) {
+        self.inner.on_update(f);
+    }
+
+    /// Applies `f` to a clone of the current value and installs the result in one update, so readers
+    /// see either none or all of the mutations `f` makes. Retries against the newer value if
+    /// another writer installs first, so `f` may run more than once.
+    pub fn update_batch<F: FnMut(&mut T)>(&self, mut f: F) {
+        self.update_from(|cur| {
+            let mut next = cur.clone();
+            f(&mut next);
+            Some(next)
+        });
+    }
+
+    /// Installs `f(current)`, calling `f` again with the newer value whenever another writer
+    /// installs first, so concurrent read-modify-writes never lose an update.
+    pub fn update_with<F: FnMut(&T) -> T>(&self, mut f: F) {
+        self.update_from(|cur| Some(f(cur)));
+    }
+
+    /// Like `AtomicUsize::fetch_update`: installs `f(current)`, calling `f` again with the newer
+    /// value whenever another writer installs first. Returns `Ok(previous)` once an update sticks,
+    /// or `Err(current)` without installing anything if `f` returns `None`.
+    pub fn fetch_update<F: FnMut(&T) -> Option<T>>(&self, mut f: F) -> Result<T, T> {
+        let mut prev = None;
+        let installed = self.update_from(|cur| {
+            prev = Some(cur.clone());
+            f(cur)
+        });
+        let prev = prev.expect("update_from always reads the current value");
+        if installed { Ok(prev) } else { Err(prev) }
+    }
+
+    /// Installs `new` only if the current value equals `expected`, otherwise hands `new` back in
+    /// `Err` and leaves the value alone. This compares values, not nodes: if the value was changed
+    /// and then changed back to something equal, the update still goes through.
+    pub fn compare_and_update(&self, expected: &T, new: T) -> Result<(), T>
+    where T: PartialEq
+    {
+        // Safety: The handle keeps `inner` alive, and InnerRcu pins every node it dereferences
+        unsafe { self.inner.compare_and_update(expected, new) }
+    }
+
+    /// Installs `f(current)` as a single read-modify-write, retrying against the newer value if
+    /// another writer installs first. If `f` returns `None` nothing is installed and this returns
+    /// `false`.
+    pub(crate) fn update_from<F: FnMut(&T) -> Option<T>>(&self, f: F) -> bool {
+        // Safety: The handle keeps `inner` alive, and InnerRcu pins every node it dereferences
+        unsafe { self.inner.update_from(f) }
+    }
+}
+
+impl<T: Clone> Rcu<Vec<T>> {
+    /// Clones out the element at `index`, or returns `None` if it is out of bounds. Only that
+    /// element is cloned, never the whole vector.
+    pub fn index_read(&self, index: usize) -> Option<T> {
+        self.read_map(|vec| vec.get(index).cloned())
+    }
+}
+
+impl<T: Clone> Clone for Rcu<T> {
+    fn clone(&self) -> Self {
+        Rcu { inner: self.inner.clone() }
+    }
+}
+
+unsafe impl<T> Send for Rcu<T> where T: Clone + Send + Sync {}
+unsafe impl<T> Sync for Rcu<T> where T: Clone + Send + Sync {}
+
+
+#[cfg(all(test, not(feature = "loom")))]
+mod test {
+    use super::*;
+    use crate::sync::{AtomicBool, AtomicUsize};
+    use std::sync::Arc;
+    use std::thread;
+
+    #[derive(Clone)]
+    struct DropCounter(Arc<AtomicUsize>);
+
+    impl Drop for DropCounter {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_rcu_update_does_not_wait_for_readers() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let rcu = Rcu::new(DropCounter(drops.clone()));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        // A long-lived reader holding on to the initial node
+        let pinned = rcu.read();
+
+        let mut reader_jhs = vec![];
+        for _ in 0..3 {
+            let rcu = rcu.clone();
+            let stop = stop.clone();
+            reader_jhs.push(thread::spawn(move || {
+                let mut reads = 0;
+                while !stop.load(Relaxed) {
+                    let _node = rcu.read();
+                    reads += 1;
+                }
+                reads
+            }));
+        }
+
+        for _ in 0..100 {
+            assert!(rcu.update(DropCounter(drops.clone())).is_ok());
+        }
+
+        stop.store(true, Relaxed);
+        for jh in reader_jhs {
+            let reads = jh.join().expect("reader panicked");
+            println!("reader performed {reads} reads");
+        }
+
+        // Everything but the initial node (kept alive by `pinned`) and the current one is gone
+        assert_eq!(drops.load(Relaxed), 99);
+        drop(pinned);
+        assert_eq!(drops.load(Relaxed), 100);
+
+        drop(rcu);
+        assert_eq!(drops.load(Relaxed), 101);
+    }
+
+    #[test]
+    fn test_rcu_update_frees_old_node_without_readers() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let rcu = InnerRcu::new(DropCounter(drops.clone()));
+        for i in 1..=10 {
+            assert!(unsafe { rcu.update(DropCounter(drops.clone())) }.is_ok());
+            // No read was in flight, so the replaced node is reclaimed straight away
+            assert_eq!(drops.load(Relaxed), i);
+            assert_eq!(rcu.state.load(Relaxed), DEFAULT);
+        }
+    }
+
+    #[test]
+    fn test_rcu_overlapping_readers_do_not_hold_back_reclamation() {
+        const UPDATES: usize = 1000;
+        let drops = Arc::new(AtomicUsize::new(0));
+        let rcu = Rcu::new(DropCounter(drops.clone()));
+
+        // Each guard is taken before the last one drops, so a read is in flight the whole time, as
+        // with readers that keep overlapping
+        let mut guard = rcu.read_guard();
+        for i in 1..=UPDATES {
+            assert!(rcu.update(DropCounter(drops.clone())).is_ok());
+            let next = rcu.read_guard();
+            drop(guard);
+            guard = next;
+            // Only values the last couple of guards could see may still be waiting
+            let waiting = i - drops.load(Relaxed);
+            assert!(waiting <= 2, "{waiting} replaced values still waiting after {i} updates");
+        }
+        drop(guard);
+        assert_eq!(drops.load(Relaxed), UPDATES);
+        assert_eq!(rcu.debug_state(), DEFAULT);
+    }
+
+    #[test]
+    fn test_rcu_version_counts_successful_updates() {
+        let rcu = Rcu::new(String::from("v0"));
+        assert_eq!(rcu.version(), 0);
+        assert_eq!(rcu.read_versioned(), (String::from("v0"), 0));
+
+        for i in 1..=5 {
+            assert!(rcu.update(format!("v{i}")).is_ok());
+            assert_eq!(rcu.version(), i);
+            assert_eq!(rcu.read_versioned(), (format!("v{i}"), i));
+        }
+
+        // A declined read-modify-write installs nothing and leaves the version alone
+        assert!(!rcu.update_from(|_| None));
+        assert_eq!(rcu.version(), 5);
+    }
+
+    #[test]
+    fn test_rcu_install_conflict_keeps_version() {
+        let rcu = InnerRcu::new(0);
+        unsafe {
+            let slot = rcu.pin();
+            let stale = rcu.cur_alloc.load(SeqCst);
+            assert!(rcu.install(stale, 1).is_ok());
+            // A second writer still holding the old pointer loses the race and gets its value back
+            assert_eq!(rcu.install(stale, 2), Err(2));
+            rcu.unpin(slot);
+
+            assert_eq!(rcu.version.load(Relaxed), 1);
+            assert_eq!(rcu.read_versioned(), (1, 1));
+        }
+    }
+
+    #[test]
+    fn test_rcu_racing_updates_hand_back_losing_values() {
+        let rcu = Rcu::new(0);
+        let (won, lost): (Vec<_>, Vec<_>) = thread::scope(|s| {
+            let writers: Vec<_> = (0..4).map(|i| {
+                let rcu = &rcu;
+                s.spawn(move || {
+                    (0..500).map(|j| {
+                        let val = 1000 * i + j + 1;
+                        match rcu.update(val) {
+                            Ok(()) => Ok(val),
+                            Err(back) => {
+                                // The loser gets exactly its own value back
+                                assert_eq!(back, val);
+                                Err(back)
+                            }
+                        }
+                    }).collect::<Vec<_>>()
+                })
+            }).collect();
+            writers.into_iter().flat_map(|jh| jh.join().expect("writer panicked")).partition(Result::is_ok)
+        });
+        println!("{} updates won, {} lost", won.len(), lost.len());
+
+        // Only winning updates move the version, and the final value is one of them
+        assert_eq!(won.len() + lost.len(), 2000);
+        assert_eq!(rcu.version(), won.len() as u64);
+        assert!(won.contains(&Ok(rcu.read().copy())));
+    }
+
+    #[test]
+    fn test_rcu_on_update_notifies_every_listener() {
+        let rcu = Rcu::new(0);
+        let first = Arc::new(AtomicUsize::new(0));
+        let second = Arc::new(AtomicUsize::new(0));
+
+        let seen = first.clone();
+        rcu.on_update(move |&val| { seen.store(val, Relaxed); });
+        let seen = second.clone();
+        rcu.on_update(move |&val| { seen.fetch_add(val, Relaxed); });
+
+        for val in 1..=3 {
+            assert!(rcu.update(val).is_ok());
+            assert_eq!(first.load(Relaxed), val);
+        }
+        assert_eq!(second.load(Relaxed), 1 + 2 + 3);
+
+        assert!(rcu.update_from(|&val| Some(val * 10)));
+        assert_eq!(first.load(Relaxed), 30);
+        assert_eq!(second.load(Relaxed), 36);
+    }
+
+    #[test]
+    fn test_rcu_update_batch_is_atomic() {
+        #[derive(Clone)]
+        struct Pair { a: u32, b: u32 }
+
+        let rcu = Rcu::new(Pair { a: 0, b: 0 });
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let mut reader_jhs = vec![];
+        for _ in 0..2 {
+            let rcu = rcu.clone();
+            let stop = stop.clone();
+            reader_jhs.push(thread::spawn(move || {
+                while !stop.load(Relaxed) {
+                    let node = rcu.read();
+                    let pair = node.data();
+                    assert_eq!(pair.a, pair.b);
+                }
+            }));
+        }
+
+        for _ in 0..1000 {
+            rcu.update_batch(|pair| {
+                pair.a += 1;
+                pair.b += 1;
+            });
+        }
+        stop.store(true, Relaxed);
+        for jh in reader_jhs {
+            jh.join().expect("reader saw a torn update");
+        }
+
+        let node = rcu.read();
+        assert_eq!((node.data().a, node.data().b), (1000, 1000));
+    }
+
+    #[test]
+    fn test_rcu_wait_for_version() {
+        let rcu = Rcu::new(0u32);
+        let waiter_rcu = rcu.clone();
+        let waiter_jh = thread::spawn(move || {
+            waiter_rcu.wait_for_version(3);
+            *waiter_rcu.read().data()
+        });
+
+        for val in 1..=3 {
+            thread::sleep(std::time::Duration::from_millis(10));
+            assert!(rcu.update(val * 10).is_ok());
+        }
+
+        // The waiter may only wake once the third update is installed
+        assert_eq!(waiter_jh.join().expect("waiter panicked"), 30);
+        // Already reached, returns immediately
+        rcu.wait_for_version(2);
+    }
+
+    #[test]
+    fn test_rcu_read_only_workload_skips_reclaim() {
+        let rcu = Rcu::new(7u32);
+        let inner = &*rcu.inner;
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        assert_eq!(*rcu.read().data(), 7);
+                    }
+                });
+            }
+        });
+        // Nothing was ever retired, so no reader tried to claim the retired list
+        assert_eq!(inner.reclaim_attempts.load(Relaxed), 0);
+
+        assert!(rcu.update(8).is_ok());
+        assert_eq!(inner.reclaim_attempts.load(Relaxed), 1);
+        assert_eq!(*rcu.read().data(), 8);
+        assert_eq!(inner.reclaim_attempts.load(Relaxed), 1);
+    }
+
+    #[test]
+    fn test_rcu_fast_path_still_reclaims_racing_updates() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let rcu = Rcu::new(DropCounter(drops.clone()));
+        let stop = AtomicBool::new(false);
+        thread::scope(|s| {
+            for _ in 0..3 {
+                s.spawn(|| {
+                    while !stop.load(Relaxed) {
+                        let _node = rcu.read();
+                    }
+                });
+            }
+            for _ in 0..200 {
+                assert!(rcu.update(DropCounter(drops.clone())).is_ok());
+            }
+            stop.store(true, Relaxed);
+        });
+
+        // Once every reader has left, each replaced node must have been freed
+        assert_eq!(drops.load(Relaxed), 200);
+        assert_eq!(rcu.inner.state.load(Relaxed), DEFAULT);
+    }
+
+    #[test]
+    fn test_rcu_fetch_update_concurrent_increments() {
+        let rcu = Rcu::new(0u32);
+        let successes = AtomicUsize::new(0);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..250 {
+                        let prev = rcu.fetch_update(|&val| Some(val + 1)).expect("f never declines");
+                        assert!(prev < 1000);
+                        successes.fetch_add(1, Relaxed);
+                    }
+                });
+            }
+        });
+        assert_eq!(*rcu.read().data() as usize, successes.load(Relaxed));
+        assert_eq!(rcu.version(), 1000);
+
+        // Declining leaves the value alone and reports what it was
+        assert_eq!(rcu.fetch_update(|_| None), Err(1000));
+        assert_eq!(rcu.fetch_update(|&val| Some(val * 2)), Ok(1000));
+        assert_eq!(*rcu.read().data(), 2000);
+    }
+
+    #[test]
+    fn test_rcu_compare_and_update() {
+        let rcu = Rcu::new(0u32);
+        let expected = *rcu.read();
+        assert!(rcu.update(1).is_ok());
+
+        // A stale expectation is rejected without installing anything
+        assert_eq!(rcu.compare_and_update(&expected, 2), Err(2));
+        assert_eq!(*rcu.read(), 1);
+        assert_eq!(rcu.version(), 1);
+
+        assert_eq!(rcu.compare_and_update(&1, 2), Ok(()));
+        assert_eq!(*rcu.read(), 2);
+
+        // Only equality is checked, so a value that came back around matches again
+        assert!(rcu.update(expected).is_ok());
+        assert_eq!(rcu.compare_and_update(&expected, 3), Ok(()));
+        assert_eq!(rcu.version(), 4);
+
+        // Racing increments built on it never lose an update
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..250 {
+                        let mut cur = *rcu.read();
+                        while rcu.compare_and_update(&cur, cur + 1).is_err() {
+                            cur = *rcu.read();
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(*rcu.read(), 1003);
+    }
+
+    #[test]
+    fn test_rcu_update_with_concurrent_writers() {
+        let rcu = Rcu::new(0u64);
+        thread::scope(|s| {
+            for i in 1..=4 {
+                let rcu = &rcu;
+                s.spawn(move || {
+                    for _ in 0..500 {
+                        rcu.update_with(|&cur| cur + i);
+                    }
+                });
+            }
+        });
+        assert_eq!(rcu.read().copy(), 500 * (1 + 2 + 3 + 4));
+        assert_eq!(rcu.version(), 2000);
+    }
+
+    #[test]
+    fn test_rcu_read_guard_checked() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let rcu = Rcu::new(DropCounter(drops.clone()));
+
+        let (seen, stale) = rcu.read_guard_checked(|guard| guard.0.load(Relaxed));
+        assert_eq!(seen, 0);
+        assert!(!stale);
+
+        let (seen, stale) = rcu.read_guard_checked(|guard| {
+            // The writer is not blocked by the guard, but the node the guard borrows must outlive it
+            thread::scope(|s| {
+                s.spawn(|| assert!(rcu.update(DropCounter(drops.clone())).is_ok()));
+            });
+            assert!(guard.is_stale());
+            assert_eq!(drops.load(Relaxed), 0);
+            guard.0.load(Relaxed)
+        });
+        // An update that landed while the guard was pinned is reported once it drops
+        assert!(stale);
+        assert_eq!(seen, 0);
+        assert_eq!(drops.load(Relaxed), 1);
+
+        let (_, stale) = rcu.read_guard_checked(|guard| assert!(!guard.is_stale()));
+        assert!(!stale);
+    }
+
+    #[test]
+    fn test_rcu_state_machine_legal_sequences() {
+        let rcu = Rcu::new(0u32);
+        assert_eq!(rcu.debug_state(), DEFAULT);
+        for i in 1..=5 {
+            assert!(rcu.update(i).is_ok());
+            assert_eq!(*rcu.read().data(), i);
+            assert_eq!(rcu.debug_state(), DEFAULT);
+        }
+
+        // A pinned guard holds retired values back, leaving them flagged for reclamation
+        let guard = rcu.read_guard();
+        assert!(rcu.update(6).is_ok());
+        assert_eq!(rcu.debug_state(), NEW_EPOCH_INIT);
+        drop(guard);
+        assert_eq!(rcu.debug_state(), DEFAULT);
+
+        // Racing readers and writers go through every transition without tripping the checker
+        thread::scope(|s| {
+            for _ in 0..2 {
+                s.spawn(|| {
+                    for _ in 0..500 {
+                        let _node = rcu.read();
+                    }
+                });
+                s.spawn(|| {
+                    for _ in 0..100 {
+                        rcu.update_batch(|val| *val += 1);
+                    }
+                });
+            }
+        });
+        assert_eq!(*rcu.read().data(), 206);
+        assert_eq!(rcu.debug_state(), DEFAULT);
+    }
+
+    #[test]
+    fn test_rcu_active_readers_and_epoch_state() {
+        use std::sync::Barrier;
+
+        let rcu = Rcu::new(0u32);
+        assert_eq!(rcu.active_readers(), 0);
+        assert_eq!(rcu.epoch_state(), EpochState::Default);
+
+        let pinned = Barrier::new(2);
+        let release = Barrier::new(2);
+        thread::scope(|s| {
+            let reader = s.spawn(|| {
+                let guard = rcu.read_guard();
+                pinned.wait();
+                release.wait();
+                *guard
+            });
+            pinned.wait();
+            assert_eq!(rcu.active_readers(), 1);
+
+            // The update cannot free the old value while the reader still holds it
+            assert!(rcu.update(1).is_ok());
+            assert_eq!(rcu.epoch_state(), EpochState::NewEpochInit);
+
+            release.wait();
+            assert_eq!(reader.join().expect("reader panicked"), 0);
+        });
+        assert_eq!(rcu.active_readers(), 0);
+        assert_eq!(rcu.epoch_state(), EpochState::Default);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "illegal rcu state transition")]
+    fn test_rcu_check_transition_rejects_skipping_init() {
+        check_transition(DEFAULT, NEW_EPOCH_COMMIT);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "illegal rcu state transition")]
+    fn test_rcu_check_transition_rejects_skipping_final() {
+        check_transition(NEW_EPOCH_COMMIT, DEFAULT);
+    }
+
+    #[test]
+    fn test_rcu_epoch_state_maps_every_state() {
+        assert_eq!(EpochState::from_raw(DEFAULT), EpochState::Default);
+        assert_eq!(EpochState::from_raw(NEW_EPOCH_INIT), EpochState::NewEpochInit);
+        assert_eq!(EpochState::from_raw(NEW_EPOCH_COMMIT), EpochState::NewEpochCommit);
+        assert_eq!(EpochState::from_raw(NEW_EPOCH_FINAL), EpochState::NewEpochFinal);
+    }
+
+    #[test]
+    fn test_rcu_index_read_clones_one_element() {
+        static CLONES: AtomicUsize = AtomicUsize::new(0);
+
+        struct Tracked(String);
+
+        impl Clone for Tracked {
+            fn clone(&self) -> Self {
+                CLONES.fetch_add(1, Relaxed);
+                Tracked(self.0.clone())
+            }
+        }
+
+        let rcu = Rcu::new(vec![Tracked(String::from("a")), Tracked(String::from("b")), Tracked(String::from("c"))]);
+        assert_eq!(rcu.index_read(1).map(|tracked| tracked.0), Some(String::from("b")));
+        assert!(rcu.index_read(3).is_none());
+        assert_eq!(rcu.read_map(|vec| vec.len()), 3);
+        assert_eq!(CLONES.load(Relaxed), 1);
+
+        let rcu = Rcu::new(vec![String::from("x"), String::from("y")]);
+        assert_eq!(rcu.index_read(0), Some(String::from("x")));
+        assert!(rcu.update(vec![String::from("z")]).is_ok());
+        assert_eq!(rcu.index_read(0), Some(String::from("z")));
+    }
+
+    #[test]
+    fn test_rcu_reclaim_frees_retired_nodes() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let rcu = Rcu::new(DropCounter(drops.clone()));
+
+        let guard = rcu.read_guard();
+        for _ in 0..3 {
+            assert!(rcu.update(DropCounter(drops.clone())).is_ok());
+        }
+        // The guard pins every node retired since it was taken
+        assert_eq!(rcu.reclaim(), 0);
+        assert_eq!(drops.load(Relaxed), 0);
+        assert_eq!(rcu.debug_state(), NEW_EPOCH_INIT);
+
+        // Leave the way a reader that lost the race with a reclaimer does, without reclaiming
+        let slot = guard.slot;
+        std::mem::forget(guard);
+        rcu.inner.num_reads[slot].fetch_sub(1, SeqCst);
+        assert_eq!(drops.load(Relaxed), 0);
+
+        assert_eq!(rcu.reclaim(), 3);
+        assert_eq!(drops.load(Relaxed), 3);
+        assert_eq!(rcu.debug_state(), DEFAULT);
+        assert_eq!(rcu.reclaim(), 0);
+
+        // A reader leaving normally frees what it held back itself
+        let guard = rcu.read_guard();
+        assert!(rcu.update(DropCounter(drops.clone())).is_ok());
+        drop(guard);
+        assert_eq!(drops.load(Relaxed), 4);
+        assert_eq!(rcu.reclaim(), 0);
+    }
+
+    #[test]
+    fn test_rcu_synchronize_waits_for_pinned_readers() {
+        use std::time::Duration;
+
+        let drops = Arc::new(AtomicUsize::new(0));
+        let rcu = Rcu::new(DropCounter(drops.clone()));
+        let guard = rcu.read_guard();
+        assert!(rcu.update(DropCounter(drops.clone())).is_ok());
+
+        thread::scope(|s| {
+            let writer = s.spawn(|| rcu.synchronize());
+            thread::sleep(Duration::from_millis(30));
+            assert!(!writer.is_finished());
+            drop(guard);
+            writer.join().expect("writer panicked");
+        });
+        assert_eq!(drops.load(Relaxed), 1);
+        assert_eq!(rcu.debug_state(), DEFAULT);
+
+        // With nothing in flight it returns straight away
+        rcu.synchronize();
+    }
+
+    #[test]
+    fn test_rcu_synchronize_leaves_no_reader_on_old_value() {
+        const UPDATES: usize = 200;
+        // How many readers currently hold a guard on each value
+        let holders: Vec<_> = (0..=UPDATES).map(|_| AtomicUsize::new(0)).collect();
+        let rcu = Rcu::new(0);
+        let stop = AtomicBool::new(false);
+
+        thread::scope(|s| {
+            for _ in 0..3 {
+                s.spawn(|| {
+                    while !stop.load(Relaxed) {
+                        let guard = rcu.read_guard();
+                        holders[*guard].fetch_add(1, SeqCst);
+                        thread::yield_now();
+                        holders[*guard].fetch_sub(1, SeqCst);
+                        drop(guard);
+                    }
+                });
+            }
+
+            for i in 1..=UPDATES {
+                assert!(rcu.update(i).is_ok());
+                rcu.synchronize();
+                let old = holders[i - 1].load(SeqCst);
+                assert_eq!(old, 0, "{old} readers still hold value {} after synchronize", i - 1);
+            }
+            stop.store(true, Relaxed);
+        });
+    }
+
+    #[test]
+    fn test_rcu_interleaved_updates_free_every_value_once() {
+        const WRITERS: usize = 4;
+        const PER_WRITER: usize = 2_000;
+        const VALUES: usize = WRITERS * PER_WRITER + 1;
+
+        // Drops per value id. Values are only ever moved, never cloned, so each id must be dropped
+        // exactly once whether it was installed, replaced or handed back by a losing update
+        struct Tracked(usize, Arc<Vec<AtomicUsize>>);
+
+        impl Clone for Tracked {
+            fn clone(&self) -> Self {
+                unreachable!("values are never cloned");
+            }
+        }
+
+        impl Drop for Tracked {
+            fn drop(&mut self) {
+                self.1[self.0].fetch_add(1, Relaxed);
+            }
+        }
+
+        let drops: Arc<Vec<_>> = Arc::new((0..VALUES).map(|_| AtomicUsize::new(0)).collect());
+        let rcu = Rcu::new(Tracked(0, drops.clone()));
+        let stop = AtomicBool::new(false);
+        let lost = thread::scope(|s| {
+            for _ in 0..2 {
+                s.spawn(|| {
+                    while !stop.load(Relaxed) {
+                        // Mix in both kinds of read so updates race pinned readers on every path
+                        let guard = rcu.read_guard();
+                        let node = rcu.read();
+                        assert!(guard.0 < VALUES && node.0 < VALUES);
+                    }
+                });
+            }
+            let writers: Vec<_> = (0..WRITERS).map(|i| {
+                let (rcu, drops) = (&rcu, &drops);
+                s.spawn(move || {
+                    (0..PER_WRITER)
+                        .filter(|j| rcu.update(Tracked(1 + i * PER_WRITER + j, drops.clone())).is_err())
+                        .count()
+                })
+            }).collect();
+            let lost: usize = writers.into_iter().map(|jh| jh.join().expect("writer panicked")).sum();
+            stop.store(true, Relaxed);
+            lost
+        });
+        assert_eq!(rcu.version() as usize, WRITERS * PER_WRITER - lost);
+
+        // With every reader gone only the current value may still be alive
+        rcu.reclaim();
+        let current = rcu.read_map(|val| val.0);
+        for (id, count) in drops.iter().enumerate() {
+            assert_eq!(count.load(Relaxed), usize::from(id != current), "value {id}");
+        }
+        drop(rcu);
+        assert!(drops.iter().all(|count| count.load(Relaxed) == 1));
+    }
+
+    #[test]
+    fn test_rcu_node_deref_reads_in_place() {
+        #[derive(Clone)]
+        struct Settings {
+            name: String,
+            limits: Vec<u32>,
+        }
+
+        let rcu = Rcu::new(Settings { name: String::from("v1"), limits: vec![1, 2, 3] });
+        let node = rcu.read();
+        assert_eq!(node.name, "v1");
+        assert_eq!(node.limits.len(), 3);
+
+        // The node keeps the value it was read with after an update replaces it
+        assert!(rcu.update(Settings { name: String::from("v2"), limits: vec![] }).is_ok());
+        assert_eq!(node.name, "v1");
+        assert!(rcu.read().limits.is_empty());
+    }
+
+    #[test]
+    fn test_rcu_weak_node_upgrade() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let node = RcuNode::new(DropCounter(drops.clone()));
+        let weak = node.downgrade();
+        assert!(weak.upgrade().is_some());
+
+        drop(node);
+        assert_eq!(drops.load(Relaxed), 1);
+        assert!(weak.upgrade().is_none());
+
+        // A weak reference to a value read out of an Rcu goes dead once the value is replaced and
+        // every reader has let go of it
+        let rcu = Rcu::new(DropCounter(drops.clone()));
+        let weak = rcu.read().downgrade();
+        assert!(weak.upgrade().is_some());
+        assert!(rcu.update(DropCounter(drops.clone())).is_ok());
+        assert!(weak.upgrade().is_none());
+        assert_eq!(drops.load(Relaxed), 2);
+    }
+
+    #[test]
+    fn test_rcu_weak_node_clones_outlive_value() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let node = RcuNode::new(DropCounter(drops.clone()));
+        let weak = node.downgrade();
+        let weak2 = weak.clone();
+
+        // An upgraded node keeps the value alive on its own, like any other strong handle
+        let upgraded = weak2.upgrade().expect("node is still alive");
+        drop(node);
+        assert_eq!(drops.load(Relaxed), 0);
+        drop(upgraded);
+        assert_eq!(drops.load(Relaxed), 1);
+
+        // The weak handles and their clones keep working once the value is gone, without dropping
+        // it again
+        let weak3 = weak.clone();
+        drop(weak);
+        assert!(weak2.upgrade().is_none() && weak3.upgrade().is_none());
+        drop(weak2);
+        drop(weak3);
+        assert_eq!(drops.load(Relaxed), 1);
+    }
+}
+
+// Run with `LOOM_MAX_PREEMPTIONS=2 cargo test --features loom --lib`, exploring every preemption
+// schedule takes far too long.
+#[cfg(all(test, feature = "loom"))]
+mod loom_test {
+    use super::*;
+    use loom::thread;
 
-}
\ No newline at end of file
+    #[test]
+    fn readers_see_monotonic_updates() {
+        loom::model(|| {
+            let rcu = Rcu::new(0u32);
+            let readers: Vec<_> = (0..2).map(|_| {
+                let rcu = rcu.clone();
+                thread::spawn(move || {
+                    let first = *rcu.read().data();
+                    let second = *rcu.read().data();
+                    assert!(first <= second && second <= 2);
+                })
+            }).collect();
+
+            assert!(rcu.update(1).is_ok());
+            assert!(rcu.update(2).is_ok());
+
+            for jh in readers {
+                jh.join().unwrap();
+            }
+            assert_eq!(*rcu.read().data(), 2);
+        });
+    }
+}
@@ -0,0 +1,305 @@
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::mem::MaybeUninit;
+use alloc::boxed::Box;
+use crate::sync::AtomicUsize;
+use crate::sync::Ordering::{Acquire, Release, Relaxed};
+use crate::semaphore::Semaphore;
+use crate::shared::Shared;
+use crate::backoff::Backoff;
+
+
+struct Slot<T> {
+    // The ticket this slot is waiting for. Equal to the enqueue ticket while the slot is free for
+    // it, ticket + 1 once that enqueue has written the value, and ticket + capacity once the
+    // matching dequeue has taken it out again
+    seq: AtomicUsize,
+    val: UnsafeCell<MaybeUninit<T>>,
+}
+
+struct InnerRingBuffer<T> {
+    slots: Box<[Slot<T>]>,
+    // Capacity - 1, the capacity is a power of two so tickets map to slots by masking
+    mask: usize,
+    // Next dequeue and enqueue tickets. Both only grow, wrapping around usize
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    // Free and filled slots. A ticket is only taken while holding a permit, so the operation that
+    // last used its slot has always already taken its own ticket and is about to finish
+    free: Semaphore,
+    filled: Semaphore,
+}
+
+impl<T> InnerRingBuffer<T> {
+    fn with_capacity(capacity: usize) -> Self {
+        assert!(capacity > 0, "RingBuffer cannot have a capacity of 0");
+        let capacity = capacity.next_power_of_two();
+        let permits = u32::try_from(capacity).expect("capacity must fit in a u32");
+        let slots = (0..capacity)
+            .map(|i| Slot { seq: AtomicUsize::new(i), val: UnsafeCell::new(MaybeUninit::uninit()) })
+            .collect();
+        Self {
+            slots,
+            mask: capacity - 1,
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            free: Semaphore::new(permits),
+            filled: Semaphore::init_with(permits, 0),
+        }
+    }
+
+    // Waits for the slot's previous user to finish with it. That happens within a few instructions,
+    // see `free` and `filled`, so spinning beats parking
+    fn slot_for(&self, ticket: usize, ready: usize) -> &Slot<T> {
+        let slot = &self.slots[ticket & self.mask];
+        let mut backoff = Backoff::new();
+        // Acquire pairs with the Release that made the slot ready for us
+        while slot.seq.load(Acquire) != ready {
+            backoff.snooze();
+        }
+        slot
+    }
+
+    // Must be called holding a `free` permit
+    fn push(&self, val: T) {
+        let ticket = self.tail.fetch_add(1, Relaxed);
+        let slot = self.slot_for(ticket, ticket);
+        // Safety: The sequence says the slot is empty and the ticket makes it ours alone
+        unsafe { (*slot.val.get()).write(val); }
+        slot.seq.store(ticket.wrapping_add(1), Release);
+        self.filled.signal();
+    }
+
+    // Must be called holding a `filled` permit
+    fn pop(&self) -> T {
+        let ticket = self.head.fetch_add(1, Relaxed);
+        let slot = self.slot_for(ticket, ticket.wrapping_add(1));
+        // Safety: The sequence says the enqueue for this ticket wrote the slot, and the ticket
+        // makes reading it out ours alone
+        let val = unsafe { (*slot.val.get()).assume_init_read() };
+        slot.seq.store(ticket.wrapping_add(self.slots.len()), Release);
+        self.free.signal();
+        val
+    }
+
+    fn try_enqueue(&self, val: T) -> Result<(), T> {
+        if !self.free.try_wait() {
+            return Err(val);
+        }
+        self.push(val);
+        Ok(())
+    }
+
+    fn enqueue(&self, val: T) {
+        self.free.wait();
+        self.push(val);
+    }
+
+    fn try_dequeue(&self) -> Result<T, Empty> {
+        if !self.filled.try_wait() {
+            return Err(Empty);
+        }
+        Ok(self.pop())
+    }
+
+    fn dequeue(&self) -> T {
+        self.filled.wait();
+        self.pop()
+    }
+}
+
+impl<T> Drop for InnerRingBuffer<T> {
+    fn drop(&mut self) {
+        // No other thread has access any more, so every filled permit is an element still sitting
+        // in its slot
+        while self.try_dequeue().is_ok() {}
+    }
+}
+
+/// Error returned by `RingBuffer::try_dequeue` when there is nothing to take.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Empty;
+
+impl fmt::Display for Empty {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ring buffer is empty")
+    }
+}
+
+impl core::error::Error for Empty {}
+
+/// A bounded multi-producer multi-consumer FIFO queue over a fixed array of slots, so it never
+/// allocates after construction. The capacity is rounded up to a power of two. Alongside the
+/// non-blocking `try_enqueue` and `try_dequeue`, `enqueue` parks until there is room and `dequeue`
+/// parks until there is an element.
+pub struct RingBuffer<T> {
+    inner: Shared<InnerRingBuffer<T>>,
+}
+
+impl<T> RingBuffer<T> {
+    /// Panics if `capacity` is 0, or does not fit in a `u32` once rounded up to a power of two.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self { inner: Shared::new(InnerRingBuffer::with_capacity(capacity)) }
+    }
+
+    /// Enqueues `val` if there is a free slot right now, otherwise hands it back.
+    pub fn try_enqueue(&self, val: T) -> Result<(), T> {
+        self.inner.try_enqueue(val)
+    }
+
+    /// Enqueues `val`, parking until a slot frees up if the buffer is full.
+    pub fn enqueue(&self, val: T) {
+        self.inner.enqueue(val)
+    }
+
+    pub fn try_dequeue(&self) -> Result<T, Empty> {
+        self.inner.try_dequeue()
+    }
+
+    /// Dequeues the oldest element, parking until one is enqueued if the buffer is empty.
+    pub fn dequeue(&self) -> T {
+        self.inner.dequeue()
+    }
+
+    /// A snapshot of the number of elements, which other threads may change straight away.
+    /// Elements still being written or read out count as neither here nor there.
+    pub fn len(&self) -> usize {
+        self.inner.filled.available_permits() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.inner.slots.len()
+    }
+}
+
+impl<T> Clone for RingBuffer<T> {
+    fn clone(&self) -> Self {
+        RingBuffer { inner: self.inner.clone() }
+    }
+}
+
+unsafe impl<T> Send for RingBuffer<T> where T: Send {}
+unsafe impl<T> Sync for RingBuffer<T> where T: Send {}
+
+
+#[cfg(all(test, not(feature = "loom")))]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_ring_buffer_single_threaded() {
+        let ring = RingBuffer::with_capacity(3);
+        assert_eq!(ring.capacity(), 4);
+        assert_eq!(ring.try_dequeue(), Err(Empty));
+        for i in 0..4 {
+            assert_eq!(ring.try_enqueue(i), Ok(()));
+        }
+        assert_eq!(ring.try_enqueue(4), Err(4));
+        assert_eq!(ring.len(), 4);
+
+        // Go around the buffer a few times so the tickets wrap past the slots
+        for i in 4..20 {
+            assert_eq!(ring.try_dequeue(), Ok(i - 4));
+            assert_eq!(ring.try_enqueue(i), Ok(()));
+        }
+        for i in 16..20 {
+            assert_eq!(ring.dequeue(), i);
+        }
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn test_ring_buffer_blocking_enqueue_waits_for_space() {
+        let ring = RingBuffer::with_capacity(1);
+        ring.enqueue(1);
+        thread::scope(|s| {
+            let producer = s.spawn(|| ring.enqueue(2));
+            thread::sleep(Duration::from_millis(30));
+            assert!(!producer.is_finished());
+            assert_eq!(ring.dequeue(), 1);
+            producer.join().expect("producer panicked");
+        });
+        assert_eq!(ring.try_dequeue(), Ok(2));
+    }
+
+    #[test]
+    fn test_ring_buffer_drops_remaining_elements() {
+        let val = Arc::new(());
+        let ring = RingBuffer::with_capacity(8);
+        for _ in 0..5 {
+            ring.enqueue(val.clone());
+        }
+        drop(ring.dequeue());
+        assert_eq!(Arc::strong_count(&val), 5);
+        drop(ring);
+        assert_eq!(Arc::strong_count(&val), 1);
+    }
+
+    #[test]
+    fn test_ring_buffer_single_producer_single_consumer() {
+        let ring = RingBuffer::with_capacity(16);
+        thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..20_000 {
+                    ring.enqueue(i);
+                }
+            });
+            // FIFO with a single producer means everything comes out in order
+            for i in 0..20_000 {
+                assert_eq!(ring.dequeue(), i);
+            }
+        });
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn test_ring_buffer_multi_producer_multi_consumer() {
+        const PRODUCERS: usize = 4;
+        const PER_PRODUCER: usize = 5_000;
+        let ring = RingBuffer::with_capacity(8);
+        let mut counts = [0; PRODUCERS];
+        thread::scope(|s| {
+            for i in 0..PRODUCERS {
+                let ring = &ring;
+                s.spawn(move || {
+                    for j in 0..PER_PRODUCER {
+                        // Mix both flavours so they have to agree on the permits
+                        if let Err(val) = ring.try_enqueue((i, j)) {
+                            ring.enqueue(val);
+                        }
+                    }
+                });
+            }
+            let consumers: Vec<_> = (0..4).map(|_| {
+                let ring = &ring;
+                s.spawn(move || {
+                    let mut last = [None; PRODUCERS];
+                    let mut counts = [0; PRODUCERS];
+                    for _ in 0..PRODUCERS * PER_PRODUCER / 4 {
+                        let (i, j) = ring.dequeue();
+                        // Each producer's elements come out in the order it enqueued them
+                        assert!(last[i] < Some(j), "producer {i}: {j} after {:?}", last[i]);
+                        last[i] = Some(j);
+                        counts[i] += 1;
+                    }
+                    counts
+                })
+            }).collect();
+            for jh in consumers {
+                let seen = jh.join().expect("consumer panicked");
+                for i in 0..PRODUCERS {
+                    counts[i] += seen[i];
+                }
+            }
+        });
+        assert_eq!(counts, [PER_PRODUCER; PRODUCERS]);
+        assert_eq!(ring.try_dequeue(), Err(Empty));
+    }
+}
@@ -1,100 +1,855 @@
-use std::sync::atomic::{Ordering::{Acquire, Release, Relaxed}, AtomicU32, AtomicUsize, fence};
-use atomic_wait::{wake_one, wake_all, wait};
+use crate::sync::{self, Ordering::{Acquire, Release, Relaxed, SeqCst}, AtomicBool, AtomicU32};
+use core::cell::UnsafeCell;
+use alloc::collections::VecDeque;
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+use alloc::sync::Arc;
+#[cfg(feature = "std")]
+use std::sync::{LockResult, PoisonError};
+#[cfg(feature = "std")]
+use std::thread::panicking;
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+use crate::sync::{seq_cst_fence, wake_one, wake_all, wait};
+use crate::shared::Shared;
+use crate::backoff::Backoff;
 
 
+struct Waiter {
+    want: u32,
+    // Set to 1 once `want` permits have been handed to this waiter, or to 2 by `interrupt` so it
+    // re-checks whether it was cancelled. It parks on this word
+    granted: AtomicU32,
+}
+
+// FIFO queue of parked waiters used by fair semaphores, guarded by a small spin lock since the
+// critical sections are only a few instructions long.
+struct WaitQueue {
+    locked: AtomicBool,
+    waiters: UnsafeCell<VecDeque<Arc<Waiter>>>,
+}
+
+impl WaitQueue {
+    fn new() -> Self {
+        Self { locked: AtomicBool::new(false), waiters: UnsafeCell::new(VecDeque::new()) }
+    }
+
+    fn lock(&self) -> WaitQueueGuard<'_> {
+        let mut spins = 0;
+        while self.locked.compare_exchange_weak(false, true, Acquire, Relaxed).is_err() {
+            // The holder may have been preempted, so stop burning its time slice after a while
+            if spins < 64 {
+                spins += 1;
+                sync::spin_loop();
+            } else {
+                sync::yield_now();
+            }
+        }
+        WaitQueueGuard { queue: self }
+    }
+}
+
+struct WaitQueueGuard<'a> {
+    queue: &'a WaitQueue,
+}
+
+impl Deref for WaitQueueGuard<'_> {
+    type Target = VecDeque<Arc<Waiter>>;
+
+    fn deref(&self) -> &Self::Target {
+        // Safety: We hold the lock
+        unsafe { &*self.queue.waiters.get() }
+    }
+}
+
+impl DerefMut for WaitQueueGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // Safety: We hold the lock
+        unsafe { &mut *self.queue.waiters.get() }
+    }
+}
+
+impl Drop for WaitQueueGuard<'_> {
+    fn drop(&mut self) {
+        self.queue.locked.store(false, Release);
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum FairAcquire {
+    Immediate,
+    Parked,
+    Cancelled,
+}
+
 struct InnerSemaphore {
     count: AtomicU32,
-    ref_count: AtomicUsize,
     max_count: u32,
+    // Only present for fair semaphores. When it is, `count` is only modified with the queue locked.
+    queue: Option<WaitQueue>,
+    // Futex gate letting one `acquire_all` caller at a time collect permits, 1 while held
+    exclusive: AtomicU32,
+    // Number of `acquire_n` callers waiting for more than one permit. They need waking on every
+    // release, not just the ones that bring the count up from zero
+    multi_waiters: AtomicU32,
+    // Number of single-permit waiters parked on `count`, so `release` knows how many to wake
+    waiters: AtomicU32,
+    // Wrapping count of interrupts, also bumped by releases while anyone is in
+    // `wait_interruptible`. Those waiters park on it rather than on `count`, so an interrupt that
+    // lands between their flag check and parking still changes the word they sleep on
+    interrupt_seq: AtomicU32,
+    // Number of threads in `wait_interruptible`
+    interruptible: AtomicU32,
+    // How many backoff rounds `wait` spins for before parking. Doubled whenever a spin ends with a
+    // permit and halved whenever it doesn't, so it tracks how long permits are usually held.
+    spin_limit: AtomicU32,
+    max_spins: u32,
+    // Whether a panic while holding a `SemaphorePermit` poisons the semaphore, see
+    // `SemaphoreBuilder::poisoning`
+    poisoning: bool,
+    poisoned: AtomicBool,
+    #[cfg(test)]
+    parks: crate::sync::AtomicUsize,
+    // Rounds `spin_for_permit` has spun, so tests can act while a waiter is spinning
+    #[cfg(test)]
+    spins: crate::sync::AtomicUsize,
 }
 
+// Upper bound on `spin_limit`, past this we would rather park
+#[cfg(not(feature = "loom"))]
+const MAX_SPINS: u32 = 16;
+// Under loom every spin is another branch to explore, and models would run out of path length
+// long before anything interesting happens, so go straight to parking
+#[cfg(feature = "loom")]
+const MAX_SPINS: u32 = 0;
+// How long `wait_timeout` sleeps between polls once it is done spinning
+#[cfg(feature = "std")]
+const TIMEOUT_POLL: Duration = Duration::from_millis(1);
+
 impl InnerSemaphore {
     fn new(max_count: u32) -> Self {
-        Self { count: AtomicU32::new(max_count), ref_count: AtomicUsize::new(1), max_count }
+        Self::init_with(max_count, max_count)
     }
 
     fn init_with(max_count: u32, init_val: u32) -> Self {
-        Self { count: AtomicU32::new(init_val), ref_count: AtomicUsize::new(1), max_count }
+        Self {
+            count: AtomicU32::new(init_val),
+            max_count,
+            queue: None,
+            exclusive: AtomicU32::new(0),
+            multi_waiters: AtomicU32::new(0),
+            waiters: AtomicU32::new(0),
+            interrupt_seq: AtomicU32::new(0),
+            interruptible: AtomicU32::new(0),
+            spin_limit: AtomicU32::new(MAX_SPINS),
+            max_spins: MAX_SPINS,
+            poisoning: false,
+            poisoned: AtomicBool::new(false),
+            #[cfg(test)]
+            parks: crate::sync::AtomicUsize::new(0),
+            #[cfg(test)]
+            spins: crate::sync::AtomicUsize::new(0),
+        }
+    }
+
+    fn with_spin(max_count: u32, spins: u32) -> Self {
+        Self { spin_limit: AtomicU32::new(spins), max_spins: spins, ..Self::new(max_count) }
+    }
+
+    fn init_fair(max_count: u32, init_val: u32) -> Self {
+        Self { queue: Some(WaitQueue::new()), ..Self::init_with(max_count, init_val) }
     }
 
     fn wait(&self) {
+        if let Some(queue) = &self.queue {
+            self.fair_acquire(queue, 1, || false);
+            return;
+        }
+        let mut backoff = Backoff::new();
+        loop {
+            let cur_count = self.count.load(Relaxed);
+            if cur_count == 0 {
+                if !self.spin_for_permit() {
+                    #[cfg(test)]
+                    self.parks.fetch_add(1, Relaxed);
+                    self.park();
+                }
+                continue;
+            }
+            // Acquire pairs with the Release in `release`: everything the thread that handed back
+            // this permit wrote before doing so is visible to us once we own it. Nothing needs to
+            // be published on the way in, so the success ordering has no Release half.
+            if self.count.compare_exchange(cur_count, cur_count - 1, Acquire, Relaxed).is_ok() {
+                break;
+            }
+            // Lost the permit to another thread, back off so we do not all retry in lockstep
+            backoff.snooze();
+        }
+    }
+
+    fn try_wait(&self) -> bool {
+        if let Some(queue) = &self.queue {
+            let waiters = queue.lock();
+            let cur_count = self.count.load(Relaxed);
+            // Don't overtake anyone already queued
+            if !waiters.is_empty() || cur_count == 0 {
+                return false;
+            }
+            self.count.store(cur_count - 1, Relaxed);
+            return true;
+        }
+        let mut cur_count = self.count.load(Relaxed);
+        while cur_count != 0 {
+            // Same ordering as `wait`
+            match self.count.compare_exchange_weak(cur_count, cur_count - 1, Acquire, Relaxed) {
+                Ok(_) => return true,
+                Err(next) => cur_count = next,
+            }
+        }
+        false
+    }
+
+    #[cfg(feature = "std")]
+    fn wait_timeout(&self, timeout: Duration) -> bool {
+        // The futex wait has no timeout, so poll instead: spin briefly, then sleep in short
+        // slices, re-checking the deadline in between
+        let Some(deadline) = Instant::now().checked_add(timeout) else {
+            self.wait();
+            return true;
+        };
+        let mut backoff = Backoff::new();
+        loop {
+            if self.try_wait() {
+                return true;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            if backoff.is_completed() {
+                std::thread::sleep(TIMEOUT_POLL.min(deadline - now));
+            } else {
+                backoff.snooze();
+            }
+        }
+    }
+
+    // Sleeps until a release wakes us, unless the count is no longer 0
+    fn park(&self) {
+        // SeqCst pairs with `release`: either it sees us waiting and wakes us, or the futex sees
+        // its count and does not sleep
+        self.waiters.fetch_add(1, SeqCst);
+        seq_cst_fence();
+        wait(&self.count, 0);
+        self.waiters.fetch_sub(1, Relaxed);
+    }
+
+    // Spins in case a permit comes back before parking would pay off. Returns whether one did, and
+    // adjusts how long the next caller spins by the outcome.
+    fn spin_for_permit(&self) -> bool {
+        let limit = self.spin_limit.load(Relaxed).min(self.max_spins);
+        let mut backoff = Backoff::new();
+        for _ in 0..limit {
+            backoff.snooze();
+            #[cfg(test)]
+            self.spins.fetch_add(1, Relaxed);
+            if self.count.load(Relaxed) != 0 {
+                self.spin_limit.store((limit * 2).max(1).min(self.max_spins), Relaxed);
+                return true;
+            }
+        }
+        self.spin_limit.store((limit / 2).max(1).min(self.max_spins), Relaxed);
+        false
+    }
+
+    #[cfg(feature = "std")]
+    fn wait_timed(&self) -> Duration {
+        if let Some(queue) = &self.queue {
+            let start = Instant::now();
+            return match self.fair_acquire(queue, 1, || false) {
+                FairAcquire::Parked => start.elapsed(),
+                _ => Duration::ZERO,
+            };
+        }
+        // Only start the clock once we actually have to park
+        let mut parked_at = None;
         loop {
             let cur_count = self.count.load(Relaxed);
             if cur_count == 0 {
-                wait(&self.count, 0);
+                parked_at.get_or_insert_with(Instant::now);
+                self.park();
+                continue;
+            }
+            if self.count.compare_exchange(cur_count, cur_count - 1, Acquire, Relaxed).is_ok() {
+                break;
+            }
+        }
+        parked_at.map_or(Duration::ZERO, |start| start.elapsed())
+    }
+
+    fn wait_interruptible(&self, flag: &AtomicBool) -> bool {
+        if let Some(queue) = &self.queue {
+            return !flag.load(Acquire) && self.fair_acquire(queue, 1, || flag.load(Acquire)) != FairAcquire::Cancelled;
+        }
+        // SeqCst pairs with `wake_waiters`: either it sees us and bumps `interrupt_seq`, or we see
+        // its count
+        self.interruptible.fetch_add(1, SeqCst);
+        seq_cst_fence();
+        let acquired = loop {
+            // Load the futex word first, so an interrupt or release that lands after the checks
+            // below keeps us from sleeping. An interrupt bumps it after setting the flag, so
+            // seeing the new value means seeing the flag too
+            let seq = self.interrupt_seq.load(SeqCst);
+            if flag.load(Acquire) {
+                break false;
+            }
+            let cur_count = self.count.load(SeqCst);
+            if cur_count == 0 {
+                wait(&self.interrupt_seq, seq);
+                continue;
+            }
+            if self.count.compare_exchange(cur_count, cur_count - 1, Acquire, Relaxed).is_ok() {
+                break true;
+            }
+        };
+        self.interruptible.fetch_sub(1, Relaxed);
+        acquired
+    }
+
+    fn acquire_n(&self, n: u32) {
+        assert!(n <= self.max_count, "cannot acquire more permits than max_count");
+        if n <= 1 {
+            if n == 1 {
+                self.wait();
+            }
+            return;
+        }
+        if let Some(queue) = &self.queue {
+            self.fair_acquire(queue, n, || false);
+            return;
+        }
+        // SeqCst pairs with `release`: either it sees us waiting and wakes us, or we see its count
+        self.multi_waiters.fetch_add(1, SeqCst);
+        seq_cst_fence();
+        loop {
+            let cur_count = self.count.load(SeqCst);
+            if cur_count < n {
+                // Sleep until the count changes, then check again whether all `n` are there. We
+                // never take some of them and hold on while waiting for the rest, which would
+                // starve single-permit waiters
+                wait(&self.count, cur_count);
                 continue;
             }
-            if self.count.compare_exchange(cur_count, cur_count - 1, Release, Relaxed).is_ok() {
-                fence(Acquire);
+            if self.count.compare_exchange(cur_count, cur_count - n, Acquire, Relaxed).is_ok() {
                 break;
             }
         }
+        self.multi_waiters.fetch_sub(1, Relaxed);
+    }
+
+    fn acquire_all(&self) {
+        if let Some(queue) = &self.queue {
+            // The queue hands out all `max_count` permits in one grant, so nothing is held while
+            // we wait and there is no partial state to deadlock on
+            self.fair_acquire(queue, self.max_count, || false);
+            return;
+        }
+        // Collecting the permits one at a time could deadlock if two callers each ended up
+        // holding some of them, so only the holder of the gate may collect
+        while self.exclusive.compare_exchange(0, 1, Acquire, Relaxed).is_err() {
+            wait(&self.exclusive, 1);
+        }
+        for _ in 0..self.max_count {
+            self.wait();
+        }
+    }
+
+    fn release_all(&self) {
+        assert!(self.release(self.max_count).is_ok(), "count may not exceed set maximum");
+        self.release_exclusive();
+    }
+
+    // Lets the next `acquire_all` caller through the gate. Fair semaphores have no gate
+    fn release_exclusive(&self) {
+        if self.queue.is_none() {
+            self.exclusive.store(0, Release);
+            wake_one(&self.exclusive);
+        }
+    }
+
+    fn interrupt(&self) {
+        if let Some(queue) = &self.queue {
+            for waiter in queue.lock().iter() {
+                // Release pairs with the Acquire in `fair_acquire`, so the waiter that resets the
+                // word sees whatever flag the interrupting thread set first
+                let _ = waiter.granted.compare_exchange(0, 2, Release, Relaxed);
+                wake_one(&waiter.granted);
+            }
+            return;
+        }
+        // A waiter that loads the new value also sees the flag set before we were called
+        self.interrupt_seq.fetch_add(1, SeqCst);
+        wake_all(&self.interrupt_seq);
     }
 
     fn signal(&self) {
+        // Every permit handed back goes through here, so only pay for the check in debug builds. A
+        // release build ignores the extra signal, leaving the count at `max_count`
+        let res = self.try_signal();
+        debug_assert!(res.is_ok(), "count may not exceed set maximum");
+    }
+
+    fn try_signal(&self) -> Result<(), Overflow> {
+        self.release(1)
+    }
+
+    fn prime(&self, n: u32) {
+        assert!(self.release(n).is_ok(), "count may not exceed set maximum");
+    }
+
+    fn release(&self, n: u32) -> Result<(), Overflow> {
+        if let Some(queue) = &self.queue {
+            let mut waiters = queue.lock();
+            let cur_count = self.count.load(Relaxed);
+            if n > self.max_count - cur_count {
+                return Err(Overflow);
+            }
+            self.count.store(cur_count + n, Relaxed);
+            self.grant_waiters(&mut waiters);
+            return Ok(());
+        }
         let mut cur_count = self.count.load(Relaxed);
         loop {
-            assert!(cur_count < self.max_count, "count may not exceed set maximum");
-            match self.count.compare_exchange(cur_count, cur_count + 1, Release, Relaxed) {
-                Ok(prev) => {
-                    if prev == 0 {
-                        wake_all(&self.count);
-                    }
-                    break;
+            if n > self.max_count - cur_count {
+                return Err(Overflow);
+            }
+            // Release publishes the critical section we are leaving to whoever acquires these
+            // permits next. A failed attempt publishes nothing, so it can be Relaxed. SeqCst on
+            // success orders the new count before our load of `multi_waiters`, see `acquire_n`.
+            match self.count.compare_exchange(cur_count, cur_count + n, SeqCst, Relaxed) {
+                Ok(_) => {
+                    self.wake_waiters(n);
+                    return Ok(());
                 },
                 Err(next) => cur_count = next,
             }
         }
     }
+
+    fn reset(&self, value: u32) {
+        assert!(value <= self.max_count, "count may not exceed set maximum");
+        if let Some(queue) = &self.queue {
+            let mut waiters = queue.lock();
+            self.count.store(value, Relaxed);
+            self.grant_waiters(&mut waiters);
+            return;
+        }
+        // SeqCst like a successful `release`, so waking below sees every waiter that missed the
+        // new count
+        self.count.swap(value, SeqCst);
+        self.wake_waiters(value);
+    }
+
+    // Wakes one parked waiter per released permit rather than all of them, since the rest would
+    // only find the permits gone and park again. Waking on every release rather than only the ones
+    // from zero matters: two quick releases may both land before the first waiter takes its permit.
+    fn wake_waiters(&self, n: u32) {
+        if n == 0 {
+            return;
+        }
+        seq_cst_fence();
+        // Interruptible waiters are not parked on `count`, and any of them may have given up, so
+        // they all get to look
+        if self.interruptible.load(SeqCst) > 0 {
+            self.interrupt_seq.fetch_add(1, SeqCst);
+            wake_all(&self.interrupt_seq);
+        }
+        // `acquire_n` callers wait for the count to reach their `n`, a single wake could land on
+        // one that still cannot proceed and be lost, so they all get to look
+        if self.multi_waiters.load(SeqCst) > 0 {
+            wake_all(&self.count);
+            return;
+        }
+        let parked = self.waiters.load(SeqCst);
+        if n >= parked {
+            if parked > 0 {
+                wake_all(&self.count);
+            }
+            return;
+        }
+        for _ in 0..n {
+            wake_one(&self.count);
+        }
+    }
+
+    fn downgrade(&self) {
+        let released = self.max_count - 1;
+        if released == 0 {
+            return;
+        }
+        if let Some(queue) = &self.queue {
+            let mut waiters = queue.lock();
+            assert_eq!(self.count.load(Relaxed), 0, "downgrade requires the caller to hold every permit");
+            self.count.store(released, Relaxed);
+            self.grant_waiters(&mut waiters);
+            return;
+        }
+        // A single CAS from 0 releases every permit but ours at once, so there is no point at which
+        // another thread could observe all `max_count` permits as available. SeqCst like a
+        // successful `release`, since waking goes the same way
+        assert!(
+            self.count.compare_exchange(0, released, SeqCst, Relaxed).is_ok(),
+            "downgrade requires the caller to hold every permit"
+        );
+        self.wake_waiters(released);
+    }
+
+    // Takes `n` permits in FIFO order, parking until they are handed to us. `cancelled` is checked
+    // before parking and every time we wake, and if it returns true we leave the queue without any
+    // permits. `interrupt` marks `granted` while we are queued, so it cannot slip in between the
+    // check and parking.
+    fn fair_acquire<F: FnMut() -> bool>(&self, queue: &WaitQueue, n: u32, mut cancelled: F) -> FairAcquire {
+        let waiter = {
+            let mut waiters = queue.lock();
+            let cur_count = self.count.load(Relaxed);
+            // Only take permits directly if nobody is queued ahead of us. The queue lock orders us
+            // after whichever thread released them.
+            if waiters.is_empty() && cur_count >= n {
+                self.count.store(cur_count - n, Relaxed);
+                return FairAcquire::Immediate;
+            }
+            let waiter = Arc::new(Waiter { want: n, granted: AtomicU32::new(0) });
+            waiters.push_back(waiter.clone());
+            waiter
+        };
+
+        loop {
+            // Acquire matches the release store in `grant_waiters`
+            let granted = waiter.granted.load(Acquire);
+            if granted == 1 {
+                return FairAcquire::Parked;
+            }
+            // Reset an interrupt before checking, so one that lands after the check wakes us again.
+            // Failing means the permits were granted meanwhile
+            if granted == 2 && waiter.granted.compare_exchange(2, 0, Acquire, Relaxed).is_err() {
+                continue;
+            }
+            if cancelled() {
+                let mut waiters = queue.lock();
+                if let Some(pos) = waiters.iter().position(|queued| Arc::ptr_eq(queued, &waiter)) {
+                    waiters.remove(pos);
+                    // Waiters that were queued behind us may be satisfiable now
+                    self.grant_waiters(&mut waiters);
+                    return FairAcquire::Cancelled;
+                }
+                // We were handed our permits before we could leave the queue, so keep them
+                continue;
+            }
+            wait(&waiter.granted, 0);
+        }
+    }
+
+    // Hands permits to queued waiters in arrival order, stopping at the first one that cannot be
+    // satisfied so that nobody overtakes it. Must be called with the queue locked.
+    fn grant_waiters(&self, waiters: &mut VecDeque<Arc<Waiter>>) {
+        let mut cur_count = self.count.load(Relaxed);
+        while waiters.front().is_some_and(|front| front.want <= cur_count) {
+            let waiter = waiters.pop_front().expect("queue should not be empty");
+            cur_count -= waiter.want;
+            waiter.granted.store(1, Release);
+            wake_one(&waiter.granted);
+        }
+        self.count.store(cur_count, Relaxed);
+    }
 }
 
+/// Error returned by `Semaphore::try_signal` when the count is already at its maximum.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Overflow;
+
+impl fmt::Display for Overflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "semaphore count is already at its maximum")
+    }
+}
+
+impl core::error::Error for Overflow {}
+
+#[derive(Clone)]
 pub struct Semaphore {
-    inner: *mut InnerSemaphore,
+    inner: Shared<InnerSemaphore>,
 }
 
 impl Semaphore {
     pub fn new(max_count: u32) -> Self {
         assert!(max_count > 0, "Semaphore cannot have a max count of 0");
-        let inner = Box::into_raw(Box::new(InnerSemaphore::new(max_count)));
-        Self { inner }
+        Self { inner: Shared::new(InnerSemaphore::new(max_count)) }
     }
 
     pub fn init_with(max_count: u32, init_count: u32) -> Self {
         assert!(max_count > 0, "Semaphore cannot have a max count of 0");
         assert!(init_count <= max_count, "Initial value cannot exceed max_count");
-        let inner = Box::into_raw(Box::new(InnerSemaphore::init_with(max_count, init_count)));
-        Self { inner }
+        Self { inner: Shared::new(InnerSemaphore::init_with(max_count, init_count)) }
+    }
+
+    /// Creates a fair semaphore: waiters queue up and permits are handed to them in arrival order,
+    /// rather than to whichever thread wins the race after a wake.
+    pub fn new_fair(max_count: u32) -> Self {
+        assert!(max_count > 0, "Semaphore cannot have a max count of 0");
+        Self { inner: Shared::new(InnerSemaphore::init_fair(max_count, max_count)) }
+    }
+
+    /// Like `new`, but `wait` spins for at most `spins` backoff rounds before parking instead of
+    /// the default 16. Spinning shrinks and grows within that bound with how long permits are held.
+    /// More suits very short critical sections on machines with cores to spare, and 0 always parks
+    /// straight away.
+    pub fn with_spin(max_count: u32, spins: u32) -> Self {
+        assert!(max_count > 0, "Semaphore cannot have a max count of 0");
+        Self { inner: Shared::new(InnerSemaphore::with_spin(max_count, spins)) }
+    }
+
+    /// Starts configuring a semaphore. Without further options this builds the same semaphore as
+    /// `Semaphore::new(1)`.
+    pub fn builder() -> SemaphoreBuilder {
+        SemaphoreBuilder { max: 1, initial: None, fair: false, poisoning: false }
     }
 
     pub fn wait(&self) {
-        // Safety: This pointer will never be null
-        unsafe { (*self.inner).wait(); }
+        self.inner.wait();
+    }
+
+    /// Returns how many permits are free right now. Other threads may take or release permits at
+    /// any moment, so this is only a snapshot, fine for monitoring but not for deciding whether a
+    /// `wait` would block.
+    pub fn available_permits(&self) -> u32 {
+        self.inner.count.load(Relaxed)
+    }
+
+    pub fn max_permits(&self) -> u32 {
+        self.inner.max_count
+    }
+
+    /// Takes a permit if one is available right now, without ever blocking. Returns whether it did;
+    /// `false` always means no permit was free, not that another thread got in the way. A
+    /// successful `try_wait` orders memory exactly like `wait`.
+    pub fn try_wait(&self) -> bool {
+        self.inner.try_wait()
     }
 
+    /// Like `wait`, but gives up and returns `false` if no permit could be taken within `timeout`.
+    /// Rather than parking, the caller polls, sleeping up to a millisecond between attempts, so a
+    /// permit may be picked up that much late and the timeout may overrun by the OS sleep
+    /// granularity. On a fair semaphore it never overtakes queued waiters, but it does not join
+    /// the queue either, so a steady stream of queued waiters can keep it from ever succeeding.
+    #[cfg(feature = "std")]
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        self.inner.wait_timeout(timeout)
+    }
+
+    /// Like `wait`, but returns how long the caller was blocked acquiring the permit.
+    /// The uncontended fast path never parks and returns `Duration::ZERO`.
+    #[cfg(feature = "std")]
+    pub fn wait_timed(&self) -> Duration {
+        self.inner.wait_timed()
+    }
+
+    /// Like `wait`, but gives up without acquiring a permit and returns `false` once `flag` is
+    /// set. The flag is checked before every acquisition attempt and after every wake. A parked
+    /// waiter wakes on releases and on `interrupt`, so set the flag and then call `interrupt` to
+    /// cancel it promptly; an interrupt that lands just before the waiter parks still wakes it.
+    /// Returns `true` if a permit was acquired.
+    pub fn wait_interruptible(&self, flag: &AtomicBool) -> bool {
+        self.inner.wait_interruptible(flag)
+    }
+
+    /// Wakes every thread in `wait_interruptible` without releasing a permit, so they re-check
+    /// their cancellation flag.
+    pub fn interrupt(&self) {
+        self.inner.interrupt();
+    }
+
+    /// Releases a permit. Over-signaling past `max_count` is a logic error: debug builds panic,
+    /// release builds ignore the extra signal. Use `try_signal` to detect and recover from it.
     pub fn signal(&self) {
-        // Safety: This pointer will never be null
-        unsafe { (*self.inner).signal(); }
+        self.inner.signal();
+    }
+
+    /// Releases a permit, returning `Err(Overflow)` instead of panicking if the count is
+    /// already at `max_count`.
+    pub fn try_signal(&self) -> Result<(), Overflow> {
+        self.inner.try_signal()
+    }
+
+    /// Releases `n` permits in a single step, e.g. to open a semaphore created with
+    /// `init_with(max, 0)` as an event gate. Panics if this would exceed `max_count`.
+    pub fn prime(&self, n: u32) {
+        self.inner.prime(n);
+    }
+
+    /// Like `wait`, but returns a permit that signals when it is dropped, so the permit is given
+    /// back on every path out of the caller, panics included. On a semaphore built with
+    /// `poisoning(true)` a panic while holding a permit also poisons it, and from then on this
+    /// returns the permit wrapped in a `PoisonError`, like `Mutex::lock`. Otherwise it is always
+    /// `Ok`.
+    #[cfg(feature = "std")]
+    pub fn acquire(&self) -> LockResult<SemaphorePermit<'_>> {
+        self.inner.wait();
+        let permit = SemaphorePermit { semaphore: self };
+        // Acquire pairs with the Release in the permit's drop, like the permit itself
+        if self.inner.poisoned.load(Acquire) {
+            Err(PoisonError::new(permit))
+        } else {
+            Ok(permit)
+        }
+    }
+
+    /// Whether a thread panicked while holding a permit from `acquire`. Always false unless the
+    /// semaphore was built with `poisoning(true)`.
+    pub fn is_poisoned(&self) -> bool {
+        self.inner.poisoned.load(Acquire)
+    }
+
+    /// Clears the poisoned state, e.g. once whatever the permits protect has been repaired.
+    pub fn clear_poison(&self) {
+        self.inner.poisoned.store(false, Release);
+    }
+
+    /// Blocks until `n` permits are available and takes them all in one step. Panics if `n`
+    /// exceeds `max_count`.
+    pub fn acquire_n(&self, n: u32) {
+        self.inner.acquire_n(n);
+    }
+
+    /// Releases `n` permits in one step, waking up to `n` parked waiters, or every waiter if some
+    /// are in `acquire_n` so each can check whether enough permits are there for it. Panics if
+    /// this would exceed `max_count`.
+    pub fn release_n(&self, n: u32) {
+        self.inner.prime(n);
+    }
+
+    /// Sets the count to `value` outright, e.g. to restore a pool to `max_count` after
+    /// reconfiguring it, and wakes waiters to take the new permits. Panics if `value` exceeds
+    /// `max_count`. Permits held at the time are not accounted for: their holders still give them
+    /// back when done, so unless the caller knows none are held those later signals can overflow.
+    pub fn reset(&self, value: u32) {
+        self.inner.reset(value);
+    }
+
+    /// Blocks until it holds every one of the `max_count` permits, for exclusive access. They are
+    /// all released when the returned guard drops. Concurrent `acquire_all` callers take turns, so
+    /// they never end up splitting the permits between them.
+    pub fn acquire_all(&self) -> SemaphoreGuard<'_> {
+        self.inner.acquire_all();
+        SemaphoreGuard { semaphore: self }
+    }
+
+    /// For a caller holding all `max_count` permits (e.g. a writer), atomically releases all but
+    /// one of them, downgrading exclusive access to shared access. Panics if the caller does not
+    /// hold every permit.
+    pub fn downgrade(&self) {
+        self.inner.downgrade();
     }
 }
 
-impl Clone for Semaphore {
-    fn clone(&self) -> Semaphore {
-        // Safety: This pointer will never be null
-        unsafe { (*self.inner).ref_count.fetch_add(1, Relaxed); }
-        Semaphore { inner: self.inner }
+/// A binary semaphore with its permit available, i.e. `Semaphore::new(1)`.
+impl Default for Semaphore {
+    fn default() -> Self {
+        Self::new(1)
     }
 }
 
-impl Drop for Semaphore {
+// Without std there is no telling whether we are unwinding, so nothing is ever poisoned
+#[cfg(not(feature = "std"))]
+fn panicking() -> bool {
+    false
+}
+
+/// Holds one permit of a semaphore, see `Semaphore::acquire`.
+#[must_use = "the permit is released as soon as it is dropped"]
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl Drop for SemaphorePermit<'_> {
     fn drop(&mut self) {
-        // Safety: This pointer will never be null up to this point
-        unsafe {
-            if (*self.inner).ref_count.fetch_sub(1, Release) == 1 {
-                fence(Acquire);
-                // Safety: We have exclusive access to `self.inner` at this point.
-                std::ptr::drop_in_place(self.inner);
-            }
+        let inner = &self.semaphore.inner;
+        if inner.poisoning && panicking() {
+            inner.poisoned.store(true, Release);
         }
+        inner.signal();
+    }
+}
+
+/// Holds every permit of a semaphore, see `Semaphore::acquire_all`.
+pub struct SemaphoreGuard<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl<'a> SemaphoreGuard<'a> {
+    /// Releases all permits but one, like `Semaphore::downgrade`, and returns a permit for the one
+    /// that is kept. Other `acquire_all` callers are let through to wait for it.
+    pub fn downgrade(self) -> SemaphorePermit<'a> {
+        let semaphore = self.semaphore;
+        semaphore.inner.downgrade();
+        semaphore.inner.release_exclusive();
+        // The permit gives back what is left, so the guard must not release everything on drop
+        core::mem::forget(self);
+        SemaphorePermit { semaphore }
+    }
+}
+
+impl Drop for SemaphoreGuard<'_> {
+    fn drop(&mut self) {
+        self.semaphore.inner.release_all();
+    }
+}
+
+/// Configures a `Semaphore` before creating it, see `Semaphore::builder`.
+#[derive(Debug, Clone)]
+pub struct SemaphoreBuilder {
+    max: u32,
+    initial: Option<u32>,
+    fair: bool,
+    poisoning: bool,
+}
+
+impl SemaphoreBuilder {
+    /// The maximum number of permits, 1 unless set.
+    pub fn max(mut self, max: u32) -> Self {
+        self.max = max;
+        self
+    }
+
+    /// The number of permits available at construction, `max` unless set.
+    pub fn initial(mut self, initial: u32) -> Self {
+        self.initial = Some(initial);
+        self
+    }
+
+    /// Whether permits are handed to waiters in arrival order, see `Semaphore::new_fair`.
+    pub fn fair(mut self, fair: bool) -> Self {
+        self.fair = fair;
+        self
+    }
+
+    /// Whether a panic while holding a permit from `acquire` poisons the semaphore, see
+    /// `Semaphore::acquire`. Permits taken with `wait` and given back with `signal` are never
+    /// noticed, since nothing is dropped when their holder panics.
+    pub fn poisoning(mut self, poisoning: bool) -> Self {
+        self.poisoning = poisoning;
+        self
+    }
+
+    pub fn build(self) -> Semaphore {
+        let init_count = self.initial.unwrap_or(self.max);
+        assert!(self.max > 0, "Semaphore cannot have a max count of 0");
+        assert!(init_count <= self.max, "Initial value cannot exceed max_count");
+        let inner = if self.fair {
+            InnerSemaphore::init_fair(self.max, init_count)
+        } else {
+            InnerSemaphore::init_with(self.max, init_count)
+        };
+        Semaphore { inner: Shared::new(InnerSemaphore { poisoning: self.poisoning, ..inner }) }
     }
 }
 
@@ -102,11 +857,22 @@ unsafe impl Send for Semaphore {}
 unsafe impl Sync for Semaphore {}
 
 
-#[cfg(test)]
+#[cfg(all(test, not(feature = "loom")))]
 mod test {
     use super::*;
     use std::thread;
-    use std::sync::{Barrier, Arc};
+    use std::sync::{Barrier, Mutex};
+    use std::time::Duration;
+
+    #[test]
+    fn test_default_semaphore_is_binary() {
+        let semaphore = Semaphore::default();
+        assert_eq!(semaphore.max_permits(), 1);
+        semaphore.wait();
+        assert!(!semaphore.try_wait());
+        semaphore.signal();
+        assert_eq!(semaphore.available_permits(), 1);
+    }
 
     #[test]
     fn test_binary_semaphore_single_reader_single_writer() {
@@ -117,11 +883,11 @@ mod test {
         // For signaling threads are finished
         let barrier = Arc::new(Barrier::new(6));
 
-        for i in 0..5 {
+        for _ in 0..5 {
             let semaphore = semaphore.clone();
             let barrier = barrier.clone();
             thread::spawn(move || {
-                for j in 0..100 {
+                for _ in 0..100 {
                     semaphore.wait();
                     unsafe { COUNTER += 1; }
                     semaphore.signal();
@@ -161,4 +927,726 @@ mod test {
         assert_eq!(unsafe { COUNTS[1] }, 600);
         assert_eq!(unsafe { COUNTS[2] }, 600);
     }
+
+    #[test]
+    fn test_signal_wait_message_passing() {
+        // Plain, non-atomic data handed from one thread to another purely through the semaphore.
+        // Under Miri any missing happens-before edge is reported as a data race.
+        struct Slot(UnsafeCell<u64>);
+        unsafe impl Sync for Slot {}
+
+        let rounds = if cfg!(miri) { 10 } else { 1000 };
+        for round in 0..rounds {
+            let semaphore = Semaphore::init_with(1, 0);
+            let slot = &Slot(UnsafeCell::new(0));
+            let semaphore = &semaphore;
+            thread::scope(|s| {
+                s.spawn(move || {
+                    unsafe { *slot.0.get() = round + 1; }
+                    semaphore.signal();
+                });
+                s.spawn(move || {
+                    semaphore.wait();
+                    assert_eq!(unsafe { *slot.0.get() }, round + 1);
+                });
+            });
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_wait_timed_reports_blocked_duration() {
+        let semaphore = Semaphore::new(1);
+        assert_eq!(semaphore.wait_timed(), Duration::ZERO);
+
+        let waiter_semaphore = semaphore.clone();
+        let waiter = thread::spawn(move || {
+            let blocked = waiter_semaphore.wait_timed();
+            waiter_semaphore.signal();
+            blocked
+        });
+
+        thread::sleep(Duration::from_millis(50));
+        semaphore.signal();
+
+        let blocked = waiter.join().expect("waiter panicked");
+        println!("waiter blocked for {:?}", blocked);
+        assert!(blocked >= Duration::from_millis(25));
+        assert!(blocked < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_try_signal_reports_overflow() {
+        let semaphore = Semaphore::init_with(2, 1);
+        assert_eq!(semaphore.try_signal(), Ok(()));
+        assert_eq!(semaphore.try_signal(), Err(Overflow));
+        assert_eq!(semaphore.try_signal(), Err(Overflow));
+
+        // The semaphore is still usable after a rejected signal
+        semaphore.wait();
+        assert_eq!(semaphore.try_signal(), Ok(()));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "count may not exceed set maximum")]
+    fn test_signal_past_max_panics_in_debug() {
+        Semaphore::new(1).signal();
+    }
+
+    #[test]
+    #[cfg(not(debug_assertions))]
+    fn test_signal_past_max_is_ignored_in_release() {
+        let semaphore = Semaphore::new(1);
+        semaphore.signal();
+        assert_eq!(semaphore.available_permits(), 1);
+        assert!(semaphore.try_wait());
+        assert!(!semaphore.try_wait());
+    }
+
+    #[test]
+    fn test_downgrade_releases_to_readers_only() {
+        let semaphore = Semaphore::new(3);
+        for _ in 0..3 {
+            semaphore.wait();
+        }
+
+        thread::scope(|s| {
+            // A second writer needing all three permits, waiting alongside a reader
+            let writer = s.spawn(|| {
+                semaphore.acquire_n(3);
+                semaphore.release_n(3);
+            });
+            let reader = s.spawn(|| {
+                semaphore.wait();
+                semaphore.signal();
+            });
+
+            thread::sleep(Duration::from_millis(20));
+            semaphore.downgrade();
+            reader.join().expect("reader panicked");
+
+            // We still hold one permit, so the writer cannot get in
+            thread::sleep(Duration::from_millis(20));
+            assert!(!writer.is_finished());
+            assert_eq!(semaphore.inner.count.load(Relaxed), 2);
+
+            // Releasing the last reader permit lets it through
+            semaphore.signal();
+            writer.join().expect("writer panicked");
+        });
+        assert_eq!(semaphore.inner.count.load(Relaxed), 3);
+    }
+
+    #[test]
+    fn test_wait_interruptible_cancelled() {
+        let semaphore = Semaphore::init_with(1, 0);
+        let cancelled = Arc::new(AtomicBool::new(false));
+
+        let waiter_semaphore = semaphore.clone();
+        let waiter_cancelled = cancelled.clone();
+        let waiter = thread::spawn(move || waiter_semaphore.wait_interruptible(&waiter_cancelled));
+
+        thread::sleep(Duration::from_millis(20));
+        cancelled.store(true, Release);
+        semaphore.interrupt();
+
+        assert!(!waiter.join().expect("waiter panicked"));
+        // The cancelled waiter must not have consumed a permit
+        semaphore.signal();
+        assert!(semaphore.wait_interruptible(&AtomicBool::new(false)));
+    }
+
+    #[test]
+    fn test_interrupt_right_after_setting_flag_is_not_lost() {
+        // No sleep before interrupting, so it often lands while the waiter is between checking
+        // the flag and parking
+        for semaphore in [Semaphore::init_with(1, 0), Semaphore::new_fair(1)] {
+            if semaphore.inner.queue.is_some() {
+                semaphore.wait();
+            }
+            for _ in 0..200 {
+                let cancelled = Arc::new(AtomicBool::new(false));
+                let waiter_semaphore = semaphore.clone();
+                let waiter_cancelled = cancelled.clone();
+                let waiter = thread::spawn(move || waiter_semaphore.wait_interruptible(&waiter_cancelled));
+                cancelled.store(true, Release);
+                semaphore.interrupt();
+                assert!(!waiter.join().expect("waiter panicked"));
+            }
+        }
+    }
+
+    #[test]
+    fn test_prime_opens_zero_initialized_semaphore() {
+        let semaphore = Semaphore::init_with(4, 0);
+        let barrier = Arc::new(Barrier::new(5));
+
+        let mut waiter_jhs = vec![];
+        for _ in 0..4 {
+            let semaphore = semaphore.clone();
+            let barrier = barrier.clone();
+            waiter_jhs.push(thread::spawn(move || {
+                barrier.wait();
+                semaphore.wait();
+            }));
+        }
+
+        barrier.wait();
+        thread::sleep(Duration::from_millis(20));
+        semaphore.prime(4);
+
+        for jh in waiter_jhs {
+            jh.join().expect("waiter panicked");
+        }
+        let available = semaphore.inner.count.load(Relaxed);
+        assert_eq!(available, 0);
+    }
+
+    fn queued_waiters(semaphore: &Semaphore) -> usize {
+        semaphore.inner.queue.as_ref().expect("semaphore should be fair").lock().len()
+    }
+
+    #[test]
+    fn test_fair_semaphore_hands_off_in_fifo_order() {
+        let semaphore = Semaphore::new_fair(1);
+        let order = Arc::new(Mutex::new(vec![]));
+        semaphore.wait();
+
+        let mut waiter_jhs = vec![];
+        for i in 0..8 {
+            let waiter_semaphore = semaphore.clone();
+            let order = order.clone();
+            waiter_jhs.push(thread::spawn(move || {
+                waiter_semaphore.wait();
+                order.lock().unwrap().push(i);
+                waiter_semaphore.signal();
+            }));
+            // Wait for waiter `i` to be parked before starting the next one
+            while queued_waiters(&semaphore) < i + 1 {
+                thread::yield_now();
+            }
+        }
+
+        // Barge in with a crowd of unqueued threads while the hand-offs happen
+        let mut barger_jhs = vec![];
+        for _ in 0..4 {
+            let barger_semaphore = semaphore.clone();
+            barger_jhs.push(thread::spawn(move || {
+                for _ in 0..100 {
+                    barger_semaphore.wait();
+                    barger_semaphore.signal();
+                }
+            }));
+        }
+
+        semaphore.signal();
+        for jh in waiter_jhs.into_iter().chain(barger_jhs) {
+            jh.join().expect("thread panicked");
+        }
+
+        // Every permit is handed to the oldest waiter, so the queued waiters go in arrival order
+        assert_eq!(*order.lock().unwrap(), (0..8).collect::<Vec<_>>());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_fair_semaphore_interruptible_leaves_queue() {
+        let semaphore = Semaphore::new_fair(1);
+        semaphore.wait();
+        let cancelled = Arc::new(AtomicBool::new(false));
+
+        let waiter_semaphore = semaphore.clone();
+        let waiter_cancelled = cancelled.clone();
+        let waiter = thread::spawn(move || waiter_semaphore.wait_interruptible(&waiter_cancelled));
+
+        while queued_waiters(&semaphore) < 1 {
+            thread::yield_now();
+        }
+        cancelled.store(true, Release);
+        semaphore.interrupt();
+        assert!(!waiter.join().expect("waiter panicked"));
+        assert_eq!(queued_waiters(&semaphore), 0);
+
+        semaphore.signal();
+        assert_eq!(semaphore.wait_timed(), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_builder_configures_semaphore() {
+        let semaphore = Semaphore::builder().max(3).initial(1).build();
+        assert!(semaphore.inner.queue.is_none());
+        assert_eq!(semaphore.inner.count.load(Relaxed), 1);
+        assert_eq!(semaphore.try_signal(), Ok(()));
+        assert_eq!(semaphore.try_signal(), Ok(()));
+        assert_eq!(semaphore.try_signal(), Err(Overflow));
+
+        let semaphore = Semaphore::builder().build();
+        assert_eq!(semaphore.inner.count.load(Relaxed), 1);
+        assert_eq!(semaphore.try_signal(), Err(Overflow));
+    }
+
+    #[test]
+    fn test_builder_fair_semaphore_is_fifo() {
+        let semaphore = Semaphore::builder().max(1).initial(0).fair(true).build();
+        let order = Arc::new(Mutex::new(vec![]));
+
+        let mut waiter_jhs = vec![];
+        for i in 0..4 {
+            let waiter_semaphore = semaphore.clone();
+            let order = order.clone();
+            waiter_jhs.push(thread::spawn(move || {
+                waiter_semaphore.wait();
+                order.lock().unwrap().push(i);
+                waiter_semaphore.signal();
+            }));
+            while queued_waiters(&semaphore) < i + 1 {
+                thread::yield_now();
+            }
+        }
+
+        semaphore.signal();
+        for jh in waiter_jhs {
+            jh.join().expect("waiter panicked");
+        }
+        assert_eq!(*order.lock().unwrap(), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_acquire_all_excludes_waiters() {
+        for semaphore in [Semaphore::new(3), Semaphore::new_fair(3)] {
+            let guard = semaphore.acquire_all();
+            let entered = AtomicU32::new(0);
+            thread::scope(|s| {
+                for _ in 0..3 {
+                    s.spawn(|| {
+                        semaphore.wait();
+                        entered.fetch_add(1, Relaxed);
+                        semaphore.signal();
+                    });
+                }
+                thread::sleep(Duration::from_millis(30));
+                assert_eq!(entered.load(Relaxed), 0);
+                drop(guard);
+            });
+            assert_eq!(entered.load(Relaxed), 3);
+            assert_eq!(semaphore.inner.count.load(Relaxed), 3);
+        }
+    }
+
+    #[test]
+    fn test_guard_downgrades_to_single_permit() {
+        let semaphore = Semaphore::new(3);
+        let permit = semaphore.acquire_all().downgrade();
+        assert_eq!(semaphore.available_permits(), 2);
+        assert!(semaphore.try_wait());
+        semaphore.signal();
+
+        thread::scope(|s| {
+            // The gate was released, so another writer gets as far as waiting for our permit
+            let writer = s.spawn(|| drop(semaphore.acquire_all()));
+            thread::sleep(Duration::from_millis(20));
+            assert!(!writer.is_finished());
+            drop(permit);
+            writer.join().expect("writer panicked");
+        });
+        assert_eq!(semaphore.available_permits(), 3);
+        assert_eq!(semaphore.inner.exclusive.load(Relaxed), 0);
+    }
+
+    #[test]
+    fn test_acquire_all_callers_take_turns() {
+        let semaphore = Semaphore::new(4);
+        let inside = AtomicU32::new(0);
+        thread::scope(|s| {
+            for _ in 0..3 {
+                s.spawn(|| {
+                    for _ in 0..50 {
+                        let _guard = semaphore.acquire_all();
+                        assert_eq!(inside.fetch_add(1, Relaxed), 0);
+                        inside.fetch_sub(1, Relaxed);
+                    }
+                });
+                s.spawn(|| {
+                    for _ in 0..50 {
+                        semaphore.wait();
+                        semaphore.signal();
+                    }
+                });
+            }
+        });
+        assert_eq!(semaphore.inner.count.load(Relaxed), 4);
+    }
+
+    fn parks_for(semaphore: &Semaphore, threads: usize, iters: usize, hold: Duration) -> usize {
+        let held = AtomicU32::new(0);
+        thread::scope(|s| {
+            for _ in 0..threads {
+                s.spawn(|| {
+                    for _ in 0..iters {
+                        semaphore.wait();
+                        assert_eq!(held.fetch_add(1, Relaxed), 0);
+                        if hold.is_zero() {
+                            for _ in 0..100 {
+                                std::hint::spin_loop();
+                            }
+                        } else {
+                            thread::sleep(hold);
+                        }
+                        held.fetch_sub(1, Relaxed);
+                        semaphore.signal();
+                    }
+                });
+            }
+        });
+        assert_eq!(semaphore.inner.count.load(Relaxed), 1);
+        semaphore.inner.parks.load(Relaxed)
+    }
+
+    // Runs `rounds` waits on `semaphore`, each on a drained semaphore with another thread that is
+    // already running and hands the permit back as soon as the waiter starts spinning or parks.
+    // The releaser never waits on the waiter beyond that, so this works the same on one core.
+    // Returns how many of those waits parked.
+    fn parks_with_prompt_releases(semaphore: &Semaphore, rounds: usize) -> usize {
+        let before = semaphore.inner.parks.load(Relaxed);
+        semaphore.wait();
+        thread::scope(|s| {
+            for _ in 0..rounds {
+                let spins = semaphore.inner.spins.load(Relaxed);
+                let releaser = s.spawn(move || {
+                    while semaphore.inner.spins.load(Relaxed) == spins && semaphore.inner.waiters.load(SeqCst) == 0 {
+                        thread::yield_now();
+                    }
+                    semaphore.signal();
+                });
+                // We hold the only permit, so this waits for the releaser to hand it back
+                semaphore.wait();
+                releaser.join().expect("releaser panicked");
+            }
+        });
+        semaphore.signal();
+        semaphore.inner.parks.load(Relaxed) - before
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "std"), ignore = "counts waiters that are asleep in the futex")]
+    fn test_wait_spins_instead_of_parking_on_short_holds() {
+        const ROUNDS: usize = 50;
+        let baseline = parks_with_prompt_releases(&Semaphore::with_spin(1, 0), ROUNDS);
+        let adaptive = parks_with_prompt_releases(&Semaphore::new(1), ROUNDS);
+        // Never spinning, every wait finds the semaphore drained and parks at least once
+        assert!(baseline >= ROUNDS);
+        println!("parks: always {baseline}, adaptive {adaptive}");
+        assert!(adaptive < baseline, "adaptive spinning parked {adaptive} times, always parking {baseline}");
+    }
+
+    #[test]
+    fn test_with_spin_bounds_spinning() {
+        let semaphore = Semaphore::with_spin(1, 64);
+        assert_eq!(semaphore.available_permits(), 1);
+        assert_eq!(semaphore.inner.spin_limit.load(Relaxed), 64);
+        parks_for(&semaphore, 3, 200, Duration::ZERO);
+        assert!(semaphore.inner.spin_limit.load(Relaxed) <= 64);
+
+        // Never spinning means every wait on a drained semaphore parks
+        let semaphore = Semaphore::with_spin(1, 0);
+        assert!(parks_for(&semaphore, 2, 20, Duration::from_millis(1)) > 0);
+        assert_eq!(semaphore.inner.spin_limit.load(Relaxed), 0);
+    }
+
+    #[test]
+    fn test_wait_backs_off_spinning_on_long_holds() {
+        let semaphore = Semaphore::new(1);
+        let parks = parks_for(&semaphore, 3, 10, Duration::from_millis(2));
+        // Spinning never outlasts a 2ms hold, so waiters end up parking and spinning shrinks
+        assert!(parks > 0);
+        assert!(semaphore.inner.spin_limit.load(Relaxed) < MAX_SPINS);
+    }
+
+    // Parks `threads` waiters on a drained semaphore that never spins, and returns once all of
+    // them are parked
+    fn park_waiters<'s>(s: &'s thread::Scope<'s, '_>, semaphore: &'s Semaphore, threads: u32) -> Vec<thread::ScopedJoinHandle<'s, ()>> {
+        let jhs = (0..threads).map(|_| s.spawn(|| semaphore.wait())).collect();
+        while semaphore.inner.waiters.load(SeqCst) < threads {
+            thread::yield_now();
+        }
+        // Give the last one time to get from the counter into the futex
+        thread::sleep(Duration::from_millis(20));
+        jhs
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "std"), ignore = "counts waiters that are asleep in the futex")]
+    fn test_signal_wakes_one_waiter_per_permit() {
+        let semaphore = Semaphore { inner: Shared::new(InnerSemaphore { max_spins: 0, ..InnerSemaphore::init_with(8, 0) }) };
+        let (parks, finished) = thread::scope(|s| {
+            let jhs = park_waiters(s, &semaphore, 8);
+            assert_eq!(semaphore.inner.parks.load(Relaxed), 8);
+
+            semaphore.signal();
+            while !jhs.iter().any(|jh| jh.is_finished()) {
+                thread::yield_now();
+            }
+            thread::sleep(Duration::from_millis(20));
+            let seen = (semaphore.inner.parks.load(Relaxed), jhs.iter().filter(|jh| jh.is_finished()).count());
+
+            semaphore.release_n(7);
+            for jh in jhs {
+                jh.join().expect("waiter panicked");
+            }
+            seen
+        });
+        // Only the waiter that got the permit woke, nobody went back to sleep
+        assert_eq!(parks, 8);
+        assert_eq!(finished, 1);
+        assert_eq!(semaphore.inner.count.load(Relaxed), 0);
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "std"), ignore = "counts waiters that are asleep in the futex")]
+    fn test_quick_signals_wake_as_many_waiters() {
+        for _ in 0..20 {
+            let semaphore = Semaphore::init_with(4, 0);
+            thread::scope(|s| {
+                let jhs = park_waiters(s, &semaphore, 4);
+                // The later signals land before the first woken waiter takes its permit, they must
+                // still wake a waiter each
+                for _ in 0..4 {
+                    semaphore.signal();
+                }
+                for jh in jhs {
+                    jh.join().expect("waiter panicked");
+                }
+            });
+            assert_eq!(semaphore.inner.count.load(Relaxed), 0);
+        }
+    }
+
+    #[test]
+    fn test_many_waiters_few_permits_make_progress() {
+        let semaphore = Semaphore::new(2);
+        let in_use = AtomicU32::new(0);
+        thread::scope(|s| {
+            for _ in 0..16 {
+                s.spawn(|| {
+                    for _ in 0..200 {
+                        semaphore.wait();
+                        assert!(in_use.fetch_add(1, Relaxed) < 2);
+                        thread::yield_now();
+                        in_use.fetch_sub(1, Relaxed);
+                        semaphore.signal();
+                    }
+                });
+            }
+        });
+        assert_eq!(semaphore.inner.count.load(Relaxed), 2);
+        assert_eq!(semaphore.inner.waiters.load(Relaxed), 0);
+    }
+
+    #[test]
+    fn test_acquire_n_woken_by_partial_releases() {
+        for semaphore in [Semaphore::init_with(2, 0), Semaphore::builder().max(2).initial(0).fair(true).build()] {
+            thread::scope(|s| {
+                let waiter = s.spawn(|| semaphore.acquire_n(2));
+                semaphore.signal();
+                thread::sleep(Duration::from_millis(20));
+                // One permit is not enough, and this release does not bring the count up from zero
+                assert!(!waiter.is_finished());
+                semaphore.signal();
+            });
+            assert_eq!(semaphore.inner.count.load(Relaxed), 0);
+            semaphore.release_n(2);
+            assert_eq!(semaphore.inner.count.load(Relaxed), 2);
+        }
+    }
+
+    #[test]
+    fn test_acquire_n_mixed_with_single_permits() {
+        let semaphore = Semaphore::new(3);
+        let in_use = AtomicU32::new(0);
+        thread::scope(|s| {
+            for n in [2, 2, 1, 1, 1] {
+                let (semaphore, in_use) = (&semaphore, &in_use);
+                s.spawn(move || {
+                    for _ in 0..200 {
+                        if n == 1 {
+                            semaphore.wait();
+                        } else {
+                            semaphore.acquire_n(n);
+                        }
+                        assert!(in_use.fetch_add(n, Relaxed) + n <= 3);
+                        in_use.fetch_sub(n, Relaxed);
+                        if n == 1 {
+                            semaphore.signal();
+                        } else {
+                            semaphore.release_n(n);
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(semaphore.inner.count.load(Relaxed), 3);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_permit_releases_on_drop() {
+        let semaphore = Semaphore::new(2);
+        {
+            let _permit = semaphore.acquire();
+            assert_eq!(semaphore.inner.count.load(Relaxed), 1);
+            let _other = semaphore.acquire();
+            assert_eq!(semaphore.inner.count.load(Relaxed), 0);
+        }
+        assert_eq!(semaphore.inner.count.load(Relaxed), 2);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_permit_releases_on_panic() {
+        let semaphore = Semaphore::new(1);
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _permit = semaphore.acquire();
+            panic!("holder panicked");
+        }));
+        assert!(res.is_err());
+        assert_eq!(semaphore.inner.count.load(Relaxed), 1);
+        // Only semaphores built with `poisoning(true)` are poisoned
+        assert!(!semaphore.is_poisoned());
+        // The permit is really back, not just counted
+        drop(semaphore.acquire());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_permit_panic_poisons_semaphore() {
+        let semaphore = Semaphore::builder().max(2).poisoning(true).build();
+        assert!(!semaphore.is_poisoned());
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _permit = semaphore.acquire().expect("not poisoned yet");
+            panic!("holder panicked");
+        }));
+        assert!(res.is_err());
+        assert!(semaphore.is_poisoned());
+
+        // The permit still came back, and the next holder can take it despite the poison
+        assert_eq!(semaphore.available_permits(), 2);
+        let Err(poisoned) = semaphore.acquire() else { panic!("semaphore should be poisoned") };
+        let permit = poisoned.into_inner();
+        assert_eq!(semaphore.available_permits(), 1);
+        drop(permit);
+        // Dropping a permit normally does not poison or un-poison anything
+        assert!(semaphore.is_poisoned());
+
+        semaphore.clear_poison();
+        assert!(semaphore.acquire().is_ok());
+    }
+
+    #[test]
+    fn test_try_wait_fails_once_drained() {
+        for semaphore in [Semaphore::new(3), Semaphore::new_fair(3)] {
+            for _ in 0..3 {
+                assert!(semaphore.try_wait());
+            }
+            assert!(!semaphore.try_wait());
+            assert_eq!(semaphore.inner.count.load(Relaxed), 0);
+
+            semaphore.signal();
+            assert!(semaphore.try_wait());
+            assert!(!semaphore.try_wait());
+            semaphore.release_n(3);
+        }
+    }
+
+    #[test]
+    fn test_try_wait_does_not_overtake_fair_waiters() {
+        let semaphore = Semaphore::builder().max(1).initial(0).fair(true).build();
+        thread::scope(|s| {
+            let waiter = s.spawn(|| semaphore.wait());
+            while queued_waiters(&semaphore) == 0 {
+                thread::yield_now();
+            }
+            // The permit goes straight to the queued waiter, leaving nothing to try for
+            semaphore.signal();
+            assert!(!semaphore.try_wait());
+            waiter.join().expect("waiter panicked");
+        });
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_wait_timeout_expires_on_exhausted_semaphore() {
+        let semaphore = Semaphore::init_with(1, 0);
+        let start = std::time::Instant::now();
+        assert!(!semaphore.wait_timeout(Duration::from_millis(50)));
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(50), "gave up after {elapsed:?}");
+        assert!(elapsed < Duration::from_millis(250), "overran to {elapsed:?}");
+        assert!(!semaphore.wait_timeout(Duration::ZERO));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_wait_timeout_takes_permit_released_in_time() {
+        let semaphore = Semaphore::init_with(1, 0);
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(20));
+                semaphore.signal();
+            });
+            assert!(semaphore.wait_timeout(Duration::from_secs(5)));
+        });
+        assert_eq!(semaphore.inner.count.load(Relaxed), 0);
+        semaphore.signal();
+        assert!(semaphore.wait_timeout(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_reset_restores_drained_semaphore() {
+        for semaphore in [Semaphore::new(3), Semaphore::new_fair(3)] {
+            while semaphore.try_wait() {}
+            thread::scope(|s| {
+                let jhs = if semaphore.inner.queue.is_some() {
+                    let jhs: Vec<_> = (0..3).map(|_| s.spawn(|| semaphore.wait())).collect();
+                    while queued_waiters(&semaphore) < 3 {
+                        thread::yield_now();
+                    }
+                    jhs
+                } else {
+                    park_waiters(s, &semaphore, 3)
+                };
+                semaphore.reset(3);
+                for jh in jhs {
+                    jh.join().expect("waiter panicked");
+                }
+            });
+            assert_eq!(semaphore.available_permits(), 0);
+
+            semaphore.reset(2);
+            assert_eq!(semaphore.available_permits(), 2);
+            semaphore.reset(0);
+            assert!(!semaphore.try_wait());
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "count may not exceed set maximum")]
+    fn test_reset_past_max_panics() {
+        Semaphore::new(2).reset(3);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_available_and_max_permits() {
+        let semaphore = Semaphore::init_with(5, 3);
+        assert_eq!(semaphore.available_permits(), 3);
+        assert_eq!(semaphore.max_permits(), 5);
+
+        let permit = semaphore.acquire().expect("semaphore does not poison");
+        assert_eq!(semaphore.available_permits(), 2);
+        drop(permit);
+        semaphore.release_n(2);
+        assert_eq!(semaphore.available_permits(), 5);
+        assert_eq!(semaphore.max_permits(), 5);
+    }
 }
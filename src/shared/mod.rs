@@ -0,0 +1,228 @@
+use core::fmt;
+use core::marker::PhantomData;
+use core::mem::ManuallyDrop;
+use core::ops::Deref;
+use core::ptr::{self, NonNull};
+use alloc::boxed::Box;
+use crate::sync::AtomicUsize;
+use crate::sync::Ordering::{Relaxed, Release, Acquire};
+use crate::sync::fence;
+
+
+struct SharedInner<T> {
+    strong: AtomicUsize,
+    // Number of `WeakShared` handles, plus one shared by all the strong handles together
+    weak: AtomicUsize,
+    // Dropped by the last strong handle, while weak handles may still keep the allocation around
+    data: ManuallyDrop<T>,
+}
+
+// The heap allocation behind every handle type in the crate: a value plus the number of handles
+// pointing at it. Cloning bumps the count, and the last handle to drop frees the value. As with
+// `Arc`, weak handles keep only the allocation alive, not the value.
+// `Shared` is neither `Send` nor `Sync`; each handle type states for itself when it is.
+pub(crate) struct Shared<T> {
+    ptr: NonNull<SharedInner<T>>,
+    phantom: PhantomData<SharedInner<T>>,
+}
+
+impl<T> Shared<T> {
+    pub(crate) fn new(data: T) -> Self {
+        let inner = Box::new(SharedInner {
+            strong: AtomicUsize::new(1),
+            weak: AtomicUsize::new(1),
+            data: ManuallyDrop::new(data),
+        });
+        // Safety: Box::into_raw never returns null
+        let ptr = unsafe { NonNull::new_unchecked(Box::into_raw(inner)) };
+        Self { ptr, phantom: PhantomData }
+    }
+
+    // For types whose methods take `&mut self` and synchronize internally. Dereferencing it
+    // mutably is only sound while no other handle can touch the same fields.
+    pub(crate) fn as_ptr(&self) -> *mut T {
+        // Safety: The value lives as long as any strong handle, including this one. ManuallyDrop
+        // is transparent, so the cast keeps pointing at the value
+        unsafe { ptr::addr_of_mut!((*self.ptr.as_ptr()).data).cast::<T>() }
+    }
+
+    #[cfg(all(test, not(feature = "loom")))]
+    pub(crate) fn ref_count(&self) -> usize {
+        // Safety: The allocation lives as long as any handle, including this one
+        unsafe { self.ptr.as_ref().strong.load(Relaxed) }
+    }
+
+    pub(crate) fn ptr_eq(a: &Self, b: &Self) -> bool {
+        a.ptr == b.ptr
+    }
+
+    pub(crate) fn downgrade(this: &Self) -> WeakShared<T> {
+        // Like `clone`, we already hold a handle, so nothing needs to be ordered
+        // Safety: The allocation lives as long as any handle, including this one
+        unsafe { this.ptr.as_ref().weak.fetch_add(1, Relaxed); }
+        WeakShared { ptr: this.ptr, phantom: PhantomData }
+    }
+}
+
+impl<T> Deref for Shared<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // Safety: The value lives as long as any strong handle, including this one
+        unsafe { &self.ptr.as_ref().data }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Shared<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<T> Clone for Shared<T> {
+    fn clone(&self) -> Self {
+        // A new handle can only be made from an existing one, so nothing needs to be ordered here
+        // Safety: The allocation lives as long as any handle, including this one
+        unsafe { self.ptr.as_ref().strong.fetch_add(1, Relaxed); }
+        Self { ptr: self.ptr, phantom: PhantomData }
+    }
+}
+
+impl<T> Drop for Shared<T> {
+    fn drop(&mut self) {
+        // Safety: The allocation lives as long as any handle, including this one
+        unsafe {
+            // Release publishes everything this handle did, and the Acquire fence makes all of it
+            // visible to whichever handle ends up dropping the value
+            if self.ptr.as_ref().strong.fetch_sub(1, Release) == 1 {
+                fence(Acquire);
+                // We were the last strong handle, and `upgrade` never revives a count of zero, so
+                // nobody else can reach the value
+                ManuallyDrop::drop(&mut (*self.ptr.as_ptr()).data);
+                // Give up the weak count the strong handles shared
+                drop(WeakShared { ptr: self.ptr, phantom: PhantomData });
+            }
+        }
+    }
+}
+
+// A handle that keeps the allocation, but not the value, alive. See `Shared::downgrade`.
+pub(crate) struct WeakShared<T> {
+    ptr: NonNull<SharedInner<T>>,
+    phantom: PhantomData<SharedInner<T>>,
+}
+
+impl<T> WeakShared<T> {
+    pub(crate) fn upgrade(&self) -> Option<Shared<T>> {
+        // Safety: The allocation lives as long as any handle, including this one
+        let strong = unsafe { &self.ptr.as_ref().strong };
+        let mut cur = strong.load(Relaxed);
+        loop {
+            // Once the count reaches zero the value is gone for good
+            if cur == 0 {
+                return None;
+            }
+            // Acquire pairs with the Release decrements, so we see every write made through the
+            // strong handles before we join them
+            match strong.compare_exchange_weak(cur, cur + 1, Acquire, Relaxed) {
+                Ok(_) => return Some(Shared { ptr: self.ptr, phantom: PhantomData }),
+                Err(next) => cur = next,
+            }
+        }
+    }
+}
+
+impl<T> Clone for WeakShared<T> {
+    fn clone(&self) -> Self {
+        // Safety: The allocation lives as long as any handle, including this one
+        unsafe { self.ptr.as_ref().weak.fetch_add(1, Relaxed); }
+        Self { ptr: self.ptr, phantom: PhantomData }
+    }
+}
+
+impl<T> Drop for WeakShared<T> {
+    fn drop(&mut self) {
+        // Safety: The allocation lives as long as any handle, including this one
+        unsafe {
+            if self.ptr.as_ref().weak.fetch_sub(1, Release) == 1 {
+                fence(Acquire);
+                // The value has already been dropped (`data` is ManuallyDrop), free the allocation
+                drop(Box::from_raw(self.ptr.as_ptr()));
+            }
+        }
+    }
+}
+
+
+#[cfg(all(test, not(feature = "loom")))]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    struct DropFlag(Arc<AtomicUsize>);
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_shared_counts_handles() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let shared = Shared::new(DropFlag(drops.clone()));
+        assert_eq!(shared.ref_count(), 1);
+
+        let other = shared.clone();
+        assert_eq!(shared.ref_count(), 2);
+        assert!(Shared::ptr_eq(&shared, &other));
+        assert!(!Shared::ptr_eq(&shared, &Shared::new(DropFlag(Arc::new(AtomicUsize::new(0))))));
+
+        drop(other);
+        assert_eq!(shared.ref_count(), 1);
+        assert_eq!(drops.load(Relaxed), 0);
+        drop(shared);
+        assert_eq!(drops.load(Relaxed), 1);
+    }
+
+    #[test]
+    fn test_shared_last_handle_frees_across_threads() {
+        struct SendShared(Shared<DropFlag>);
+        unsafe impl Send for SendShared {}
+
+        let drops = Arc::new(AtomicUsize::new(0));
+        let shared = Shared::new(DropFlag(drops.clone()));
+        let jhs: Vec<_> = (0..4).map(|_| {
+            let handle = SendShared(shared.clone());
+            thread::spawn(move || {
+                let handle = handle;
+                for _ in 0..100 {
+                    drop(handle.0.clone());
+                }
+            })
+        }).collect();
+        drop(shared);
+        for jh in jhs {
+            jh.join().expect("thread panicked");
+        }
+        assert_eq!(drops.load(Relaxed), 1);
+    }
+
+    #[test]
+    fn test_weak_shared_does_not_keep_value_alive() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let shared = Shared::new(DropFlag(drops.clone()));
+        let weak = Shared::downgrade(&shared);
+
+        let upgraded = weak.upgrade().expect("a strong handle is still alive");
+        assert_eq!(shared.ref_count(), 2);
+        drop(upgraded);
+
+        let other_weak = weak.clone();
+        drop(shared);
+        assert_eq!(drops.load(Relaxed), 1);
+        assert!(weak.upgrade().is_none());
+        assert!(other_weak.upgrade().is_none());
+    }
+}
@@ -0,0 +1,162 @@
+use alloc::{vec, vec::Vec};
+use crate::semaphore::Semaphore;
+use super::Stack;
+
+
+/// A `Stack` holding at most `capacity` elements. A second semaphore, starting at `capacity`,
+/// counts the free slots: a push takes a slot before touching the stack and a pop gives one back.
+pub struct BoundedStack<T> {
+    stack: Stack<T>,
+    space: Semaphore,
+}
+
+impl<T> BoundedStack<T> {
+    /// Panics if `capacity` is 0 or does not fit in a `u32`.
+    pub fn with_capacity(capacity: usize) -> Self {
+        let capacity = u32::try_from(capacity).expect("capacity must fit in a u32");
+        Self { stack: Stack::new(), space: Semaphore::new(capacity) }
+    }
+
+    /// Pushes `val`, parking until there is room for it if the stack is full.
+    pub fn push(&self, val: T) {
+        self.space.wait();
+        self.stack.push(val);
+    }
+
+    /// Pushes `val` if there is room for it right now, otherwise hands it back.
+    pub fn try_push(&self, val: T) -> Result<(), T> {
+        if !self.space.try_wait() {
+            return Err(val);
+        }
+        self.stack.push(val);
+        Ok(())
+    }
+
+    /// Pushes elements from `iter` in order for as long as there is room, all under a single lock
+    /// acquisition like `Stack::push_all`. If the stack fills up first, the elements that did not
+    /// fit are handed back in their original order.
+    pub fn try_push_all<I: IntoIterator<Item = T>>(&self, iter: I) -> Result<(), Vec<T>> {
+        let mut iter = iter.into_iter();
+        let mut fits = vec![];
+        let mut rest = None;
+        for val in iter.by_ref() {
+            // Take a slot per element up front, so nothing that does not fit is ever pushed
+            if !self.space.try_wait() {
+                rest = Some(val);
+                break;
+            }
+            fits.push(val);
+        }
+        self.stack.push_all(fits);
+        match rest {
+            Some(val) => Err(core::iter::once(val).chain(iter).collect()),
+            None => Ok(()),
+        }
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        let val = self.stack.pop()?;
+        self.space.signal();
+        Some(val)
+    }
+
+    /// A snapshot of the number of elements, see `Stack::len`.
+    pub fn len(&self) -> usize {
+        self.stack.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stack.is_empty()
+    }
+
+    /// Always `Some`, unlike `Stack::capacity`.
+    pub fn capacity(&self) -> Option<usize> {
+        Some(self.space.max_permits() as usize)
+    }
+
+    /// How many more elements a `try_push_all` would take right now, always `Some`. Under
+    /// concurrency this is only a snapshot, like `len`.
+    pub fn remaining_capacity(&self) -> Option<usize> {
+        // The free slots rather than capacity minus `len`: a push takes its slot before the
+        // element shows up in `len`, so the difference would count slots that are already spoken for
+        Some(self.space.available_permits() as usize)
+    }
+}
+
+impl<T> Clone for BoundedStack<T> {
+    fn clone(&self) -> Self {
+        BoundedStack { stack: self.stack.clone(), space: self.space.clone() }
+    }
+}
+
+
+#[cfg(all(test, not(feature = "loom")))]
+mod test {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_bounded_stack_try_push_rejects_when_full() {
+        let stack = BoundedStack::with_capacity(3);
+        for i in 0..3 {
+            assert_eq!(stack.try_push(i), Ok(()));
+        }
+        assert_eq!(stack.try_push(3), Err(3));
+        assert_eq!(Some(stack.len()), stack.capacity());
+
+        assert_eq!(stack.pop(), Some(2));
+        assert_eq!(stack.try_push(4), Ok(()));
+        assert_eq!(stack.try_push(5), Err(5));
+    }
+
+    #[test]
+    fn test_bounded_stack_try_push_all_pushes_what_fits() {
+        let stack = BoundedStack::with_capacity(4);
+        stack.push(10);
+        assert_eq!(stack.try_push_all([1, 2, 3, 4, 5]), Err(vec![4, 5]));
+        assert_eq!(stack.len(), 4);
+        assert_eq!(stack.try_push_all(Vec::new()), Ok(()));
+        assert_eq!(stack.try_push_all([6]), Err(vec![6]));
+
+        for expected in [3, 2, 1, 10] {
+            assert_eq!(stack.pop(), Some(expected));
+        }
+        assert_eq!(stack.try_push_all([7, 8]), Ok(()));
+        assert_eq!(stack.pop(), Some(8));
+        assert_eq!(stack.pop(), Some(7));
+        assert_eq!(stack.pop(), None);
+    }
+
+    #[test]
+    fn test_bounded_stack_capacity_tracks_pushes_and_pops() {
+        let stack = BoundedStack::with_capacity(3);
+        assert_eq!((stack.capacity(), stack.remaining_capacity()), (Some(3), Some(3)));
+        for (i, remaining) in [(0, 2), (1, 1), (2, 0)] {
+            stack.push(i);
+            assert_eq!((stack.capacity(), stack.remaining_capacity()), (Some(3), Some(remaining)));
+        }
+        assert_eq!(stack.pop(), Some(2));
+        assert_eq!(stack.remaining_capacity(), Some(1));
+        assert_eq!(stack.try_push_all([7, 8]), Err(vec![8]));
+        assert_eq!(stack.remaining_capacity(), Some(0));
+        while stack.pop().is_some() {}
+        assert_eq!((stack.capacity(), stack.remaining_capacity()), (Some(3), Some(3)));
+    }
+
+    #[test]
+    fn test_bounded_stack_push_waits_for_space() {
+        let stack = BoundedStack::with_capacity(1);
+        stack.push(1);
+        thread::scope(|s| {
+            let pusher = s.spawn(|| stack.push(2));
+            thread::sleep(Duration::from_millis(30));
+            assert!(!pusher.is_finished());
+            assert_eq!(stack.pop(), Some(1));
+            pusher.join().expect("pusher panicked");
+        });
+        assert_eq!(stack.pop(), Some(2));
+        assert_eq!(stack.pop(), None);
+        assert!(stack.is_empty());
+    }
+}
@@ -0,0 +1,95 @@
+use super::Stack;
+
+
+/// A single-slot cell for publishing the latest value: each `push_replace` swaps the new value in
+/// and hands back the one it superseded. Built on a `Stack` that only ever has its top replaced,
+/// so it never holds more than one node.
+pub struct LatestCell<T> {
+    stack: Stack<T>,
+}
+
+impl<T> LatestCell<T> {
+    pub fn new() -> Self {
+        Self { stack: Stack::new() }
+    }
+
+    /// Stores `val`, returning the value it replaced, or `None` if the cell was empty.
+    pub fn push_replace(&self, val: T) -> Option<T> {
+        self.stack.replace_top(val)
+    }
+
+    /// Takes the value out, leaving the cell empty.
+    pub fn take(&self) -> Option<T> {
+        self.stack.pop()
+    }
+
+    /// Returns a clone of the current value without taking it.
+    pub fn peek(&self) -> Option<T>
+    where T: Clone
+    {
+        self.stack.peek()
+    }
+
+    /// 1 if the cell holds a value, 0 otherwise.
+    pub fn len(&self) -> usize {
+        self.stack.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stack.is_empty()
+    }
+}
+
+impl<T> Default for LatestCell<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Clone for LatestCell<T> {
+    fn clone(&self) -> Self {
+        LatestCell { stack: self.stack.clone() }
+    }
+}
+
+
+#[cfg(all(test, not(feature = "loom")))]
+mod test {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_latest_cell_push_replace_returns_previous() {
+        let cell = LatestCell::new();
+        assert!(cell.is_empty());
+        assert_eq!(cell.push_replace(0), None);
+        for i in 1..10 {
+            assert_eq!(cell.push_replace(i), Some(i - 1));
+            assert_eq!(cell.len(), 1);
+        }
+        assert_eq!(cell.peek(), Some(9));
+        assert_eq!(cell.take(), Some(9));
+        assert!(cell.is_empty());
+        assert_eq!(cell.push_replace(10), None);
+    }
+
+    #[test]
+    fn test_latest_cell_concurrent_publishers_lose_nothing() {
+        let cell = LatestCell::new();
+        let mut seen: Vec<_> = thread::scope(|s| {
+            let publishers: Vec<_> = (0..4).map(|i| {
+                let cell = &cell;
+                s.spawn(move || {
+                    (0..500).filter_map(|j| cell.push_replace(500 * i + j)).collect::<Vec<_>>()
+                })
+            }).collect();
+            publishers.into_iter().flat_map(|jh| jh.join().expect("publisher panicked")).collect()
+        });
+        assert_eq!(cell.len(), 1);
+
+        // Every value was either superseded exactly once or is the one left in the cell
+        seen.extend(cell.take());
+        seen.sort();
+        assert_eq!(seen, (0..2000).collect::<Vec<_>>());
+    }
+}
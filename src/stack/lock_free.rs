@@ -0,0 +1,578 @@
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::mem::MaybeUninit;
+use core::ptr;
+use alloc::boxed::Box;
+use crate::sync::{AtomicPtr, AtomicU64};
+use crate::sync::Ordering::{Acquire, Release, Relaxed, SeqCst};
+use crate::shared::Shared;
+use crate::backoff::Backoff;
+use crate::hazard::{Hazard, HazardDomain};
+use crate::tagged::{pack, unpack};
+
+
+struct LockFreeNode<T> {
+    // Initialized while the node is on the element list, taken out by whoever pops it
+    data: UnsafeCell<MaybeUninit<T>>,
+    // Atomic because a popper that lost a race may still read it while another thread pops the node
+    next: AtomicPtr<LockFreeNode<T>>,
+}
+
+// A Treiber list whose head is a tagged pointer, with the tag bumped by every successful CAS. A
+// popper publishes a hazard on the head and checks the head word is unchanged before it reads
+// `next`, so the node cannot be freed, and its address reused, until the popper is done with it.
+// The tag means the check fails if the node was popped and anything else pushed in the meantime.
+struct TaggedList<T> {
+    head: AtomicU64,
+    // The list owns the nodes `head` points to
+    phantom: PhantomData<Box<LockFreeNode<T>>>,
+}
+
+impl<T> TaggedList<T> {
+    fn new() -> Self {
+        Self { head: AtomicU64::new(pack::<LockFreeNode<T>>(ptr::null_mut(), 0)), phantom: PhantomData }
+    }
+
+    unsafe fn push_node(&self, node: *mut LockFreeNode<T>) {
+        let mut cur = self.head.load(Relaxed);
+        loop {
+            let (head, tag) = unpack::<LockFreeNode<T>>(cur);
+            (*node).next.store(head, Relaxed);
+            // Release publishes the node's data and `next` to whoever pops it
+            match self.head.compare_exchange_weak(cur, pack(node, tag.wrapping_add(1)), Release, Relaxed) {
+                Ok(_) => return,
+                Err(next) => cur = next,
+            }
+        }
+    }
+
+    // A single attempt at `push_node`, returning false if another thread changed the head first
+    unsafe fn try_push_node(&self, node: *mut LockFreeNode<T>) -> bool {
+        let cur = self.head.load(Relaxed);
+        let (head, tag) = unpack::<LockFreeNode<T>>(cur);
+        (*node).next.store(head, Relaxed);
+        self.head.compare_exchange(cur, pack(node, tag.wrapping_add(1)), Release, Relaxed).is_ok()
+    }
+
+    // Protects the node at the head word `cur` with `hazard`, returning the head word as it is
+    // afterwards. If it still equals `cur` the node was on top once the hazard was visible, so it
+    // cannot be freed before the hazard is cleared
+    fn protect(&self, hazard: &Hazard<'_>, cur: u64) -> u64 {
+        hazard.protect(unpack::<LockFreeNode<T>>(cur).0);
+        // SeqCst pairs with the hazard loads in `HazardDomain::scan`
+        self.head.load(SeqCst)
+    }
+
+    // A single attempt at `pop_node`, returning `None` if another thread changed the head first
+    unsafe fn try_pop_node(&self, hazard: &Hazard<'_>) -> Option<*mut LockFreeNode<T>> {
+        let cur = self.head.load(Acquire);
+        let (head, tag) = unpack::<LockFreeNode<T>>(cur);
+        if head.is_null() {
+            return Some(head);
+        }
+        if self.protect(hazard, cur) != cur {
+            return None;
+        }
+        let next = (*head).next.load(Relaxed);
+        // SeqCst for the same reason as in `pop_node`
+        self.head.compare_exchange(cur, pack(next, tag.wrapping_add(1)), SeqCst, Relaxed).is_ok().then_some(head)
+    }
+
+    unsafe fn pop_node(&self, hazard: &Hazard<'_>) -> *mut LockFreeNode<T> {
+        let mut cur = self.head.load(Acquire);
+        loop {
+            let (head, tag) = unpack::<LockFreeNode<T>>(cur);
+            if head.is_null() {
+                return head;
+            }
+            let now = self.protect(hazard, cur);
+            if now != cur {
+                cur = now;
+                continue;
+            }
+            // The hazard keeps `head` alive even if another thread pops it after our check. In that
+            // case `next` may be stale, but the tag has moved on and the CAS below fails.
+            let next = (*head).next.load(Relaxed);
+            // SeqCst puts the unlink in a single order with the hazard store and head load in
+            // `protect`: either the other popper's check sees the node gone, or the scan that frees
+            // it sees the hazard
+            match self.head.compare_exchange_weak(cur, pack(next, tag.wrapping_add(1)), SeqCst, Acquire) {
+                Ok(_) => return head,
+                Err(word) => cur = word,
+            }
+        }
+    }
+}
+
+const ELIMINATION_SLOTS: usize = 4;
+// How many backoff rounds a pusher waits in a slot for a popper before withdrawing its offer
+const ELIMINATION_ROUNDS: u32 = 4;
+
+// Exchange slots where a push and a pop that both lost a race on the head can meet and cancel out
+// without touching it. Each slot is a tagged pointer like the list heads: a pusher offers its node
+// by swinging an empty slot to point at it, and a popper takes the node by swinging it back to
+// empty. The tag makes sure a pusher withdrawing its offer can tell its own offer from the same
+// node offered again by someone else after it was taken and its memory reused.
+struct EliminationArray<T> {
+    slots: [AtomicU64; ELIMINATION_SLOTS],
+    // Without std there are no thread locals to keep seeds in, so `slot` counts its calls instead
+    #[cfg(not(feature = "std"))]
+    calls: crate::sync::AtomicU32,
+    phantom: PhantomData<Box<LockFreeNode<T>>>,
+}
+
+impl<T> EliminationArray<T> {
+    fn new() -> Self {
+        Self {
+            slots: core::array::from_fn(|_| AtomicU64::new(pack::<LockFreeNode<T>>(ptr::null_mut(), 0))),
+            #[cfg(not(feature = "std"))]
+            calls: crate::sync::AtomicU32::new(0),
+            phantom: PhantomData,
+        }
+    }
+
+    // Spreads threads over the slots with a per-thread xorshift, so pushers and poppers meet
+    // without all contending on one slot
+    #[cfg(feature = "std")]
+    fn slot(&self) -> &AtomicU64 {
+        thread_local! {
+            static SEED: core::cell::Cell<u32> = const { core::cell::Cell::new(0) };
+        }
+        let x = SEED.with(|seed| {
+            let mut x = seed.get();
+            if x == 0 {
+                // Any non-zero start will do, so use where this thread's seed lives
+                x = (seed as *const core::cell::Cell<u32> as usize as u32) | 1;
+            }
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            seed.set(x);
+            x
+        });
+        &self.slots[x as usize % ELIMINATION_SLOTS]
+    }
+
+    // Without thread locals, hash where this call's stack frame lives, which differs between
+    // threads, together with a shared call count so that retries move on to other slots. Every
+    // call then touches the counter's cache line, but this is only the fallback
+    #[cfg(not(feature = "std"))]
+    fn slot(&self) -> &AtomicU64 {
+        let frame = 0u8;
+        let call = self.calls.fetch_add(1, Relaxed).wrapping_mul(0x9e37_79b9);
+        let mut x = ((&frame as *const u8 as usize as u32) ^ call) | 1;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        &self.slots[x as usize % ELIMINATION_SLOTS]
+    }
+
+    // Offers `node`, which must hold initialized data, to a popper for a short while. Returns true
+    // if a popper took it, in which case the node now belongs to that popper.
+    unsafe fn offer(&self, node: *mut LockFreeNode<T>) -> bool {
+        let slot = self.slot();
+        let cur = slot.load(Relaxed);
+        let (occupant, tag) = unpack::<LockFreeNode<T>>(cur);
+        if !occupant.is_null() {
+            return false;
+        }
+        let offered = pack(node, tag.wrapping_add(1));
+        // Release publishes the node's data to the popper that takes it
+        if slot.compare_exchange(cur, offered, Release, Relaxed).is_err() {
+            return false;
+        }
+        let mut backoff = Backoff::new();
+        for _ in 0..ELIMINATION_ROUNDS {
+            backoff.snooze();
+            // While it holds our offer, only a popper taking it can change the slot
+            if slot.load(Relaxed) != offered {
+                return true;
+            }
+        }
+        // Withdraw, unless a popper got in just before us
+        slot.compare_exchange(offered, pack::<LockFreeNode<T>>(ptr::null_mut(), tag.wrapping_add(2)), Relaxed, Relaxed).is_err()
+    }
+
+    // Takes a node offered by a pusher, or returns null if there is none
+    unsafe fn take(&self) -> *mut LockFreeNode<T> {
+        let slot = self.slot();
+        let cur = slot.load(Relaxed);
+        let (node, tag) = unpack::<LockFreeNode<T>>(cur);
+        if node.is_null() {
+            return node;
+        }
+        // Acquire pairs with the Release in `offer`, making the node's data visible
+        match slot.compare_exchange(cur, pack::<LockFreeNode<T>>(ptr::null_mut(), tag.wrapping_add(1)), Acquire, Relaxed) {
+            Ok(_) => node,
+            Err(_) => ptr::null_mut(),
+        }
+    }
+}
+
+struct InnerLockFreeStack<T> {
+    items: TaggedList<T>,
+    // Only present for stacks created with `LockFreeStack::with_elimination`
+    elimination: Option<EliminationArray<T>>,
+    // Popped nodes are retired here rather than freed straight away, since other poppers may
+    // still be reading `next` off them
+    hazards: HazardDomain,
+}
+
+impl<T> InnerLockFreeStack<T> {
+    fn new() -> Self {
+        Self { items: TaggedList::new(), elimination: None, hazards: HazardDomain::new() }
+    }
+
+    fn with_elimination() -> Self {
+        Self { items: TaggedList::new(), elimination: Some(EliminationArray::new()), hazards: HazardDomain::new() }
+    }
+
+    fn push(&self, val: T) {
+        let node = Box::into_raw(Box::new(LockFreeNode {
+            data: UnsafeCell::new(MaybeUninit::new(val)),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }));
+        // Safety: Nobody else can see the node until it is pushed or handed to a popper
+        unsafe {
+            let Some(elimination) = &self.elimination else {
+                self.items.push_node(node);
+                return;
+            };
+            // Whenever we lose the race on the head, try to hand the node straight to a popper
+            // before going back to it
+            while !self.items.try_push_node(node) && !elimination.offer(node) {}
+        }
+    }
+
+    fn pop(&self) -> Option<T> {
+        let hazard = self.hazards.hazard();
+        // Safety: A node returned by `pop_node` or `take` belongs to this thread alone, and every
+        // node on the element list or in an elimination slot holds initialized data
+        unsafe {
+            let (node, eliminated) = match &self.elimination {
+                None => (self.items.pop_node(&hazard), false),
+                Some(elimination) => loop {
+                    if let Some(node) = self.items.try_pop_node(&hazard) {
+                        break (node, false);
+                    }
+                    let node = elimination.take();
+                    if !node.is_null() {
+                        break (node, true);
+                    }
+                },
+            };
+            drop(hazard);
+            if node.is_null() {
+                return None;
+            }
+            let val = (*(*node).data.get()).assume_init_read();
+            if eliminated {
+                // A node handed over through a slot was never on the element list, so no other
+                // popper can be looking at it
+                drop(Box::from_raw(node));
+            } else {
+                self.hazards.retire(node);
+            }
+            Some(val)
+        }
+    }
+}
+
+impl<T> Drop for InnerLockFreeStack<T> {
+    fn drop(&mut self) {
+        // The popped nodes are retired, and the hazard domain frees them when it drops right after
+        while self.pop().is_some() {}
+    }
+}
+
+/// A lock-free LIFO stack. Unlike `Stack`, pushes and pops never block each other; they race on
+/// the head with a CAS instead. A popped node is freed once no other popper can still be reading
+/// it, which hazard pointers keep track of. At most a few dozen nodes plus one per concurrent pop
+/// wait to be freed at any time.
+///
+/// Stacks created with `with_elimination` additionally let a push and a pop that both lost the race
+/// on the head exchange the element directly, which scales better when pushes and pops are
+/// roughly balanced.
+pub struct LockFreeStack<T> {
+    inner: Shared<InnerLockFreeStack<T>>,
+}
+
+impl<T> LockFreeStack<T> {
+    pub fn new() -> Self {
+        Self { inner: Shared::new(InnerLockFreeStack::new()) }
+    }
+
+    /// Creates a stack with an elimination array, see the type-level docs.
+    pub fn with_elimination() -> Self {
+        Self { inner: Shared::new(InnerLockFreeStack::with_elimination()) }
+    }
+
+    pub fn push(&self, val: T) {
+        self.inner.push(val);
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        self.inner.pop()
+    }
+}
+
+impl<T> Default for LockFreeStack<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Clone for LockFreeStack<T> {
+    fn clone(&self) -> Self {
+        LockFreeStack { inner: self.inner.clone() }
+    }
+}
+
+unsafe impl<T> Send for LockFreeStack<T> where T: Send {}
+unsafe impl<T> Sync for LockFreeStack<T> where T: Send {}
+
+
+#[cfg(all(test, not(feature = "loom")))]
+mod test {
+    use super::*;
+    use crate::hazard::SCAN_THRESHOLD;
+    use std::collections::HashSet;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    #[test]
+    fn test_lock_free_stack_single_threaded() {
+        let stack = LockFreeStack::new();
+        for i in 0..100 {
+            stack.push(i);
+        }
+        for i in (0..100).rev() {
+            assert_eq!(stack.pop(), Some(i));
+        }
+        assert_eq!(stack.pop(), None);
+    }
+
+    #[test]
+    fn test_lock_free_stack_hazard_defeats_aba() {
+        let stack = LockFreeStack::new();
+        stack.push(1);
+        stack.push(2);
+
+        // Stall like a popper that protected the top node but has not swung the head yet
+        let stale = stack.inner.items.head.load(Relaxed);
+        let hazard = stack.inner.hazards.hazard();
+        assert_eq!(stack.inner.items.protect(&hazard, stale), stale);
+
+        // Pop and push again: the popped node cannot be freed, so the new top is a different node
+        assert_eq!(stack.pop(), Some(2));
+        stack.push(3);
+        let cur = stack.inner.items.head.load(Relaxed);
+        let (stale_node, _) = unpack::<LockFreeNode<i32>>(stale);
+        let (cur_node, _) = unpack::<LockFreeNode<i32>>(cur);
+        assert_ne!(stale_node, cur_node);
+        assert_eq!(stack.inner.hazards.scan(), 0);
+
+        // The stalled popper must not be able to swing the head, and its node is still there to read
+        assert!(stack.inner.items.head.compare_exchange(stale, 0, Acquire, Relaxed).is_err());
+        // Safety: The hazard keeps both nodes alive, and the stack is not touched concurrently
+        unsafe { assert_eq!((*stale_node).next.load(Relaxed), (*cur_node).next.load(Relaxed)); }
+        drop(hazard);
+        assert_eq!(stack.inner.hazards.scan(), 1);
+        assert_eq!(stack.pop(), Some(3));
+        assert_eq!(stack.pop(), Some(1));
+        assert_eq!(stack.pop(), None);
+    }
+
+    #[test]
+    fn test_tagged_head_rejects_reused_address() {
+        let list = TaggedList::<u32>::new();
+        let node = Box::into_raw(Box::new(LockFreeNode {
+            data: UnsafeCell::new(MaybeUninit::new(7)),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }));
+        let domain = HazardDomain::new();
+        let hazard = domain.hazard();
+        // Safety: The node stays ours throughout, it is only ever on this list
+        unsafe {
+            list.push_node(node);
+            let stale = list.head.load(Relaxed);
+            assert_eq!(list.pop_node(&hazard), node);
+            // The same address is back on top, as if its memory had been freed and reused
+            list.push_node(node);
+            let cur = list.head.load(Relaxed);
+            assert_eq!(unpack::<LockFreeNode<u32>>(cur).0, unpack::<LockFreeNode<u32>>(stale).0);
+            // A popper that stalled with the old head word must not be able to swing it
+            assert_ne!(cur, stale);
+            assert!(list.head.compare_exchange(stale, pack::<LockFreeNode<u32>>(ptr::null_mut(), 0), SeqCst, Relaxed).is_err());
+            assert_eq!(list.pop_node(&hazard), node);
+            drop(Box::from_raw(node));
+        }
+    }
+
+    #[test]
+    fn test_elimination_array_hands_node_to_popper() {
+        struct SyncArray(EliminationArray<u32>);
+        unsafe impl Sync for SyncArray {}
+
+        let array = &SyncArray(EliminationArray::new());
+        let node = Box::into_raw(Box::new(LockFreeNode {
+            data: UnsafeCell::new(MaybeUninit::new(7)),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }));
+
+        // Nobody is popping, so the offer is withdrawn and the slot left empty again
+        assert!(!unsafe { array.0.offer(node) });
+        assert!(array.0.slots.iter().all(|slot| unpack::<LockFreeNode<u32>>(slot.load(Relaxed)).0.is_null()));
+
+        let node_addr = node as usize;
+        let taken = thread::scope(|s| {
+            s.spawn(move || {
+                // Keep offering until a popper takes it
+                while !unsafe { array.0.offer(node_addr as *mut LockFreeNode<u32>) } {}
+            });
+            loop {
+                let taken = unsafe { array.0.take() };
+                if !taken.is_null() {
+                    break taken;
+                }
+                thread::yield_now();
+            }
+        });
+        assert_eq!(taken, node);
+        // Safety: The node was taken by us and holds the value it was created with
+        unsafe {
+            assert_eq!((*(*taken).data.get()).assume_init_read(), 7);
+            drop(Box::from_raw(taken));
+        }
+    }
+
+    #[test]
+    fn test_lock_free_stack_drops_remaining_elements() {
+        let val = Arc::new(());
+        let stack = LockFreeStack::new();
+        for _ in 0..10 {
+            stack.push(val.clone());
+        }
+        drop(stack.pop());
+        assert_eq!(Arc::strong_count(&val), 10);
+        drop(stack);
+        assert_eq!(Arc::strong_count(&val), 1);
+    }
+
+    #[test]
+    fn test_lock_free_stack_frees_popped_nodes_under_contention() {
+        // Boxed values give ASAN and Miri an allocation to catch a use after free or a double free
+        // on; `cargo +nightly miri test lock_free` runs a scaled down version
+        let (threads, rounds) = if cfg!(miri) { (3, 50) } else { (4, 20_000) };
+        for stack in [LockFreeStack::new(), LockFreeStack::with_elimination()] {
+            let mut seen: Vec<_> = thread::scope(|s| {
+                let jhs: Vec<_> = (0..threads).map(|i| {
+                    let stack = &stack;
+                    s.spawn(move || {
+                        let mut popped = vec![];
+                        for j in 0..rounds {
+                            stack.push(Box::new(i * rounds + j));
+                            popped.extend(stack.pop().map(|val| *val));
+                        }
+                        popped
+                    })
+                }).collect();
+                jhs.into_iter().flat_map(|jh| jh.join().expect("thread panicked")).collect()
+            });
+            // Popped nodes were freed along the way instead of piling up
+            assert!(stack.inner.hazards.retired_len() < SCAN_THRESHOLD + threads);
+
+            while let Some(val) = stack.pop() {
+                seen.push(*val);
+            }
+            seen.sort();
+            assert_eq!(seen, (0..threads * rounds).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_lock_free_stack_multi_producer_multi_consumer() {
+        mpmc_stress(LockFreeStack::new());
+    }
+
+    #[test]
+    fn test_lock_free_stack_elimination_multi_producer_multi_consumer() {
+        mpmc_stress(LockFreeStack::with_elimination());
+    }
+
+    fn mpmc_stress(stack: LockFreeStack<usize>) {
+        const PRODUCERS: usize = 4;
+        const PER_PRODUCER: usize = 10_000;
+        let popped = Arc::new(AtomicUsize::new(0));
+
+        let mut producer_jhs = vec![];
+        for i in 0..PRODUCERS {
+            let stack = stack.clone();
+            producer_jhs.push(thread::spawn(move || {
+                for j in 0..PER_PRODUCER {
+                    stack.push(i * PER_PRODUCER + j);
+                }
+            }));
+        }
+
+        let mut consumer_jhs = vec![];
+        for _ in 0..4 {
+            let stack = stack.clone();
+            let popped = popped.clone();
+            consumer_jhs.push(thread::spawn(move || {
+                let mut seen = vec![];
+                while popped.load(Relaxed) < PRODUCERS * PER_PRODUCER {
+                    if let Some(val) = stack.pop() {
+                        popped.fetch_add(1, Relaxed);
+                        seen.push(val);
+                    } else {
+                        thread::yield_now();
+                    }
+                }
+                seen
+            }));
+        }
+
+        for jh in producer_jhs {
+            jh.join().expect("producer panicked");
+        }
+        let mut all = HashSet::new();
+        for jh in consumer_jhs {
+            for val in jh.join().expect("consumer panicked") {
+                assert!(all.insert(val), "{val} was popped twice");
+            }
+        }
+        assert_eq!(all.len(), PRODUCERS * PER_PRODUCER);
+        assert_eq!(stack.pop(), None);
+    }
+}
+
+#[cfg(all(test, feature = "loom"))]
+mod loom_test {
+    use super::*;
+    use loom::thread;
+
+    #[test]
+    fn concurrent_push_pop_reclaims_nodes() {
+        loom::model(|| {
+            let stack = LockFreeStack::new();
+            stack.push(0);
+            let jhs: Vec<_> = (1..3).map(|i| {
+                let stack = stack.clone();
+                thread::spawn(move || {
+                    let popped = stack.pop();
+                    stack.push(i);
+                    popped
+                })
+            }).collect();
+
+            let mut popped: Vec<_> = jhs.into_iter().filter_map(|jh| jh.join().unwrap()).collect();
+            while let Some(val) = stack.pop() {
+                popped.push(val);
+            }
+            popped.sort();
+            assert_eq!(popped, vec![0, 1, 2]);
+        });
+    }
+}
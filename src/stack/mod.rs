@@ -1,8 +1,22 @@
-use std::ptr;
-use std::sync::atomic::{AtomicUsize, AtomicU32, AtomicPtr};
-use std::sync::atomic::Ordering::{Relaxed, Release, Acquire};
-use std::sync::atomic::fence;
+use core::cell::UnsafeCell;
+use core::ptr;
+use core::fmt;
+use core::marker::PhantomData;
+use alloc::{boxed::Box, sync::Arc, vec, vec::Vec};
+use crate::sync::{seq_cst_fence, wait, wake_all, wake_one, AtomicBool, AtomicU32, AtomicUsize};
+use crate::sync::Ordering::{SeqCst, Relaxed};
+use crate::channel::Sender;
 use crate::semaphore::Semaphore;
+use crate::shared::Shared;
+
+mod bounded;
+mod latest;
+mod lock_free;
+#[cfg(feature = "serde")]
+mod serde_impls;
+pub use bounded::BoundedStack;
+pub use latest::LatestCell;
+pub use lock_free::LockFreeStack;
 
 
 struct StackNode<T> {
@@ -11,152 +25,1881 @@ struct StackNode<T> {
 }
 
 impl<T> StackNode<T> {
+    fn init_with(val: T) -> Self {
+        Self { data: Some(val), next: ptr::null_mut::<StackNode<T>>() }
+    }
+}
+
+impl<T> Default for StackNode<T>
+where T: Default
+{
+    fn default() -> Self {
+        Self {
+            data: Some(T::default()),
+            next: ptr::null_mut::<StackNode<T>>(),
+        }
+    }
+}
+
+/// Error returned by `Stack::pop_blocking` once the stack is closed and empty.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Closed;
+
+impl fmt::Display for Closed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "stack is closed and empty")
+    }
+}
+
+impl core::error::Error for Closed {}
+
+/// How `Stack::push_batch_notify` wakes threads blocked waiting for pushes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatchWake {
+    /// A single `wake_all` for the whole batch, like `push_all`.
+    All,
+    /// One `wake_one` per element, so no more consumers wake than there are elements for. Falls
+    /// back to a single `wake_all` when at least as many threads are waiting as there are
+    /// elements, or when one of them is in `pop_exactly`, which may wake and still be short.
+    OnePerItem,
+}
+
+/// What a stack observer registered with `Stack::on_event` is told about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackEvent {
+    Pushed,
+    Popped,
+    /// A `pop` found the stack empty.
+    PopEmpty,
+}
+
+/// Result of `Stack::try_pop`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TryPop<T> {
+    Empty,
+    /// Another thread held the stack's lock, so nothing was attempted.
+    Contended,
+    Value(T),
+}
+
+type Observer = Arc<dyn Fn(StackEvent) + Send + Sync>;
+
+// Tells `observer`, if there is one, about `n` occurrences of `event`. Callers clone the observer
+// under the lock and call this after releasing it, so the observer may use the stack itself
+fn emit(observer: Option<Observer>, event: StackEvent, n: usize) {
+    if let Some(observer) = observer {
+        for _ in 0..n {
+            observer(event);
+        }
+    }
+}
+
+// How many emptied nodes a stack keeps around for reuse. Beyond this popped nodes are freed, so a
+// burst of pushes does not pin its memory forever
+const MAX_FREE_NODES: usize = 64;
+
+// Everything `sem` guards. Only ever touched with it held, or through `&mut InnerStack`
+struct Chain<T> {
+    head: *mut StackNode<T>,
+    // Emptied nodes that `push` reuses before allocating, and how many there are
+    free: *mut StackNode<T>,
+    free_len: usize,
+    observer: Option<Observer>,
+}
+
+impl<T> Chain<T> {
+    // Takes a node off the free list, or allocates one if it is empty
+    unsafe fn alloc_node(&mut self, val: T) -> *mut StackNode<T> {
+        if self.free.is_null() {
+            return Box::into_raw(Box::new(StackNode::init_with(val)));
+        }
+        let node = self.free;
+        self.free = (*node).next;
+        self.free_len -= 1;
+        (*node).data = Some(val);
+        (*node).next = ptr::null_mut::<StackNode<T>>();
+        node
+    }
+
+    // Takes the data out of a node that has been unlinked from the chain, and keeps the node for
+    // reuse if the free list has room
+    unsafe fn recycle_node(&mut self, node: *mut StackNode<T>) -> Option<T> {
+        let data = (*node).data.take();
+        if self.free_len < MAX_FREE_NODES {
+            (*node).next = self.free;
+            self.free = node;
+            self.free_len += 1;
+        } else {
+            drop(Box::from_raw(node));
+        }
+        data
+    }
+}
+
+struct InnerStack<T> {
+    // Guarded by `sem`
+    chain: UnsafeCell<Chain<T>>,
+    sem: Semaphore,
+    // Number of nodes in the chain. Only changed with `sem` held, so it always matches the chain
+    // at the moment the lock is released, but may be read without it
+    size: AtomicUsize,
+    // Wrapping count of pushes, also bumped once by `close`. Blocking pops park on it
+    push_seq: AtomicU32,
+    // Number of threads parked waiting for a push, so `push` only wakes when someone is waiting
+    push_waiters: AtomicU32,
+    // How many of `push_waiters` are in `pop_exactly`. Those may wake and still be short, so while
+    // there are any a wake must reach everyone
+    exact_waiters: AtomicU32,
+    closed: AtomicBool,
+    #[cfg(test)]
+    wakes: AtomicUsize,
+}
+
+impl<T>  InnerStack<T> {
     fn new() -> Self {
-        Self { data: None, next: ptr::null_mut::<StackNode<T>>() }
+        let chain = Chain {
+            head: ptr::null_mut::<StackNode<T>>(),
+            free: ptr::null_mut::<StackNode<T>>(),
+            free_len: 0,
+            observer: None,
+        };
+        let sem = Semaphore::init_with(1, 1);
+        Self {
+            chain: UnsafeCell::new(chain),
+            sem,
+            size: AtomicUsize::new(0),
+            push_seq: AtomicU32::new(0),
+            push_waiters: AtomicU32::new(0),
+            exact_waiters: AtomicU32::new(0),
+            closed: AtomicBool::new(false),
+            #[cfg(test)]
+            wakes: AtomicUsize::new(0),
+        }
+    }
+
+    // Must be called with `sem` held, and the borrow must end before it is released
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn chain(&self) -> &mut Chain<T> {
+        &mut *self.chain.get()
+    }
+
+    // Tells threads in `pop_blocking` or `pop_exactly` that `pushed` elements arrived. Every path
+    // that adds elements calls this after releasing the lock
+    fn notify_pushed(&self, pushed: usize, wake: BatchWake) {
+        // SeqCst pairs with the blocking pops: either they see our push, or we see them waiting
+        self.push_seq.fetch_add(pushed as u32, SeqCst);
+        seq_cst_fence();
+        let waiting = self.push_waiters.load(SeqCst);
+        if waiting == 0 {
+            return;
+        }
+        if wake == BatchWake::All || pushed >= waiting as usize || self.exact_waiters.load(SeqCst) > 0 {
+            #[cfg(test)]
+            self.wakes.fetch_add(1, Relaxed);
+            wake_all(&self.push_seq);
+            return;
+        }
+        for _ in 0..pushed {
+            #[cfg(test)]
+            self.wakes.fetch_add(1, Relaxed);
+            wake_one(&self.push_seq);
+        }
+    }
+
+    fn push(&self, val: T) {
+        self.sem.wait();
+        // Safety: We hold the semaphore, so only this thread has access to the free list, `neo` and
+        // the head
+        let observer = unsafe {
+            let chain = self.chain();
+            let neo = chain.alloc_node(val);
+            (*neo).next = chain.head;
+            chain.head = neo;
+            chain.observer.clone()
+        };
+        self.size.fetch_add(1, Relaxed);
+        self.sem.signal();
+        self.notify_pushed(1, BatchWake::All);
+        emit(observer, StackEvent::Pushed, 1);
+    }
+
+    fn push_all<I: IntoIterator<Item = T>>(&self, iter: I, wake: BatchWake) {
+        // Build the chain before taking the lock, each new node on top of the previous one
+        let mut top = ptr::null_mut::<StackNode<T>>();
+        let mut bottom = ptr::null_mut::<StackNode<T>>();
+        let mut len = 0;
+        for val in iter {
+            let node = Box::into_raw(Box::new(StackNode::init_with(val)));
+            // Safety: Only this thread knows about the chain so far
+            unsafe { (*node).next = top; }
+            if bottom.is_null() {
+                bottom = node;
+            }
+            top = node;
+            len += 1;
+        }
+        if len == 0 {
+            return;
+        }
+
+        self.sem.wait();
+        // Safety: We hold the semaphore, so only this thread has access to the head, and the new
+        // nodes are still ours alone
+        let observer = unsafe {
+            let chain = self.chain();
+            (*bottom).next = chain.head;
+            chain.head = top;
+            chain.observer.clone()
+        };
+        self.size.fetch_add(len, Relaxed);
+        self.sem.signal();
+        // Counting every element, so `pop_exactly` sees them all
+        self.notify_pushed(len, wake);
+        emit(observer, StackEvent::Pushed, len);
+    }
+
+    fn peek(&self) -> Option<T>
+    where T: Clone
+    {
+        self.sem.wait();
+        // Safety: We hold the semaphore, so the head cannot be unlinked or freed while we clone it
+        let res = unsafe { self.chain().head.as_ref().and_then(|head| head.data.clone()) };
+        self.sem.signal();
+        res
+    }
+
+    fn pop_exactly(&self, n: usize) -> Vec<T> {
+        let mut popped = Vec::with_capacity(n);
+        self.exact_waiters.fetch_add(1, SeqCst);
+        self.push_waiters.fetch_add(1, SeqCst);
+        let start = self.push_seq.load(SeqCst);
+        while popped.len() < n {
+            let seq = self.push_seq.load(SeqCst);
+            // Once closed, settle for whatever is left
+            let closed = self.closed.load(SeqCst);
+            if closed || seq.wrapping_sub(start) as usize >= n {
+                self.sem.wait();
+                let before = popped.len();
+                // Safety: We hold the semaphore, so only this thread has access to the chain
+                let observer = unsafe {
+                    let chain = self.chain();
+                    while popped.len() < n && !chain.head.is_null() {
+                        let prev = chain.head;
+                        chain.head = (*prev).next;
+                        self.size.fetch_sub(1, Relaxed);
+                        popped.extend(chain.recycle_node(prev));
+                    }
+                    chain.observer.clone()
+                };
+                self.sem.signal();
+                emit(observer, StackEvent::Popped, popped.len() - before);
+                if closed || popped.len() == n {
+                    break;
+                }
+            }
+            // Other consumers may have taken some, keep going as more are pushed
+            wait(&self.push_seq, seq);
+        }
+        self.push_waiters.fetch_sub(1, SeqCst);
+        self.exact_waiters.fetch_sub(1, SeqCst);
+        popped
+    }
+
+    fn pop_blocking(&self) -> Result<T, Closed> {
+        self.push_waiters.fetch_add(1, SeqCst);
+        let res = loop {
+            let seq = self.push_seq.load(SeqCst);
+            if let Some(val) = self.pop() {
+                break Ok(val);
+            }
+            if self.closed.load(SeqCst) {
+                // Pushes made before `close` are visible now, so one more pop settles it
+                break self.pop().ok_or(Closed);
+            }
+            wait(&self.push_seq, seq);
+        };
+        self.push_waiters.fetch_sub(1, SeqCst);
+        res
+    }
+
+    fn close(&self) {
+        self.closed.store(true, SeqCst);
+        // Change the futex word so that waiters which already loaded it do not sleep through this
+        self.push_seq.fetch_add(1, SeqCst);
+        wake_all(&self.push_seq);
+    }
+
+    fn pop(&self) -> Option<T> {
+        self.sem.wait();
+        self.pop_locked()
+    }
+
+    fn try_pop(&self) -> TryPop<T> {
+        if !self.sem.try_wait() {
+            return TryPop::Contended;
+        }
+        match self.pop_locked() {
+            Some(val) => TryPop::Value(val),
+            None => TryPop::Empty,
+        }
+    }
+
+    // Pops with `sem` already held by the caller, and releases it
+    fn pop_locked(&self) -> Option<T> {
+        // Safety: The caller holds the semaphore, so only this thread has access to the chain
+        let chain = unsafe { self.chain() };
+        // The observer is called after the lock is released, so it may use the stack itself
+        let observer = chain.observer.clone();
+        let res = if chain.head.is_null() {
+            self.sem.signal();
+            None
+        } else {
+            unsafe {
+                let prev = chain.head;
+                chain.head = (*prev).next;
+                self.size.fetch_sub(1, Relaxed);
+                // `prev` was unlinked while we hold the semaphore, and every other access to a node
+                // also happens under it, so no other thread can still be looking at `prev`
+                let data = chain.recycle_node(prev);
+                self.sem.signal();
+                data
+            }
+        };
+        emit(observer, if res.is_some() { StackEvent::Popped } else { StackEvent::PopEmpty }, 1);
+        res
+    }
+
+    fn on_event(&self, observer: Observer) {
+        self.sem.wait();
+        // Safety: We hold the semaphore, so only this thread has access to the chain
+        unsafe { self.chain().observer = Some(observer); }
+        self.sem.signal();
+    }
+
+    fn pop_if<F: FnOnce(&T) -> bool>(&self, f: F) -> Option<T> {
+        self.sem.wait();
+        // Safety: We hold the semaphore, so only this thread has access to the chain
+        let chain = unsafe { self.chain() };
+        let observer = chain.observer.clone();
+        if chain.head.is_null() {
+            self.sem.signal();
+            emit(observer, StackEvent::PopEmpty, 1);
+            return None;
+        }
+        // Safety: The head is not null, and we still hold the semaphore
+        unsafe {
+            if !(*chain.head).data.as_ref().is_some_and(f) {
+                self.sem.signal();
+                return None;
+            }
+            let prev = chain.head;
+            chain.head = (*prev).next;
+            self.size.fetch_sub(1, Relaxed);
+            // `prev` was unlinked under the semaphore, so no other thread can reach it
+            let data = chain.recycle_node(prev);
+            self.sem.signal();
+            emit(observer, StackEvent::Popped, 1);
+            data
+        }
+    }
+
+    fn replace_top(&self, val: T) -> Option<T> {
+        self.sem.wait();
+        // Safety: We hold the semaphore, so only this thread has access to the chain
+        let (res, pushed, observer) = unsafe {
+            let chain = self.chain();
+            if chain.head.is_null() {
+                chain.head = chain.alloc_node(val);
+                self.size.fetch_add(1, Relaxed);
+                (None, true, chain.observer.clone())
+            } else {
+                ((*chain.head).data.replace(val), false, chain.observer.clone())
+            }
+        };
+        self.sem.signal();
+        // Only a push onto an empty stack adds an element, a swap leaves the count alone
+        if pushed {
+            self.notify_pushed(1, BatchWake::All);
+        } else {
+            emit(observer.clone(), StackEvent::Popped, 1);
+        }
+        emit(observer, StackEvent::Pushed, 1);
+        res
+    }
+
+    fn pop_n(&self, n: usize) -> Vec<T> {
+        self.sem.wait();
+        // Safety: We hold the semaphore, so only this thread has access to the chain
+        let (first, taken, observer) = unsafe {
+            let chain = self.chain();
+            let first = chain.head;
+            let mut taken = 0;
+            let mut last = ptr::null_mut::<StackNode<T>>();
+            while taken < n && !chain.head.is_null() {
+                last = chain.head;
+                chain.head = (*last).next;
+                taken += 1;
+            }
+            if !last.is_null() {
+                (*last).next = ptr::null_mut::<StackNode<T>>();
+            }
+            (first, taken, chain.observer.clone())
+        };
+        self.size.fetch_sub(taken, Relaxed);
+        self.sem.signal();
+
+        // Take the values out and free the unlinked nodes outside the critical section
+        let mut popped = Vec::with_capacity(taken);
+        let mut cur = if taken == 0 { ptr::null_mut() } else { first };
+        // Safety: These nodes are no longer reachable from the head
+        unsafe {
+            while !cur.is_null() {
+                let node = Box::from_raw(cur);
+                cur = node.next;
+                popped.extend(node.data);
+            }
+        }
+        emit(observer, StackEvent::Popped, popped.len());
+        popped
+    }
+
+    fn drain_while<F: FnMut(&T) -> bool>(&self, mut f: F) -> Vec<T> {
+        let mut drained = vec![];
+        self.sem.wait();
+        // Safety: We hold the semaphore, so only this thread has access to the chain
+        let (first, last, observer) = unsafe {
+            let chain = self.chain();
+            let first = chain.head;
+            let mut last = ptr::null_mut::<StackNode<T>>();
+            while !chain.head.is_null() && (*chain.head).data.as_ref().is_some_and(&mut f) {
+                last = chain.head;
+                chain.head = (*last).next;
+                self.size.fetch_sub(1, Relaxed);
+                drained.extend((*last).data.take());
+            }
+            if !last.is_null() {
+                (*last).next = ptr::null_mut::<StackNode<T>>();
+            }
+            (first, last, chain.observer.clone())
+        };
+        self.sem.signal();
+        emit(observer, StackEvent::Popped, drained.len());
+
+        // Free the unlinked nodes outside the critical section
+        let mut cur = if last.is_null() { ptr::null_mut() } else { first };
+        // Safety: These nodes are no longer reachable from the head
+        unsafe {
+            while !cur.is_null() {
+                let node = Box::from_raw(cur);
+                cur = node.next;
+            }
+        }
+        drained
+    }
+
+    fn clear(&self) {
+        self.sem.wait();
+        // Safety: We hold the semaphore, so only this thread has access to the chain
+        let (mut cur, observer) = unsafe {
+            let chain = self.chain();
+            (core::mem::replace(&mut chain.head, ptr::null_mut::<StackNode<T>>()), chain.observer.clone())
+        };
+        let cleared = self.size.swap(0, Relaxed);
+        self.sem.signal();
+        emit(observer, StackEvent::Popped, cleared);
+
+        // Drop the elements outside the critical section, their destructors may take a while
+        // Safety: These nodes are no longer reachable from the head
+        unsafe {
+            while !cur.is_null() {
+                let node = Box::from_raw(cur);
+                cur = node.next;
+            }
+        }
+    }
+
+    // `other` must be a different stack
+    fn append(&self, other: &InnerStack<T>) {
+        // Always lock the stack at the lower address first, so two threads appending the same pair
+        // of stacks in opposite directions cannot deadlock
+        let self_first = (self as *const InnerStack<T>) < (other as *const InnerStack<T>);
+        if self_first {
+            self.sem.wait();
+            other.sem.wait();
+        } else {
+            other.sem.wait();
+            self.sem.wait();
+        }
+
+        // Safety: We hold both semaphores, so no other thread can access either chain, and the two
+        // chains are distinct
+        let (moved, observer, other_observer) = unsafe {
+            let (chain, other_chain) = (self.chain(), other.chain());
+            let observers = (chain.observer.clone(), other_chain.observer.clone());
+            let moved = if other_chain.head.is_null() {
+                0
+            } else {
+                let mut tail = other_chain.head;
+                while !(*tail).next.is_null() {
+                    tail = (*tail).next;
+                }
+                (*tail).next = chain.head;
+                chain.head = other_chain.head;
+                other_chain.head = ptr::null_mut::<StackNode<T>>();
+                let moved = other.size.swap(0, Relaxed);
+                self.size.fetch_add(moved, Relaxed);
+                moved
+            };
+            (moved, observers.0, observers.1)
+        };
+
+        self.sem.signal();
+        other.sem.signal();
+        if moved > 0 {
+            self.notify_pushed(moved, BatchWake::All);
+        }
+        // Each side's observer hears about its own stack
+        emit(other_observer, StackEvent::Popped, moved);
+        emit(observer, StackEvent::Pushed, moved);
+    }
+
+    // Detaches and returns the whole chain and its length, but only if it holds at least `min` nodes
+    fn take_all_if(&self, min: usize) -> Option<(*mut StackNode<T>, usize)> {
+        self.sem.wait();
+        let len = self.size.load(Relaxed);
+        // Safety: We hold the semaphore, so only this thread has access to the chain
+        let chain = unsafe { self.chain() };
+        let observer = chain.observer.clone();
+        let taken = (len >= min).then(|| {
+            self.size.store(0, Relaxed);
+            (core::mem::replace(&mut chain.head, ptr::null_mut::<StackNode<T>>()), len)
+        });
+        self.sem.signal();
+        emit(observer, StackEvent::Popped, taken.map_or(0, |(_, len)| len));
+        taken
+    }
+
+    // Detaches and returns everything below the top `n` nodes, along with how many nodes that is
+    fn split_off(&self, n: usize) -> (*mut StackNode<T>, usize) {
+        self.sem.wait();
+        let len = self.size.load(Relaxed);
+        let rest_len = len.saturating_sub(n);
+        self.size.store(len - rest_len, Relaxed);
+        // Safety: We hold the semaphore, so no other thread can access the chain
+        let (rest, observer) = unsafe {
+            let chain = self.chain();
+            let rest = if n == 0 {
+                core::mem::replace(&mut chain.head, ptr::null_mut::<StackNode<T>>())
+            } else {
+                let mut last = chain.head;
+                for _ in 1..n {
+                    if last.is_null() {
+                        break;
+                    }
+                    last = (*last).next;
+                }
+                if last.is_null() {
+                    ptr::null_mut::<StackNode<T>>()
+                } else {
+                    let rest = (*last).next;
+                    (*last).next = ptr::null_mut::<StackNode<T>>();
+                    rest
+                }
+            };
+            (rest, chain.observer.clone())
+        };
+        self.sem.signal();
+        emit(observer, StackEvent::Popped, rest_len);
+        (rest, rest_len)
+    }
+
+    fn map<U, F: FnMut(&T) -> U>(&self, mut f: F) -> Vec<U> {
+        self.sem.wait();
+        let mut mapped = vec![];
+        // Safety: We hold the semaphore, so no other thread can unlink or free nodes while we walk
+        unsafe {
+            let mut cur = self.chain().head;
+            while !cur.is_null() {
+                if let Some(data) = (*cur).data.as_ref() {
+                    mapped.push(f(data));
+                }
+                cur = (*cur).next;
+            }
+        }
+        self.sem.signal();
+        mapped
+    }
+
+    fn count_matching<F: FnMut(&T) -> bool>(&self, mut f: F) -> usize {
+        self.sem.wait();
+        let mut count = 0;
+        // Safety: We hold the semaphore, so no other thread can unlink or free nodes while we walk
+        unsafe {
+            let mut cur = self.chain().head;
+            while !cur.is_null() {
+                if (*cur).data.as_ref().is_some_and(&mut f) {
+                    count += 1;
+                }
+                cur = (*cur).next;
+            }
+        }
+        self.sem.signal();
+        count
+    }
+
+    fn len(&self) -> usize {
+        self.size.load(Relaxed)
+    }
+
+    fn lock_iter(&self) -> StackLockGuard<'_, T> {
+        self.sem.wait();
+        StackLockGuard { stack: self }
+    }
+
+    fn try_lock_iter(&self) -> Option<StackLockGuard<'_, T>> {
+        // Only build the guard once we hold the lock, since dropping it releases the lock
+        if self.sem.try_wait() {
+            Some(StackLockGuard { stack: self })
+        } else {
+            None
+        }
+    }
+}
+
+impl<T> Drop for InnerStack<T> {
+    fn drop(&mut self) {
+        let chain = self.chain.get_mut();
+        // Safety: There are no threads that have access to the chain or the free list
+        unsafe {
+            for mut cur in [chain.head, chain.free] {
+                while !cur.is_null() {
+                    let node = Box::from_raw(cur);
+                    cur = node.next;
+                }
+            }
+        }
+    }
+}
+
+/// Holds a stack's lock for as long as it lives, so the chain cannot change underneath it. See
+/// `Stack::lock_iter`.
+pub struct StackLockGuard<'a, T> {
+    stack: &'a InnerStack<T>,
+}
+
+impl<T> StackLockGuard<'_, T> {
+    /// Iterates over references to the elements, top to bottom.
+    pub fn iter(&self) -> StackLockGuardIter<'_, T> {
+        // Safety: The guard holds `sem` for as long as the iterator borrows it
+        StackLockGuardIter { cur: unsafe { self.stack.chain().head }, phantom: PhantomData }
+    }
+}
+
+impl<'g, T> IntoIterator for &'g StackLockGuard<'_, T> {
+    type Item = &'g T;
+    type IntoIter = StackLockGuardIter<'g, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<T> Drop for StackLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.stack.sem.signal();
+    }
+}
+
+/// Iterator over references to a stack's elements, borrowed from a `StackLockGuard`.
+pub struct StackLockGuardIter<'g, T> {
+    cur: *mut StackNode<T>,
+    // The references we hand out borrow the guard, so they cannot outlive the lock
+    phantom: PhantomData<&'g T>,
+}
+
+impl<'g, T> Iterator for StackLockGuardIter<'g, T> {
+    type Item = &'g T;
+
+    fn next(&mut self) -> Option<&'g T> {
+        // Safety: The guard we borrow from holds the semaphore for at least `'g`, so no node
+        // reachable from the head can be unlinked or freed while the returned references are alive
+        unsafe {
+            while !self.cur.is_null() {
+                let node = self.cur;
+                self.cur = (*node).next;
+                if let Some(data) = (*node).data.as_ref() {
+                    return Some(data);
+                }
+            }
+        }
+        None
+    }
+}
+
+
+/// A concurrent LIFO stack. Handles can be cloned and sent to other threads, or, since the stack
+/// is `Sync`, borrowed by scoped threads without cloning at all:
+///
+/// ```
+/// use concurrent_collections::stack::Stack;
+/// use std::thread;
+///
+/// let stack = Stack::new();
+/// thread::scope(|s| {
+///     for i in 0..3 {
+///         let stack = &stack;
+///         s.spawn(move || stack.push(i));
+///     }
+/// });
+/// assert_eq!(stack.count_matching(|_| true), 3);
+/// ```
+pub struct Stack<T> {
+    inner: Shared<InnerStack<T>>,
+}
+
+impl<T> Stack<T> {
+    pub fn new() -> Self {
+        Self { inner: Shared::new(InnerStack::new()) }
+    }
+
+    pub fn push(&self, val: T) {
+        self.inner.push(val);
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        self.inner.pop()
+    }
+
+    /// Pushes every element of `iter` in order, so the last one ends up on top, under a single lock
+    /// acquisition. The nodes are linked up before the lock is taken and spliced in with one head
+    /// update, so no other push or pop lands in between.
+    pub fn push_all<I: IntoIterator<Item = T>>(&self, iter: I) {
+        self.inner.push_all(iter, BatchWake::All);
+    }
+
+    /// Like `push_all`, but chooses how threads blocked in `pop_blocking` or `pop_exactly` are
+    /// woken for the batch, see `BatchWake`. Either way the batch gets its wakes once, after it has
+    /// been pushed, rather than one round per element.
+    pub fn push_batch_notify<I: IntoIterator<Item = T>>(&self, iter: I, wake: BatchWake) {
+        self.inner.push_all(iter, wake);
+    }
+
+    /// Pushes clones of `vals` in order, see `push_all`.
+    pub fn push_slice(&self, vals: &[T])
+    where T: Clone
+    {
+        self.push_all(vals.iter().cloned());
+    }
+
+    /// Returns a clone of the top element without removing it. Other threads may pop or push right
+    /// after, so by the time the caller looks at it the value may no longer be on top.
+    pub fn peek(&self) -> Option<T>
+    where T: Clone
+    {
+        self.inner.peek()
+    }
+
+    /// Pops the top element unless another thread holds the stack's lock, in which case it returns
+    /// `TryPop::Contended` straight away instead of waiting for it.
+    pub fn try_pop(&self) -> TryPop<T> {
+        self.inner.try_pop()
+    }
+
+    /// Blocks until at least `n` elements have been pushed since the call, then pops `n` elements
+    /// and returns them in pop order. If other consumers get to some of them first, this keeps
+    /// waiting for further pushes until it has `n`, or returns fewer once the stack is closed.
+    pub fn pop_exactly(&self, n: usize) -> Vec<T> {
+        self.inner.pop_exactly(n)
+    }
+
+    /// Pops the top element, parking until one is pushed if the stack is empty. Returns
+    /// `Err(Closed)` once the stack has been closed and everything pushed before has been popped.
+    pub fn pop_blocking(&self) -> Result<T, Closed> {
+        self.inner.pop_blocking()
+    }
+
+    /// Marks the end of the stream and wakes every parked consumer. Elements already on the stack
+    /// can still be popped; after that `pop_blocking` returns `Err(Closed)`.
+    pub fn close(&self) {
+        self.inner.close();
+    }
+
+    /// Registers `f` to be told about every `push` and `pop`, replacing any previous observer. It
+    /// runs on the calling thread after the operation has released the stack's lock. Bulk
+    /// operations report once per element: whatever leaves the stack, drained, cleared, split off
+    /// or moved away by `append`, counts as `Popped`, and whatever arrives as `Pushed`.
+    /// `replace_top` on a non-empty stack reports both.
+    pub fn on_event<F: Fn(StackEvent) + Send + Sync + 'static>(&self, f: F) {
+        self.inner.on_event(Arc::new(f));
+    }
+
+    /// Pops the top element only if `f` returns true for it, checking and popping under one lock
+    /// acquisition. A rejected top is left in place.
+    pub fn pop_if<F: FnOnce(&T) -> bool>(&self, f: F) -> Option<T> {
+        self.inner.pop_if(f)
+    }
+
+    /// Swaps `val` in as the top element and returns the one it replaced. If the stack is empty
+    /// `val` is pushed and `None` is returned.
+    pub fn replace_top(&self, val: T) -> Option<T> {
+        self.inner.replace_top(val)
+    }
+
+    /// Pops up to `n` elements under a single lock acquisition and returns them in pop order. Fewer
+    /// come back if the stack runs out first. Unlike `pop_exactly` this never waits for pushes.
+    pub fn pop_n(&self, n: usize) -> Vec<T> {
+        self.inner.pop_n(n)
+    }
+
+    /// Pops elements off the top for as long as `f` returns true for the current top, under a
+    /// single lock acquisition. The first element `f` rejects, and everything below it, is left.
+    pub fn drain_while<F: FnMut(&T) -> bool>(&self, f: F) -> Vec<T> {
+        self.inner.drain_while(f)
+    }
+
+    /// Drops every element, leaving the stack empty and ready for reuse. The chain is detached in
+    /// one step and dropped after the lock is released, so other threads are only held up briefly.
+    pub fn clear(&self) {
+        self.inner.clear()
+    }
+
+    /// Returns an iterator that pops elements until it finds the stack empty, leaving the stack
+    /// itself usable afterwards. Each element is popped separately, so elements pushed by other
+    /// threads while draining are drained too, and a steady enough stream of pushes means the
+    /// iterator never ends. Use `drain_while(|_| true)` to take everything in one lock acquisition.
+    pub fn drain(&self) -> Drain<'_, T> {
+        Drain { stack: self }
+    }
+
+    /// Pops every element that is there right now, under a single lock acquisition, and sends
+    /// them into `tx` in pop order. Several stacks can feed the same channel this way.
+    pub fn drain_to(&self, tx: &Sender<T>) {
+        for val in self.pop_n(usize::MAX) {
+            tx.send(val);
+        }
+    }
+
+    /// Moves every element of `other` onto the top of `self`, preserving their order and leaving
+    /// `other` empty.
+    pub fn append(&self, other: &Stack<T>) {
+        if Shared::ptr_eq(&self.inner, &other.inner) {
+            return;
+        }
+        self.inner.append(&other.inner);
+    }
+
+    /// Detaches every element into a new stack, but only if there are at least `min` of them.
+    /// Otherwise returns `None` and leaves `self` untouched.
+    pub fn take_all_if(&self, min: usize) -> Option<Stack<T>> {
+        let (chain, len) = self.inner.take_all_if(min)?;
+        let taken = Stack::new();
+        // Safety: Nobody else can see `taken` yet
+        unsafe { taken.inner.chain().head = chain; }
+        taken.inner.size.store(len, Relaxed);
+        Some(taken)
+    }
+
+    /// Keeps the top `n` elements in `self` and moves everything below them, in the same order,
+    /// into the returned stack. The chain is cut at the boundary rather than copied.
+    pub fn split_off(&self, n: usize) -> Stack<T> {
+        let rest = Stack::new();
+        let (chain, len) = self.inner.split_off(n);
+        // Safety: Nobody else can see `rest` yet
+        unsafe { rest.inner.chain().head = chain; }
+        rest.inner.size.store(len, Relaxed);
+        rest
+    }
+
+    /// Builds a new stack by applying `f` to each element, top to bottom. The top of `self` maps
+    /// to the top of the returned stack.
+    pub fn map<U, F: FnMut(&T) -> U>(&self, f: F) -> Stack<U>
+    where T: Clone
+    {
+        let mut mapped = self.inner.map(f);
+        mapped.reverse();
+        Stack::from(mapped)
+    }
+
+    /// Clones every element into a `Vec`, top first, as one consistent point-in-time view that
+    /// leaves the stack as it was. The clones are made with the lock held, which blocks other
+    /// threads for as long as cloning takes; for large elements consider storing `Arc`s.
+    pub fn snapshot(&self) -> Vec<T>
+    where T: Clone
+    {
+        self.inner.map(T::clone)
+    }
+
+    pub fn count_matching<F: FnMut(&T) -> bool>(&self, f: F) -> usize {
+        self.inner.count_matching(f)
+    }
+
+    /// Returns the number of elements. Other threads may push or pop at any moment, so under
+    /// concurrency this is only a snapshot.
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The most elements the stack can hold, always `None` since a `Stack` is unbounded. See
+    /// `BoundedStack::capacity`.
+    pub fn capacity(&self) -> Option<usize> {
+        None
+    }
+
+    /// How many more elements fit, always `None` since a `Stack` is unbounded. See
+    /// `BoundedStack::remaining_capacity`.
+    pub fn remaining_capacity(&self) -> Option<usize> {
+        None
+    }
+
+    /// Locks the stack so its elements can be borrowed, top to bottom, through the guard's `iter`
+    /// without copying them. Any other operation on the stack blocks until the guard is dropped,
+    /// and the borrowed elements cannot outlive it:
+    ///
+    /// ```compile_fail
+    /// use concurrent_collections::stack::Stack;
+    ///
+    /// let stack = Stack::from(vec![1]);
+    /// let top = {
+    ///     let guard = stack.lock_iter();
+    ///     guard.iter().next()
+    /// };
+    /// stack.pop();
+    /// println!("{top:?}");
+    /// ```
+    pub fn lock_iter(&self) -> StackLockGuard<'_, T> {
+        self.inner.lock_iter()
+    }
+}
+
+impl<T> From<Vec<T>> for Stack<T> {
+    fn from(vec: Vec<T>) -> Self {
+        let stack = Stack::new();
+        stack.push_all(vec);
+        stack
+    }
+}
+
+/// Pushes the elements in the order the iterator yields them, so the last one ends up on top.
+impl<T> FromIterator<T> for Stack<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let mut stack = Stack::new();
+        stack.extend(iter);
+        stack
+    }
+}
+
+/// Pushes the elements in order, leaving the last one on top, in one step like `push_all`.
+impl<T> Extend<T> for Stack<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        self.push_all(iter);
+    }
+}
+
+/// Pops every element in LIFO order. Other clones of the stack can still push and pop while the
+/// iterator runs, so it only sees everything if this was the last handle.
+impl<T> IntoIterator for Stack<T> {
+    type Item = T;
+    type IntoIter = IntoIter<T>;
+
+    fn into_iter(self) -> IntoIter<T> {
+        IntoIter { stack: self }
+    }
+}
+
+/// Owning iterator returned by `Stack::into_iter`, popping one element per call to `next`.
+pub struct IntoIter<T> {
+    stack: Stack<T>,
+}
+
+impl<T> Iterator for IntoIter<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.stack.pop()
+    }
+}
+
+/// Iterator returned by `Stack::drain`.
+pub struct Drain<'a, T> {
+    stack: &'a Stack<T>,
+}
+
+impl<T> Iterator for Drain<'_, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.stack.pop()
+    }
+}
+
+// Prints only the length by default. The alternate form `{:#?}` lists the elements as well, unless
+// the lock is held, possibly by the very thread formatting, in which case it says so instead of
+// blocking.
+impl<T: fmt::Debug> fmt::Debug for Stack<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let alternate = f.alternate();
+        let mut debug = f.debug_struct("Stack");
+        debug.field("len", &self.len());
+        if alternate {
+            match self.inner.try_lock_iter() {
+                Some(guard) => debug.field("elements", &guard.iter().collect::<Vec<_>>()),
+                None => debug.field("elements", &format_args!("<locked>")),
+            };
+        }
+        debug.finish()
+    }
+}
+
+impl<T> Default for Stack<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Clone for Stack<T> {
+    fn clone(&self) -> Self {
+        Stack { inner: self.inner.clone() }
+    }
+}
+
+unsafe impl<T> Send for Stack<T> where T: Send {}
+unsafe impl<T> Sync for Stack<T> where T: Send {}
+
+
+#[cfg(all(test, not(feature = "loom")))]
+mod test {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_stack_default_is_empty() {
+        let stack: Stack<u32> = Default::default();
+        assert!(stack.is_empty());
+        stack.push(1);
+        assert_eq!(stack.pop(), Some(1));
+        assert_eq!(stack.pop(), None);
+    }
+
+    #[test]
+    fn test_stack_single_threaded() {
+        let stack = Stack::new();
+        for i in 0..100 {
+            stack.push(i);
+        }
+        for i in (0..100).rev() {
+            let Some(val) = stack.pop() else { panic!("stack should not be empty") };
+            println!("popped {val}");
+            assert_eq!(val, i);
+        }
+    }
+
+    #[test]
+    fn test_stack_len_tracks_push_and_pop() {
+        let stack = Stack::new();
+        assert!(stack.is_empty());
+        for i in 0..100 {
+            stack.push(i);
+            assert_eq!(stack.len(), i + 1);
+        }
+        for i in (0..100).rev() {
+            assert_eq!(stack.pop(), Some(i));
+            assert_eq!(stack.len(), i);
+        }
+        // Popping an empty stack leaves the count alone
+        assert_eq!(stack.pop(), None);
+        assert_eq!(stack.len(), 0);
+        assert!(stack.is_empty());
+    }
+
+    #[test]
+    fn test_unbounded_stack_has_no_capacity() {
+        let stack = Stack::new();
+        assert_eq!((stack.capacity(), stack.remaining_capacity()), (None, None));
+        stack.push_all(0..100);
+        assert_eq!((stack.capacity(), stack.remaining_capacity()), (None, None));
+        stack.clear();
+        assert_eq!((stack.capacity(), stack.remaining_capacity()), (None, None));
+    }
+
+    #[test]
+    fn test_stack_len_tracks_bulk_operations() {
+        let stack = Stack::from((0..10).collect::<Vec<_>>());
+        let rest = stack.split_off(4);
+        assert_eq!((stack.len(), rest.len()), (4, 6));
+
+        stack.append(&rest);
+        assert_eq!((stack.len(), rest.len()), (10, 0));
+
+        assert_eq!(stack.drain_while(|&val| val > 2).len(), 3);
+        assert_eq!(stack.len(), 7);
+        assert!(stack.pop_if(|_| false).is_none());
+        assert!(stack.pop_if(|_| true).is_some());
+        assert_eq!(stack.pop_exactly(0).len(), 0);
+        assert_eq!(stack.len(), 6);
+
+        let taken = stack.take_all_if(6).expect("stack holds enough elements");
+        assert_eq!((stack.len(), taken.len()), (0, 6));
+        assert_eq!(stack.replace_top(1), None);
+        assert_eq!(stack.replace_top(2), Some(1));
+        assert_eq!(stack.len(), 1);
+    }
+
+    #[test]
+    fn test_stack_try_pop_reports_contention() {
+        let stack = Stack::from(vec![1, 2]);
+        let (locked_tx, locked_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        thread::scope(|s| {
+            let stack = &stack;
+            s.spawn(move || {
+                let guard = stack.lock_iter();
+                locked_tx.send(()).unwrap();
+                release_rx.recv().unwrap();
+                drop(guard);
+            });
+            locked_rx.recv().unwrap();
+            // The lock is held elsewhere, so this must come back without blocking or popping
+            assert_eq!(stack.try_pop(), TryPop::Contended);
+            release_tx.send(()).unwrap();
+        });
+
+        assert_eq!(stack.try_pop(), TryPop::Value(2));
+        assert_eq!(stack.try_pop(), TryPop::Value(1));
+        assert_eq!(stack.try_pop(), TryPop::Empty);
     }
 
-    fn init_with(val: T) -> Self {
-        Self { data: Some(val), next: ptr::null_mut::<StackNode<T>>() }
+    #[test]
+    fn test_stack_into_iter_pops_in_lifo_order() {
+        let stack = Stack::new();
+        for i in 0..100 {
+            stack.push(i);
+        }
+        let collected: Vec<_> = stack.into_iter().collect();
+        assert_eq!(collected, (0..100).rev().collect::<Vec<_>>());
+
+        // A clone keeps the stack alive, and elements it pushes in the meantime are popped too
+        let stack = Stack::from(vec![1]);
+        let other = stack.clone();
+        let mut iter = stack.into_iter();
+        other.push(2);
+        assert_eq!(iter.next(), Some(2));
+        assert_eq!(iter.next(), Some(1));
+        assert_eq!(iter.next(), None);
+        assert!(other.is_empty());
     }
-}
 
-impl<T> Default for StackNode<T>
-where T: Default
-{
-    fn default() -> Self {
-        Self {
-            data: Some(T::default()),
-            next: ptr::null_mut::<StackNode<T>>(),
+    #[test]
+    fn test_stack_collect_and_extend() {
+        let mut stack: Stack<i32> = (0..10).collect();
+        assert_eq!(stack.len(), 10);
+        stack.extend([10, 11]);
+        assert_eq!(stack.lock_iter().iter().copied().collect::<Vec<_>>(), (0..12).rev().collect::<Vec<_>>());
+        for expected in (0..12).rev() {
+            assert_eq!(stack.pop(), Some(expected));
         }
+        assert_eq!(stack.pop(), None);
     }
-}
 
-struct InnerStack<T> {
-    head: *mut StackNode<T>,
-    sem: Semaphore,
-    ref_count: AtomicUsize,
-}
+    #[test]
+    fn test_stack_drain() {
+        let stack: Stack<_> = (0..50).collect();
+        let drained: Vec<_> = stack.drain().collect();
+        assert_eq!(drained, (0..50).rev().collect::<Vec<_>>());
+        assert!(stack.is_empty());
 
-impl<T>  InnerStack<T> {
-    fn new() -> Self {
-        let head = ptr::null_mut::<StackNode<T>>();
-        let sem = Semaphore::init_with(1, 1);
-        let ref_count = AtomicUsize::new(1);
-        Self { head, sem, ref_count }
+        // The stack is still usable afterwards
+        stack.push(1);
+        assert_eq!(stack.drain().collect::<Vec<_>>(), vec![1]);
     }
 
-    fn push(&mut self, val: T) {
-        let mut neo = Box::into_raw(Box::new(StackNode::init_with(val)));
-        self.sem.wait();
-        // Safety: Only this thread has access to neo at this point as well as `self.head`
-        unsafe {
-            (*neo).next = self.head;
-            self.head = neo;
+    #[test]
+    fn test_stacks_drain_to_one_channel() {
+        let (tx, rx) = crate::channel::channel();
+        let evens: Stack<_> = (0..100).step_by(2).collect();
+        let odds: Stack<_> = (1..100).step_by(2).collect();
+        thread::scope(|s| {
+            for stack in [&evens, &odds] {
+                let tx = tx.clone();
+                s.spawn(move || stack.drain_to(&tx));
+            }
+        });
+        drop(tx);
+        assert!(evens.is_empty() && odds.is_empty());
+
+        let mut received = vec![];
+        while let Ok(val) = rx.recv() {
+            received.push(val);
         }
-        self.sem.signal();
+        // Each stack's elements arrive in pop order
+        let from_evens: Vec<_> = received.iter().copied().filter(|val| val % 2 == 0).collect();
+        assert_eq!(from_evens, (0..100).step_by(2).rev().collect::<Vec<_>>());
+        received.sort_unstable();
+        assert_eq!(received, (0..100).collect::<Vec<_>>());
     }
 
-    fn pop(&mut self) -> Option<T> {
-        self.sem.wait();
-        // Safety: We know we are the only thread that has access to `self.head` at this point
-        let res = if self.head.is_null() {
-            self.sem.signal();
-            None
-        } else {
-            unsafe {
-                let prev = self.head;
-                let next = (*prev).next;
-                let data = (*prev).data.take();
-                self.head = next;
-                self.sem.signal();
-                ptr::drop_in_place(prev);
-                data
-            }
-        };
-        res
+    #[test]
+    fn test_stack_peek() {
+        let stack = Stack::new();
+        assert_eq!(stack.peek(), None);
+        stack.push(String::from("a"));
+        stack.push(String::from("b"));
+        assert_eq!(stack.peek().as_deref(), Some("b"));
+        assert_eq!(stack.len(), 2);
+        assert_eq!(stack.pop().as_deref(), Some("b"));
+        assert_eq!(stack.peek().as_deref(), Some("a"));
+        assert_eq!(stack.len(), 1);
     }
-}
 
-impl<T> Drop for InnerStack<T> {
-    fn drop(&mut self) {
-        assert_eq!(self.ref_count.load(Relaxed), 0);
-        let mut cur = self.head;
-        // Safety: There are no threads that have access to `self.head`
-        unsafe {
-            while !cur.is_null() {
-                let next = (*cur).next;
-                (*cur).next = ptr::null_mut::<StackNode<T>>();
-                ptr::drop_in_place(cur);
-                cur = next
+    #[test]
+    fn test_stack_recycles_popped_nodes() {
+        let stack = Stack::new();
+        stack.push(1);
+        // Safety: Only this thread uses the stack
+        let node = unsafe { stack.inner.chain().head };
+        assert_eq!(stack.pop(), Some(1));
+        // The next push gets the same node back instead of allocating
+        stack.push(2);
+        assert_eq!(unsafe { stack.inner.chain().head }, node);
+
+        // Only up to MAX_FREE_NODES are kept
+        let stack = Stack::from((0..2 * MAX_FREE_NODES).collect::<Vec<_>>());
+        while stack.pop().is_some() {}
+        assert_eq!(unsafe { stack.inner.chain().free_len }, MAX_FREE_NODES);
+    }
+
+    #[test]
+    fn test_stack_recycled_nodes_neither_leak_nor_double_drop() {
+        let val = Arc::new(());
+        let stack = Stack::new();
+        for round in 0..100 {
+            for _ in 0..10 {
+                stack.push(val.clone());
+            }
+            for _ in 0..(round % 10) {
+                drop(stack.pop());
+            }
+            drop(stack.pop_if(|_| true));
+            if round % 3 == 0 {
+                drop(stack.replace_top(val.clone()));
             }
+            assert_eq!(Arc::strong_count(&val), 1 + stack.len());
         }
+        drop(stack);
+        assert_eq!(Arc::strong_count(&val), 1);
     }
-}
 
-pub struct Stack<T> {
-    inner: *mut InnerStack<T>
-}
+    #[test]
+    fn test_stack_debug() {
+        let stack = Stack::from(vec![1, 2, 3]);
+        assert_eq!(format!("{stack:?}"), "Stack { len: 3 }");
+        assert_eq!(format!("{stack:#?}"), "Stack {\n    len: 3,\n    elements: [\n        3,\n        2,\n        1,\n    ],\n}");
 
-impl<T> Stack<T> {
-    pub fn new() -> Self {
-        let inner = Box::into_raw(Box::new(InnerStack::new()));
-        Self { inner }
+        // Formatting while this thread holds the lock must not deadlock
+        let guard = stack.lock_iter();
+        assert_eq!(format!("{stack:?}"), "Stack { len: 3 }");
+        assert!(format!("{stack:#?}").contains("elements: <locked>"));
+        drop(guard);
     }
 
-    pub fn push(&self, val: T) {
-        // Safety: We know this pointer will never be null
-        unsafe { (*self.inner).push(val); }
+    #[test]
+    fn test_stack_count_matching() {
+        let stack = Stack::new();
+        for i in [3, 17, 8, 42, 1, 25, 10] {
+            stack.push(i);
+        }
+        assert_eq!(stack.count_matching(|&val| val > 9), 4);
+        assert_eq!(stack.count_matching(|_| true), 7);
+        assert_eq!(stack.count_matching(|&val| val > 100), 0);
+
+        // Counting does not drain the stack
+        assert_eq!(stack.pop(), Some(10));
     }
 
-    pub fn pop(&self) -> Option<T> {
-        // Safety: We know this pointer will never be null
-        unsafe { (*self.inner).pop() }
+    #[test]
+    fn test_stack_from_vec() {
+        let stack = Stack::from(vec![String::from("a"), String::from("b"), String::from("c")]);
+        assert_eq!(stack.pop().as_deref(), Some("c"));
+        assert_eq!(stack.pop().as_deref(), Some("b"));
+        assert_eq!(stack.pop().as_deref(), Some("a"));
+        assert_eq!(stack.pop(), None);
     }
-}
 
-impl<T> Clone for Stack<T> {
-    fn clone(&self) -> Self {
-        // Safety: We know this pointer will not be null
-        unsafe {
-            (*self.inner).ref_count.fetch_add(1, Relaxed);
+    #[test]
+    fn test_stack_append() {
+        let stack = Stack::from(vec![1, 2]);
+        let other = Stack::from(vec![3, 4, 5]);
+        stack.append(&other);
+
+        assert_eq!(other.pop(), None);
+        for expected in [5, 4, 3, 2, 1] {
+            assert_eq!(stack.pop(), Some(expected));
         }
-        Stack { inner: self.inner }
+        assert_eq!(stack.pop(), None);
+
+        // Appending a stack to itself is a no-op
+        stack.push(6);
+        stack.append(&stack.clone());
+        assert_eq!(stack.pop(), Some(6));
+        assert_eq!(stack.pop(), None);
     }
-}
 
-impl<T> Drop for Stack<T> {
-    fn drop(&mut self) {
-        // Safety: This pointer will not be null at this point
-        unsafe {
-            if (*self.inner).ref_count.fetch_sub(1, Release) == 1 {
-                fence(Acquire);
-                // We have exclusive access to `self.inner` at this point, and no other thread
-                // will ever have access to it again so it is safe to drop
-                ptr::drop_in_place(self.inner);
+    #[test]
+    fn test_stack_split_off() {
+        let stack = Stack::from((0..10).collect::<Vec<_>>());
+        let rest = stack.split_off(4);
+        assert_eq!(stack.count_matching(|_| true), 4);
+        assert_eq!(rest.count_matching(|_| true), 6);
+        assert_eq!(stack.drain_while(|_| true), vec![9, 8, 7, 6]);
+        assert_eq!(rest.drain_while(|_| true), vec![5, 4, 3, 2, 1, 0]);
+
+        let stack = Stack::from(vec![1, 2]);
+        assert_eq!(stack.split_off(5).pop(), None);
+        assert_eq!(stack.split_off(0).count_matching(|_| true), 2);
+        assert_eq!(stack.pop(), None);
+    }
+
+    #[test]
+    fn test_stack_take_all_if() {
+        let stack = Stack::from(vec![1, 2, 3]);
+        assert!(stack.take_all_if(4).is_none());
+        assert_eq!(stack.count_matching(|_| true), 3);
+
+        let taken = stack.take_all_if(3).expect("stack holds enough elements");
+        assert_eq!(stack.pop(), None);
+        assert_eq!(taken.drain_while(|_| true), vec![3, 2, 1]);
+
+        // An empty stack meets a threshold of zero
+        assert!(stack.take_all_if(0).is_some_and(|taken| taken.pop().is_none()));
+    }
+
+    #[test]
+    fn test_stack_map() {
+        #[derive(Clone)]
+        struct Job {
+            id: u32,
+            name: String,
+        }
+
+        let stack = Stack::new();
+        for id in 0..5 {
+            stack.push(Job { id, name: format!("job-{id}") });
+        }
+
+        let ids = stack.map(|job| job.id);
+        for expected in (0..5).rev() {
+            assert_eq!(ids.pop(), Some(expected));
+        }
+        assert_eq!(ids.pop(), None);
+
+        // The source stack is left untouched
+        assert_eq!(stack.pop().map(|job| job.name), Some(String::from("job-4")));
+    }
+
+    #[test]
+    fn test_stack_pop_if() {
+        let stack = Stack::from(vec![8, 3, 12]);
+        assert_eq!(stack.pop_if(|&val| val > 10), Some(12));
+        assert_eq!(stack.pop_if(|&val| val > 10), None);
+        // The rejected top is still there
+        assert_eq!(stack.count_matching(|_| true), 2);
+        assert_eq!(stack.pop(), Some(3));
+        assert_eq!(stack.pop_if(|&val| val > 5), Some(8));
+        assert_eq!(stack.pop_if(|_| true), None);
+    }
+
+    #[test]
+    fn test_stack_replace_top() {
+        let stack = Stack::new();
+        assert_eq!(stack.replace_top(1), None);
+        assert_eq!(stack.count_matching(|_| true), 1);
+
+        stack.push(2);
+        assert_eq!(stack.replace_top(3), Some(2));
+        assert_eq!(stack.pop(), Some(3));
+        assert_eq!(stack.pop(), Some(1));
+        assert_eq!(stack.pop(), None);
+    }
+
+    #[test]
+    fn test_stack_push_all_matches_individual_pushes() {
+        let one_by_one = Stack::new();
+        let batched = Stack::new();
+        let sliced = Stack::new();
+        for stack in [&one_by_one, &batched, &sliced] {
+            stack.push(100);
+        }
+        for i in 0..20 {
+            one_by_one.push(i);
+        }
+        batched.push_all(0..20);
+        sliced.push_slice(&(0..20).collect::<Vec<_>>());
+        batched.push_all(std::iter::empty());
+
+        assert_eq!(batched.len(), 21);
+        assert_eq!(sliced.len(), 21);
+        let expected = one_by_one.drain().collect::<Vec<_>>();
+        assert_eq!(batched.drain().collect::<Vec<_>>(), expected);
+        assert_eq!(sliced.drain().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn test_stack_push_all_wakes_pop_exactly() {
+        let stack = Stack::new();
+        thread::scope(|s| {
+            let consumer = s.spawn(|| stack.pop_exactly(5));
+            // Only pushes made after the call count, so let the consumer register and park first
+            while stack.inner.push_waiters.load(SeqCst) == 0 {
+                thread::yield_now();
+            }
+            thread::sleep(std::time::Duration::from_millis(20));
+            stack.push_all(0..5);
+            assert_eq!(consumer.join().expect("consumer panicked"), vec![4, 3, 2, 1, 0]);
+        });
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "std"), ignore = "counts waiters that are asleep in the futex")]
+    fn test_stack_push_batch_notify_wakes_once_per_batch() {
+        const CONSUMERS: u32 = 4;
+        let stack = Stack::new();
+        let park_all = || {
+            while stack.inner.push_waiters.load(SeqCst) < CONSUMERS || !stack.is_empty() {
+                thread::yield_now();
             }
+            thread::sleep(std::time::Duration::from_millis(20));
+        };
+        let wakes = || stack.inner.wakes.load(Relaxed);
+        let mut received = thread::scope(|s| {
+            let consumers: Vec<_> = (0..CONSUMERS)
+                .map(|_| s.spawn(|| {
+                    let mut popped = vec![];
+                    while let Ok(val) = stack.pop_blocking() {
+                        popped.push(val);
+                    }
+                    popped
+                }))
+                .collect();
+
+            park_all();
+            let before = wakes();
+            stack.push_batch_notify(0..100, BatchWake::All);
+            assert_eq!(wakes() - before, 1);
+
+            // Fewer elements than waiters, so each gets its own wake
+            park_all();
+            let before = wakes();
+            stack.push_batch_notify([100, 101], BatchWake::OnePerItem);
+            assert_eq!(wakes() - before, 2);
+
+            // Enough for everyone, so one wake reaches them all
+            park_all();
+            let before = wakes();
+            stack.push_batch_notify(102..110, BatchWake::OnePerItem);
+            assert_eq!(wakes() - before, 1);
+
+            park_all();
+            stack.close();
+            consumers.into_iter().flat_map(|consumer| consumer.join().expect("consumer panicked")).collect::<Vec<_>>()
+        });
+        received.sort_unstable();
+        assert_eq!(received, (0..110).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_stack_pop_n_in_batches() {
+        let stack: Stack<_> = (0..95).collect();
+        for top in (14..95).step_by(10).rev() {
+            assert_eq!(stack.pop_n(10), (top - 9..=top).rev().collect::<Vec<_>>());
         }
+        assert_eq!(stack.pop_n(10), vec![4, 3, 2, 1, 0]);
+        assert_eq!(stack.len(), 0);
+        assert!(stack.pop_n(10).is_empty());
+        assert!(stack.pop_n(0).is_empty());
     }
-}
 
-unsafe impl<T> Send for Stack<T> where T: Send {}
+    #[test]
+    fn test_stack_snapshot_leaves_stack_unchanged() {
+        let stack: Stack<_> = (0..5).collect();
+        assert_eq!(stack.snapshot(), vec![4, 3, 2, 1, 0]);
+        assert_eq!(stack.len(), 5);
+        assert_eq!(stack.pop(), Some(4));
+        assert_eq!(stack.snapshot(), vec![3, 2, 1, 0]);
+        assert!(Stack::<i32>::new().snapshot().is_empty());
+    }
+
+    #[test]
+    fn test_stack_clear_drops_every_element() {
+        let val = Arc::new(());
+        let stack = Stack::new();
+        stack.push_all((0..200).map(|_| val.clone()));
+        assert_eq!(Arc::strong_count(&val), 201);
 
+        stack.clear();
+        assert!(stack.is_empty());
+        assert_eq!(Arc::strong_count(&val), 1);
+        assert!(stack.pop().is_none());
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use std::thread;
+        // The stack is still usable afterwards
+        stack.push(val.clone());
+        assert_eq!(stack.len(), 1);
+        stack.clear();
+        stack.clear();
+        assert_eq!(Arc::strong_count(&val), 1);
+    }
 
     #[test]
-    fn test_stack_single_threaded() {
+    fn test_stack_drain_while() {
         let stack = Stack::new();
-        for i in 0..100 {
+        for i in 0..10 {
             stack.push(i);
         }
-        for i in (0..100).rev() {
-            let Some(val) = stack.pop() else { panic!("stack should not be empty") };
-            println!("popped {val}");
-            assert_eq!(val, i);
+        assert_eq!(stack.drain_while(|&val| val > 5), vec![9, 8, 7, 6]);
+        assert!(stack.drain_while(|&val| val > 5).is_empty());
+        for expected in (0..6).rev() {
+            assert_eq!(stack.pop(), Some(expected));
+        }
+        assert!(stack.drain_while(|_| true).is_empty());
+    }
+
+    #[test]
+    fn test_stack_shared_by_scoped_threads() {
+        let stack = Stack::new();
+        thread::scope(|s| {
+            for i in 0..3 {
+                let stack = &stack;
+                s.spawn(move || {
+                    for j in 0..1000 {
+                        stack.push(3 * j + i);
+                    }
+                });
+            }
+        });
+
+        assert_eq!(stack.count_matching(|_| true), 3000);
+        for i in 0..3 {
+            assert_eq!(stack.count_matching(|val| val % 3 == i), 1000);
+        }
+    }
+
+    #[test]
+    fn test_stack_shared_push_pop_without_cloning() {
+        let stack = Stack::new();
+        let popped = thread::scope(|s| {
+            for i in 0..2 {
+                let stack = &stack;
+                s.spawn(move || {
+                    for j in 0..1000 {
+                        stack.push(2 * j + i);
+                    }
+                });
+            }
+            let consumers: Vec<_> = (0..2).map(|_| s.spawn(|| {
+                let mut popped = vec![];
+                for _ in 0..1000 {
+                    popped.extend(stack.pop());
+                }
+                popped
+            })).collect();
+            consumers.into_iter().flat_map(|jh| jh.join().expect("consumer panicked")).collect::<Vec<_>>()
+        });
+
+        let mut all: Vec<_> = popped.into_iter().chain(std::iter::from_fn(|| stack.pop())).collect();
+        all.sort();
+        assert_eq!(all, (0..2000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_stack_lock_iter() {
+        use std::sync::atomic::AtomicBool;
+        use std::time::Duration;
+
+        let stack = Stack::from(vec![1, 2, 3, 4]);
+        let pushed = AtomicBool::new(false);
+        thread::scope(|s| {
+            let guard = stack.lock_iter();
+            let mut iter = guard.iter();
+            s.spawn(|| {
+                stack.push(5);
+                pushed.store(true, Relaxed);
+            });
+
+            thread::sleep(Duration::from_millis(50));
+            assert_eq!(iter.by_ref().sum::<i32>(), 10);
+            assert!(!pushed.load(Relaxed));
+
+            thread::sleep(Duration::from_millis(50));
+            assert!(!pushed.load(Relaxed));
+            drop(guard);
+        });
+
+        assert!(pushed.load(Relaxed));
+        assert_eq!(stack.lock_iter().iter().copied().collect::<Vec<_>>(), vec![5, 4, 3, 2, 1]);
+        let guard = stack.lock_iter();
+        assert_eq!((&guard).into_iter().count(), 5);
+    }
+
+    #[test]
+    fn test_stack_drain_race() {
+        use std::sync::atomic::AtomicUsize;
+
+        const PRODUCERS: usize = 3;
+        const CONSUMERS: usize = 3;
+        const PER_PRODUCER: usize = 1000;
+
+        let stack = Stack::new();
+        let received = AtomicUsize::new(0);
+        let mut popped = thread::scope(|s| {
+            for i in 0..PRODUCERS {
+                let stack = &stack;
+                s.spawn(move || {
+                    for j in 0..PER_PRODUCER {
+                        stack.push(PRODUCERS * j + i);
+                    }
+                });
+            }
+
+            let consumers: Vec<_> = (0..CONSUMERS).map(|_| {
+                s.spawn(|| {
+                    let mut popped = vec![];
+                    while received.load(Relaxed) < PRODUCERS * PER_PRODUCER {
+                        if let Some(val) = stack.pop() {
+                            popped.push(val);
+                            received.fetch_add(1, Relaxed);
+                        }
+                    }
+                    popped
+                })
+            }).collect();
+
+            consumers.into_iter()
+                .flat_map(|jh| jh.join().expect("consumer panicked"))
+                .collect::<Vec<_>>()
+        });
+
+        // Every pushed value must come out exactly once
+        popped.sort();
+        assert_eq!(popped, (0..PRODUCERS * PER_PRODUCER).collect::<Vec<_>>());
+        assert_eq!(stack.pop(), None);
+    }
+
+    #[test]
+    fn test_stack_pop_exactly_waits_for_pushes() {
+        let stack = Stack::from(vec![100]);
+        thread::scope(|s| {
+            let consumer = s.spawn(|| stack.pop_exactly(5));
+            // Give the consumer a chance to park before anything is pushed
+            while stack.inner.push_waiters.load(SeqCst) == 0 {
+                thread::yield_now();
+            }
+            for i in 0..4 {
+                stack.push(i);
+            }
+            thread::sleep(std::time::Duration::from_millis(20));
+            assert!(!consumer.is_finished());
+
+            stack.push(4);
+            stack.push(5);
+            let popped = consumer.join().expect("consumer panicked");
+            assert_eq!(popped.len(), 5);
+        });
+        // Whatever the consumer left behind is still on the stack
+        assert_eq!(stack.count_matching(|_| true), 2);
+        assert!(stack.pop_exactly(0).is_empty());
+    }
+
+    #[test]
+    fn test_stack_close_ends_blocking_consumer() {
+        let stack = Stack::new();
+        let mut received = thread::scope(|s| {
+            let consumer = s.spawn(|| {
+                let mut received = vec![];
+                while let Ok(val) = stack.pop_blocking() {
+                    received.push(val);
+                }
+                received
+            });
+
+            let producers: Vec<_> = (0..2).map(|i| {
+                let stack = &stack;
+                s.spawn(move || {
+                    for j in 0..200 {
+                        stack.push(2 * j + i);
+                    }
+                })
+            }).collect();
+            for jh in producers {
+                jh.join().expect("producer panicked");
+            }
+            stack.close();
+            consumer.join().expect("consumer panicked")
+        });
+
+        received.sort();
+        assert_eq!(received, (0..400).collect::<Vec<_>>());
+        assert_eq!(stack.pop_blocking(), Err(Closed));
+    }
+
+    #[test]
+    fn test_stack_pop_blocking_parks_until_push() {
+        use std::time::Duration;
+
+        let stack = Stack::new();
+        thread::scope(|s| {
+            let consumer = s.spawn(|| (0..3).map(|_| stack.pop_blocking().unwrap()).collect::<Vec<_>>());
+            // Let the consumer find the stack empty and park before anything is pushed
+            thread::sleep(Duration::from_millis(30));
+            assert!(!consumer.is_finished());
+            assert_eq!(stack.inner.push_waiters.load(SeqCst), 1);
+
+            for i in 0..3 {
+                stack.push(i);
+            }
+            let mut popped = consumer.join().expect("consumer panicked");
+            popped.sort();
+            assert_eq!(popped, vec![0, 1, 2]);
+        });
+        assert_eq!(stack.inner.push_waiters.load(SeqCst), 0);
+        assert!(stack.is_empty());
+    }
+
+    #[test]
+    fn test_stack_append_and_replace_top_wake_blocking_pop() {
+        let stack = Stack::new();
+        let other = Stack::from(vec![1, 2]);
+        thread::scope(|s| {
+            let consumer = s.spawn(|| (0..3).map(|_| stack.pop_blocking().unwrap()).collect::<Vec<_>>());
+            while stack.inner.push_waiters.load(SeqCst) == 0 {
+                thread::yield_now();
+            }
+            stack.append(&other);
+            // The consumer takes both appended elements, so the next insert lands on an empty stack
+            while !stack.is_empty() {
+                thread::yield_now();
+            }
+            assert_eq!(stack.replace_top(3), None);
+            let mut popped = consumer.join().expect("consumer panicked");
+            popped.sort();
+            assert_eq!(popped, vec![1, 2, 3]);
+        });
+        assert!(other.is_empty());
+    }
+
+    #[test]
+    fn test_stack_on_event_counts_operations() {
+        use std::sync::atomic::AtomicUsize;
+
+        let pushed = Arc::new(AtomicUsize::new(0));
+        let popped = Arc::new(AtomicUsize::new(0));
+        let empty = Arc::new(AtomicUsize::new(0));
+        let stack = Stack::new();
+        {
+            let (pushed, popped, empty) = (pushed.clone(), popped.clone(), empty.clone());
+            stack.on_event(move |event| {
+                let counter = match event {
+                    StackEvent::Pushed => &pushed,
+                    StackEvent::Popped => &popped,
+                    StackEvent::PopEmpty => &empty,
+                };
+                counter.fetch_add(1, Relaxed);
+            });
         }
+
+        let mut pops = 0;
+        let mut empty_pops = 0;
+        thread::scope(|s| {
+            for i in 0..3 {
+                let stack = &stack;
+                s.spawn(move || {
+                    for j in 0..500 {
+                        stack.push(3 * j + i);
+                    }
+                });
+            }
+            while pops < 1500 {
+                if stack.pop().is_some() {
+                    pops += 1;
+                } else {
+                    empty_pops += 1;
+                }
+            }
+        });
+
+        assert_eq!(stack.pop(), None);
+        assert_eq!(pushed.load(Relaxed), 1500);
+        assert_eq!(popped.load(Relaxed), 1500);
+        assert_eq!(empty.load(Relaxed), empty_pops + 1);
+
+        // Every other path that changes the chain reports as well, once per element
+        let counts = || (pushed.load(Relaxed), popped.load(Relaxed), empty.load(Relaxed));
+        let mut expected = counts();
+
+        stack.push_all(0..10);
+        expected.0 += 10;
+        assert_eq!(counts(), expected);
+
+        assert_eq!(stack.pop_if(|top| *top == 9), Some(9));
+        assert_eq!(stack.pop_if(|_| false), None);
+        expected.1 += 1;
+        assert_eq!(counts(), expected);
+
+        assert_eq!(stack.drain_while(|val| *val >= 6), vec![8, 7, 6]);
+        expected.1 += 3;
+        assert_eq!(counts(), expected);
+
+        thread::scope(|s| {
+            let consumer = s.spawn(|| stack.pop_exactly(2));
+            while stack.inner.push_waiters.load(SeqCst) == 0 {
+                thread::yield_now();
+            }
+            stack.push_all([20, 21]);
+            assert_eq!(consumer.join().expect("consumer panicked"), vec![21, 20]);
+        });
+        expected.0 += 2;
+        expected.1 += 2;
+        assert_eq!(counts(), expected);
+
+        // The old top leaves and the new one arrives
+        assert_eq!(stack.replace_top(40), Some(5));
+        expected.0 += 1;
+        expected.1 += 1;
+        assert_eq!(counts(), expected);
+
+        let other = Stack::from(vec![100, 101]);
+        stack.append(&other);
+        expected.0 += 2;
+        assert_eq!(counts(), expected);
+        other.append(&stack);
+        expected.1 += 8;
+        assert_eq!(counts(), expected);
+        stack.append(&other);
+        expected.0 += 8;
+        assert_eq!(counts(), expected);
+
+        assert_eq!(stack.split_off(3).len(), 5);
+        expected.1 += 5;
+        assert_eq!(counts(), expected);
+
+        assert!(stack.take_all_if(10).is_none());
+        assert_eq!(stack.take_all_if(1).map(|taken| taken.len()), Some(3));
+        expected.1 += 3;
+        assert_eq!(counts(), expected);
+
+        // Onto an empty stack, so only a push
+        assert_eq!(stack.replace_top(7), None);
+        expected.0 += 1;
+        assert_eq!(counts(), expected);
+
+        stack.clear();
+        expected.1 += 1;
+        assert_eq!(counts(), expected);
+
+        assert_eq!(stack.pop_if(|_| true), None);
+        expected.2 += 1;
+        assert_eq!(counts(), expected);
     }
 
     #[test]
@@ -240,3 +1983,37 @@ mod test {
     }
 }
 
+
+// Run with `LOOM_MAX_PREEMPTIONS=2 cargo test --features loom --lib`, exploring every preemption
+// schedule takes far too long.
+#[cfg(all(test, feature = "loom"))]
+mod loom_test {
+    use super::*;
+    use loom::thread;
+
+    #[test]
+    fn concurrent_push_pop() {
+        loom::model(|| {
+            let stack = Stack::new();
+            let producers: Vec<_> = (0..2).map(|i| {
+                let stack = stack.clone();
+                thread::spawn(move || stack.push(i))
+            }).collect();
+
+            let consumer = {
+                let stack = stack.clone();
+                thread::spawn(move || stack.pop())
+            };
+
+            for jh in producers {
+                jh.join().unwrap();
+            }
+            let mut popped: Vec<_> = consumer.join().unwrap().into_iter().collect();
+            while let Some(val) = stack.pop() {
+                popped.push(val);
+            }
+            popped.sort();
+            assert_eq!(popped, vec![0, 1]);
+        });
+    }
+}
@@ -0,0 +1,40 @@
+use ::serde::de::{Deserialize, Deserializer};
+use ::serde::ser::{Serialize, Serializer};
+use alloc::vec::Vec;
+use super::Stack;
+
+
+// Elements are written top first, under a single lock acquisition so the snapshot is consistent
+impl<T: Serialize> Serialize for Stack<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let guard = self.lock_iter();
+        serializer.collect_seq(guard.iter())
+    }
+}
+
+// Reads the top-first sequence written by `serialize` and pushes it bottom first, so the rebuilt
+// stack pops in the same order as the original
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Stack<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let elements = Vec::<T>::deserialize(deserializer)?;
+        Ok(elements.into_iter().rev().collect())
+    }
+}
+
+
+#[cfg(all(test, not(feature = "loom")))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_stack_serde_round_trip_preserves_pop_order() {
+        let stack: Stack<u32> = (0..5).collect();
+        let json = serde_json::to_string(&stack).expect("stack serializes");
+        assert_eq!(json, "[4,3,2,1,0]");
+        // Serializing does not consume anything
+        assert_eq!(stack.len(), 5);
+
+        let restored: Stack<u32> = serde_json::from_str(&json).expect("stack deserializes");
+        assert_eq!(restored.drain().collect::<Vec<_>>(), stack.drain().collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,96 @@
+// Every atomic and futex operation in the crate goes through this module. With `--features loom`
+// they are swapped for loom's model-checked equivalents, so
+// `LOOM_MAX_PREEMPTIONS=2 cargo test --features loom --lib` explores the interleavings of the loom
+// model tests instead of running the regular suite.
+//
+// Parking needs an OS, so the futex only comes from `atomic_wait` with the `std` feature, which is
+// on by default and also makes `atomic_wait` a dependency. Without it the atomics come from `core`
+// and waiting falls back to spinning, which keeps every collection correct but burns the waiting
+// thread's core instead of sleeping. The rest of the crate builds without std as well, except for
+// what needs a clock or unwinding (`Semaphore`'s timed waits and `acquire`) and `cache`, which is
+// built on `HashMap`.
+
+#[cfg(not(feature = "loom"))]
+pub(crate) use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicU64, AtomicUsize, Ordering, fence};
+#[cfg(not(feature = "loom"))]
+pub(crate) use core::hint::spin_loop;
+#[cfg(all(feature = "std", not(feature = "loom")))]
+pub(crate) use std::thread::yield_now;
+#[cfg(all(feature = "std", not(feature = "loom")))]
+pub(crate) use atomic_wait::{wait, wake_all, wake_one};
+
+// Without a scheduler to yield to, backing off can only spin
+// Called between a SeqCst store or RMW and a SeqCst load of another atomic wherever the crate relies
+// on the two threads of a handshake not both missing each other's store. SeqCst already rules that
+// out, so this is a no-op outside loom. loom models SeqCst accesses as AcqRel, which allows it,
+// and would report lost wakeups the real memory model cannot produce. A SeqCst fence, which loom
+// does model, restores the guarantee.
+#[cfg(not(feature = "loom"))]
+#[inline(always)]
+pub(crate) fn seq_cst_fence() {}
+
+#[cfg(all(not(feature = "std"), not(feature = "loom")))]
+pub(crate) fn yield_now() {
+    spin_loop();
+}
+
+// Like the loom stand-ins below: a futex waiter may always wake spuriously, so returning after a
+// spin is a valid `wait`, and every caller re-checks its condition in a loop. The wakes then have
+// nobody to wake.
+#[cfg(all(not(feature = "std"), not(feature = "loom")))]
+pub(crate) fn wait(atomic: &AtomicU32, value: u32) {
+    if atomic.load(Ordering::Relaxed) == value {
+        spin_loop();
+    }
+}
+
+#[cfg(all(not(feature = "std"), not(feature = "loom")))]
+pub(crate) fn wake_one(_atomic: *const AtomicU32) {}
+
+#[cfg(all(not(feature = "std"), not(feature = "loom")))]
+pub(crate) fn wake_all(_atomic: *const AtomicU32) {}
+
+#[cfg(feature = "loom")]
+pub(crate) use loom::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicU64, AtomicUsize, Ordering, fence};
+#[cfg(feature = "loom")]
+pub(crate) use loom::{hint::spin_loop, thread::yield_now};
+
+#[cfg(feature = "loom")]
+pub(crate) fn seq_cst_fence() {
+    fence(Ordering::SeqCst);
+}
+
+// loom has no futex, so one is modelled with a mutex and condvar shared by every futex word. A
+// waiter checks the word with the mutex held and sleeps on the condvar, and a wake takes the mutex
+// before notifying, so a wake can never slip in between the check and the sleep. Every wake
+// notifies all waiters, which callers must cope with anyway since a futex may wake spuriously.
+// Unlike yielding, this lets loom report a lost wakeup as a deadlock.
+#[cfg(feature = "loom")]
+struct Futex {
+    lock: loom::sync::Mutex<()>,
+    woken: loom::sync::Condvar,
+}
+
+#[cfg(feature = "loom")]
+loom::lazy_static! {
+    static ref FUTEX: Futex = Futex { lock: loom::sync::Mutex::new(()), woken: loom::sync::Condvar::new() };
+}
+
+#[cfg(feature = "loom")]
+pub(crate) fn wait(atomic: &AtomicU32, value: u32) {
+    let guard = FUTEX.lock.lock().unwrap();
+    if atomic.load(Ordering::SeqCst) == value {
+        drop(FUTEX.woken.wait(guard).unwrap());
+    }
+}
+
+#[cfg(feature = "loom")]
+pub(crate) fn wake_one(atomic: *const AtomicU32) {
+    wake_all(atomic);
+}
+
+#[cfg(feature = "loom")]
+pub(crate) fn wake_all(_atomic: *const AtomicU32) {
+    let _guard = FUTEX.lock.lock().unwrap();
+    FUTEX.woken.notify_all();
+}
@@ -0,0 +1,33 @@
+// Tagged pointers for the lock-free structures: a node address in the low bits of a u64 and a
+// counter in the top bits. Bumping the counter on every change makes a CAS against a stale word
+// fail even when the same node has come back in the meantime (the ABA problem). x86_64 and aarch64
+// user space addresses fit in the low 48 bits, and `pack` checks that they do.
+const TAG_SHIFT: u32 = 48;
+const ADDR_MASK: u64 = (1 << TAG_SHIFT) - 1;
+
+pub(crate) fn pack<N>(node: *mut N, tag: u64) -> u64 {
+    let addr = node as usize as u64;
+    assert_eq!(addr & !ADDR_MASK, 0, "node address does not fit in {TAG_SHIFT} bits");
+    (tag << TAG_SHIFT) | addr
+}
+
+pub(crate) fn unpack<N>(word: u64) -> (*mut N, u64) {
+    ((word & ADDR_MASK) as usize as *mut N, word >> TAG_SHIFT)
+}
+
+
+#[cfg(all(test, not(feature = "loom")))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pack_round_trips_and_wraps_tag() {
+        let mut val = 0u64;
+        let ptr: *mut u64 = &mut val;
+        assert_eq!(unpack::<u64>(pack(ptr, 5)), (ptr, 5));
+        // The counter only has the top bits, so it wraps rather than spilling into the address
+        let max_tag = u64::MAX >> TAG_SHIFT;
+        assert_eq!(unpack::<u64>(pack(ptr, max_tag.wrapping_add(1) & max_tag)), (ptr, 0));
+        assert_eq!(unpack::<u64>(pack(ptr, max_tag)), (ptr, max_tag));
+    }
+}